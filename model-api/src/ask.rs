@@ -0,0 +1,34 @@
+use axum::{extract::State, routing::post, Json, Router};
+use error_stack::ResultExt;
+use maiven_search_store::{
+    ask::{AskAnswer, AskQuery},
+    check_sampling_params, check_temperature,
+};
+
+use crate::{
+    errors::{ApiError, ApiResult},
+    AppState, AppStateContents,
+};
+
+async fn ask(State(state): AppState, Json(query): Json<AskQuery>) -> ApiResult<AskAnswer> {
+    if query.question.trim().is_empty() {
+        return Err(ApiError::ArgError("question must not be empty".to_string()).into());
+    }
+
+    check_temperature(&query.temperature)
+        .change_context(ApiError::ArgError("temperature".to_string()))?;
+    check_sampling_params(&query.sampling)
+        .change_context(ApiError::ArgError("sampling parameters".to_string()))?;
+
+    let answer = state
+        .search_store
+        .ask(&query)
+        .await
+        .change_context(ApiError::Passthrough)?;
+
+    Ok(Json(answer))
+}
+
+pub fn create_router() -> Router<AppStateContents> {
+    Router::new().route("/", post(ask))
+}
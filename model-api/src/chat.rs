@@ -3,45 +3,429 @@ use axum::{
     http::StatusCode,
     response::IntoResponse,
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use error_stack::{Report, ResultExt};
+use maiven_search_store::{
+    check_sampling_params, check_temperature,
+    db::{
+        chat_sessions::{
+            self, ChatMessage, ChatMessageSystemInfo, ChatSession, ChatSessionConfig,
+            ChatSessionUpdate, NewChatSession,
+        },
+        chat_system_messages,
+    },
+    models::{
+        chat::{self, ChatRole, ChatSubmission},
+        completion::SamplingParams,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat_context::build_chat_context,
+    errors::{ApiError, ApiReport, ApiResult, IntoPassthrough},
+    AppState, AppStateContents, AppStateInner,
 };
 
-use crate::{errors::ApiReport, AppState, AppStateContents};
+#[derive(Serialize)]
+struct ChatSessionsResult {
+    sessions: Vec<ChatSession>,
+}
+
+#[derive(Serialize)]
+struct ChatSessionWithMessages {
+    #[serde(flatten)]
+    session: ChatSession,
+    messages: Vec<ChatMessage>,
+}
 
-async fn list_chat_sessions(State(state): AppState) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+#[derive(Deserialize)]
+struct NewChatSessionBody {
+    name: Option<String>,
+    system_message_id: Option<i64>,
+    #[serde(default)]
+    config: ChatSessionConfig,
+}
+
+#[derive(Serialize)]
+struct ChatMessagesResult {
+    messages: Vec<ChatMessage>,
+}
+
+#[derive(Deserialize)]
+struct PromoteMessageBody {
+    /// The name of the new session. Defaults to the name of the current session.
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AddMessageBody {
+    message: String,
+    /// The message that this one replies to. Defaults to the latest message in the session.
+    parent_id: Option<i64>,
+    #[serde(default)]
+    important: bool,
+}
+
+fn check_config(config: &ChatSessionConfig) -> Result<(), Report<ApiError>> {
+    check_temperature(&config.temperature)
+        .change_context(ApiError::ArgError("temperature".to_string()))?;
+    check_sampling_params(&config.sampling)
+        .change_context(ApiError::ArgError("sampling parameters".to_string()))
+}
+
+/// Make sure that a session's system message exists.
+async fn check_system_message(
+    state: &AppStateInner,
+    system_message_id: Option<i64>,
+) -> Result<(), ApiReport> {
+    if let Some(id) = system_message_id {
+        chat_system_messages::get_chat_system_message(&state.pool, id)
+            .await?
+            .ok_or_else(|| ApiError::ArgError(format!("system message {id} does not exist")))?;
+    }
+
+    Ok(())
+}
+
+/// Build the messages to send to the model from the system message, the earlier messages in
+/// the conversation, and the new user message.
+fn build_conversation(
+    system: Option<String>,
+    summary: Option<String>,
+    history: Vec<ChatMessage>,
+    user_message: String,
+) -> Vec<chat::ChatMessage> {
+    let mut messages = Vec::with_capacity(history.len() * 2 + 3);
+    if let Some(system) = system {
+        messages.push(chat::ChatMessage {
+            role: ChatRole::System,
+            content: system,
+            name: None,
+        });
+    }
+
+    if let Some(summary) = summary {
+        messages.push(chat::ChatMessage {
+            role: ChatRole::System,
+            content: format!("Summary of the earlier conversation:\n{summary}"),
+            name: None,
+        });
+    }
+
+    for message in history {
+        messages.push(chat::ChatMessage {
+            role: ChatRole::User,
+            content: message.user_message,
+            name: None,
+        });
+
+        if let Some(ai_message) = message.ai_message {
+            messages.push(chat::ChatMessage {
+                role: ChatRole::Assistant,
+                content: ai_message,
+                name: None,
+            });
+        }
+    }
+
+    messages.push(chat::ChatMessage {
+        role: ChatRole::User,
+        content: user_message,
+        name: None,
+    });
+
+    messages
+}
+
+async fn list_chat_sessions(State(state): AppState) -> ApiResult<ChatSessionsResult> {
+    let sessions = chat_sessions::list_chat_sessions(&state.pool).await?;
+    Ok(Json(ChatSessionsResult { sessions }))
 }
 
 async fn get_chat_session(
     State(state): AppState,
-    Path(id): Path<i32>,
-) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+    Path(id): Path<i64>,
+) -> ApiResult<ChatSessionWithMessages> {
+    let session = chat_sessions::get_chat_session(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let messages = chat_sessions::get_chat_session_messages(&state.pool, id).await?;
+
+    Ok(Json(ChatSessionWithMessages { session, messages }))
 }
 
 async fn update_chat_session(
     State(state): AppState,
-    Path(id): Path<i32>,
-) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+    Path(id): Path<i64>,
+    Json(body): Json<ChatSessionUpdate>,
+) -> ApiResult<ChatSession> {
+    if let Some(config) = body.config.as_ref() {
+        check_config(config)?;
+    }
+    check_system_message(&state, body.system_message_id).await?;
+
+    let session = chat_sessions::update_chat_session(&state.pool, id, &body)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(session))
 }
 
 async fn delete_chat_session(
     State(state): AppState,
-    Path(id): Path<i32>,
+    Path(id): Path<i64>,
 ) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+    let deleted = chat_sessions::delete_chat_session(&state.pool, id).await?;
+    if !deleted {
+        return Err(ApiError::NotFound.into());
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Add a user message to the session as a reply to `parent_id`, and generate the AI response.
+async fn run_chat_turn(
+    state: &AppStateInner,
+    session: ChatSession,
+    parent_id: Option<i64>,
+    user_message: String,
+    important: bool,
+) -> Result<ChatMessage, ApiReport> {
+    let model_id = session.config.model_id.ok_or_else(|| {
+        ApiError::ArgError("chat session does not have a model configured".to_string())
+    })?;
+
+    let model = state
+        .search_store
+        .loaded_chat_models
+        .read()
+        .iter()
+        .find(|model| model.id == model_id)
+        .ok_or(ApiError::ModelNotLoaded("chat"))?
+        .model
+        .clone();
+
+    let history = match parent_id {
+        Some(parent_id) => chat_sessions::get_chat_message_ancestry(&state.pool, parent_id).await?,
+        None => Vec::new(),
+    };
+
+    let system = match session.system_message_id {
+        Some(system_id) => chat_system_messages::get_chat_system_message(&state.pool, system_id)
+            .await?
+            .map(|system| system.render(&session.config.variables)),
+        None => None,
+    };
+
+    let context = build_chat_context(
+        state,
+        &session,
+        model.as_ref(),
+        system.as_deref(),
+        &user_message,
+        history,
+    )
+    .await?;
+
+    let submission = ChatSubmission {
+        messages: build_conversation(
+            system,
+            context.summary,
+            context.messages,
+            user_message.clone(),
+        ),
+        temperature: session.config.temperature,
+        // The history was fit into the context assuming this much room for the answer.
+        sampling: SamplingParams {
+            max_tokens: Some(context.output_tokens),
+            ..session.config.sampling
+        },
+    };
+
+    let answer = tokio::task::spawn_blocking(move || {
+        model.chat(submission).change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
+
+    // The message is only saved once the model has answered, so that a failed turn does not
+    // leave a message without a response at the end of the conversation.
+    let message = chat_sessions::add_chat_message(
+        &state.pool,
+        session.id,
+        parent_id,
+        important,
+        user_message,
+        None,
+    )
+    .await?;
+
+    let system_info = ChatMessageSystemInfo {
+        model_id,
+        finish_reason: answer.finish_reason,
+        usage: answer.usage,
+    };
+
+    let message = chat_sessions::add_ai_response_to_chat_message(
+        &state.pool,
+        message.id,
+        answer.message.content,
+        &system_info,
+    )
+    .await?;
+
+    chat_sessions::update_chat_session(&state.pool, session.id, &ChatSessionUpdate::default())
+        .await?;
+
+    Ok(message)
+}
+
+/// Look up a message, making sure that it belongs to the session.
+async fn get_session_message(
+    state: &AppStateInner,
+    session_id: i64,
+    message_id: i64,
+) -> Result<ChatMessage, ApiReport> {
+    let message = chat_sessions::get_chat_message(&state.pool, message_id)
+        .await?
+        .filter(|m| m.session_id == session_id)
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(message)
 }
 
 async fn add_chat_message(
     State(state): AppState,
-    Path(id): Path<i32>,
-) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+    Path(id): Path<i64>,
+    Json(body): Json<AddMessageBody>,
+) -> ApiResult<ChatMessage> {
+    let session = chat_sessions::get_chat_session(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let parent_id = match body.parent_id {
+        // A session can continue from the message in its parent session that it was promoted
+        // from, but otherwise the parent must be one of its own messages.
+        Some(parent_id) if Some(parent_id) == session.parent_message_id => Some(parent_id),
+        Some(parent_id) => Some(get_session_message(&state, id, parent_id).await?.id),
+        None => chat_sessions::get_latest_chat_message(&state.pool, id)
+            .await?
+            .map(|m| m.id)
+            .or(session.parent_message_id),
+    };
+
+    let message = run_chat_turn(&state, session, parent_id, body.message, body.important).await?;
+    Ok(Json(message))
+}
+
+/// Generate a new response to a message. The new response is added as a sibling of the
+/// original message, so that both branches of the conversation are kept.
+async fn regenerate_chat_message(
+    State(state): AppState,
+    Path((id, message_id)): Path<(i64, i64)>,
+) -> ApiResult<ChatMessage> {
+    let session = chat_sessions::get_chat_session(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let original = get_session_message(&state, id, message_id).await?;
+
+    let message = run_chat_turn(
+        &state,
+        session,
+        original.parent_id,
+        original.user_message,
+        original.important,
+    )
+    .await?;
+    Ok(Json(message))
+}
+
+/// List the branches that start at the beginning of the session.
+async fn list_root_messages(
+    State(state): AppState,
+    Path(id): Path<i64>,
+) -> ApiResult<ChatMessagesResult> {
+    let session = chat_sessions::get_chat_session(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let messages =
+        chat_sessions::get_chat_message_children(&state.pool, id, session.parent_message_id)
+            .await?;
+    Ok(Json(ChatMessagesResult { messages }))
+}
+
+/// List the branches that continue from a message.
+async fn list_child_messages(
+    State(state): AppState,
+    Path((id, message_id)): Path<(i64, i64)>,
+) -> ApiResult<ChatMessagesResult> {
+    get_session_message(&state, id, message_id).await?;
+
+    let messages =
+        chat_sessions::get_chat_message_children(&state.pool, id, Some(message_id)).await?;
+    Ok(Json(ChatMessagesResult { messages }))
+}
+
+/// Get the conversation leading up to and including a message.
+async fn get_message_path(
+    State(state): AppState,
+    Path((id, message_id)): Path<(i64, i64)>,
+) -> ApiResult<ChatMessagesResult> {
+    get_session_message(&state, id, message_id).await?;
+
+    let messages = chat_sessions::get_chat_message_ancestry(&state.pool, message_id).await?;
+    Ok(Json(ChatMessagesResult { messages }))
+}
+
+/// Start a new session that continues the conversation from a message in this session.
+async fn promote_chat_message(
+    State(state): AppState,
+    Path((id, message_id)): Path<(i64, i64)>,
+    Json(body): Json<PromoteMessageBody>,
+) -> ApiResult<ChatSession> {
+    let session = chat_sessions::get_chat_session(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    get_session_message(&state, id, message_id).await?;
+
+    let new_session = chat_sessions::create_chat_session(
+        &state.pool,
+        &NewChatSession {
+            name: body.name.or(session.name),
+            system_message_id: session.system_message_id,
+            parent_session: Some(id),
+            parent_message_id: Some(message_id),
+            config: session.config,
+        },
+    )
+    .await?;
+
+    Ok(Json(new_session))
 }
 
-async fn new_chat_session(State(state): AppState) -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+async fn new_chat_session(
+    State(state): AppState,
+    Json(body): Json<NewChatSessionBody>,
+) -> ApiResult<ChatSession> {
+    check_config(&body.config)?;
+    check_system_message(&state, body.system_message_id).await?;
+
+    let session = chat_sessions::create_chat_session(
+        &state.pool,
+        &NewChatSession {
+            name: body.name,
+            system_message_id: body.system_message_id,
+            parent_session: None,
+            parent_message_id: None,
+            config: body.config,
+        },
+    )
+    .await?;
+
+    Ok(Json(session))
 }
 
 pub fn create_router() -> Router<AppStateContents> {
@@ -54,4 +438,18 @@ pub fn create_router() -> Router<AppStateContents> {
                 .delete(delete_chat_session),
         )
         .route("/:id/add_message", post(add_chat_message))
+        .route("/:id/roots", get(list_root_messages))
+        .route("/:id/messages/:message_id/path", get(get_message_path))
+        .route(
+            "/:id/messages/:message_id/children",
+            get(list_child_messages),
+        )
+        .route(
+            "/:id/messages/:message_id/regenerate",
+            post(regenerate_chat_message),
+        )
+        .route(
+            "/:id/messages/:message_id/promote",
+            post(promote_chat_message),
+        )
 }
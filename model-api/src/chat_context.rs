@@ -0,0 +1,278 @@
+use std::{fmt::Write, sync::Arc};
+
+use error_stack::{IntoReport, Report, ResultExt};
+use maiven_search_store::{
+    chunking::{chunk_text, ChunkOptions},
+    db::chat_sessions::{self, ChatMessage, ChatSession},
+    models::{
+        chat::{
+            context::{plan_context, ContextMessage, MESSAGE_OVERHEAD_TOKENS},
+            ChatModel,
+        },
+        completion::{CompletionModel, CompletionSubmission, SamplingParams},
+        ModelError,
+    },
+};
+
+use crate::{
+    errors::{ApiError, ApiReport, IntoPassthrough},
+    AppStateInner,
+};
+
+/// The longest summary that will be generated.
+const SUMMARY_TOKENS: usize = 256;
+
+/// The part of the conversation history that fits into the chat model's context.
+pub struct ChatContext {
+    /// A summary of the older messages that were left out
+    pub summary: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    /// The tokens set aside for the model's answer
+    pub output_tokens: usize,
+}
+
+fn message_tokens(model: &dyn ChatModel, message: &ChatMessage) -> usize {
+    let ai_tokens = message
+        .ai_message
+        .as_deref()
+        .map(|m| model.count_tokens(m) + MESSAGE_OVERHEAD_TOKENS)
+        .unwrap_or(0);
+
+    model.count_tokens(&message.user_message) + MESSAGE_OVERHEAD_TOKENS + ai_tokens
+}
+
+/// Fit the conversation history into the chat model's context window. If the history is too
+/// long, the older messages are replaced by a rolling summary, which is saved to `chat_summaries`
+/// so that later turns can reuse it. Messages marked as important are always kept.
+pub async fn build_chat_context(
+    state: &AppStateInner,
+    session: &ChatSession,
+    model: &dyn ChatModel,
+    system: Option<&str>,
+    user_message: &str,
+    history: Vec<ChatMessage>,
+) -> Result<ChatContext, ApiReport> {
+    let context_size = model.context_size();
+    let output_tokens = session
+        .config
+        .sampling
+        .max_tokens
+        .unwrap_or(context_size / 4);
+    let fixed_tokens = system
+        .into_iter()
+        .chain([user_message])
+        .map(|text| model.count_tokens(text) + MESSAGE_OVERHEAD_TOKENS)
+        .sum::<usize>();
+    if output_tokens + fixed_tokens > context_size {
+        return Err(ApiError::ArgError(
+            "The message does not fit in the model's context".to_string(),
+        )
+        .into());
+    }
+    let budget = context_size - output_tokens - fixed_tokens;
+
+    let sizes = history
+        .iter()
+        .map(|message| ContextMessage {
+            tokens: message_tokens(model, message),
+            important: message.important,
+        })
+        .collect::<Vec<_>>();
+
+    let summary_tokens = SUMMARY_TOKENS + MESSAGE_OVERHEAD_TOKENS;
+    let plan = plan_context(&sizes, budget, summary_tokens).ok_or_else(|| {
+        ApiError::ArgError("The important messages do not fit in the model's context".to_string())
+    })?;
+    let Some(dropped_through) = plan.summarized_through else {
+        return Ok(ChatContext {
+            summary: None,
+            messages: history,
+            output_tokens,
+        });
+    };
+
+    // Find the newest summary of this conversation path.
+    let existing = chat_sessions::get_chat_summaries(&state.pool, session.id)
+        .await?
+        .into_iter()
+        .rev()
+        .find_map(|summary| {
+            history
+                .iter()
+                .position(|m| m.id == summary.end_message_id)
+                .map(|end| (end, summary))
+        });
+
+    let summary_model = match session.config.summary_model_id {
+        Some(summary_model_id) => Some(
+            state
+                .search_store
+                .loaded_completion_models
+                .read()
+                .iter()
+                .find(|model| model.id == summary_model_id)
+                .ok_or(ApiError::ModelNotLoaded("completion"))?
+                .model
+                .clone(),
+        ),
+        None => None,
+    };
+
+    let (summary, summary_end) = match (existing, summary_model) {
+        (Some((end, summary)), _) if end >= dropped_through => (Some(summary.summary), end),
+        (existing, Some(summary_model)) => {
+            // Summarize further back than is strictly needed, so that the summary can be reused
+            // for the next few turns instead of generating a new one every time.
+            let target = plan_context(&sizes, budget / 2, summary_tokens)
+                .and_then(|plan| plan.summarized_through)
+                .unwrap_or(dropped_through)
+                .max(dropped_through);
+
+            let (previous, start) = match existing {
+                Some((end, summary)) => (Some(summary), end + 1),
+                None => (None, 0),
+            };
+
+            let text = summarize(
+                summary_model,
+                previous.as_ref().map(|s| s.summary.clone()),
+                &history[start..=target],
+            )
+            .await?;
+
+            let summary = chat_sessions::add_chat_summary(
+                &state.pool,
+                session.id,
+                previous.map(|s| s.id),
+                text,
+                history[0].id,
+                history[target].id,
+            )
+            .await?;
+
+            (Some(summary.summary), target)
+        }
+        // Without a summary model, an older summary is still used, and the messages between
+        // its end and the ones that fit are left out.
+        (Some((_, summary)), None) => (Some(summary.summary), dropped_through),
+        (None, None) => (None, dropped_through),
+    };
+
+    let messages = history
+        .into_iter()
+        .enumerate()
+        .filter(|(i, message)| *i > summary_end || message.important)
+        .map(|(_, message)| message)
+        .collect();
+
+    Ok(ChatContext {
+        summary,
+        messages,
+        output_tokens,
+    })
+}
+
+fn format_message(message: &ChatMessage) -> String {
+    let mut text = format!("User: {}\n", message.user_message);
+    if let Some(ai_message) = message.ai_message.as_ref() {
+        writeln!(text, "Assistant: {ai_message}").ok();
+    }
+
+    text
+}
+
+fn summary_prompt(previous: Option<&str>, conversation: &str) -> String {
+    let mut prompt = String::from(
+        "Write a concise summary of the conversation below. Keep any names, facts, and decisions that may be needed to continue the conversation.\n\n",
+    );
+
+    if let Some(previous) = previous {
+        writeln!(prompt, "Summary of the conversation so far:\n{previous}\n").ok();
+    }
+
+    prompt.push_str("Conversation:\n");
+    prompt.push_str(conversation);
+    prompt.push_str("\nSummary:");
+    prompt
+}
+
+/// Summarize the messages in steps that each fit into the model's context, folding each step
+/// into the summary of the steps before it.
+fn summarize_messages(
+    model: &dyn CompletionModel,
+    previous: Option<String>,
+    messages: &[String],
+) -> Result<String, Report<ModelError>> {
+    let budget = model.context_size().saturating_sub(SUMMARY_TOKENS);
+    let mut summary = previous;
+    let mut remaining = messages;
+
+    while !remaining.is_empty() {
+        let available =
+            budget.saturating_sub(model.count_tokens(&summary_prompt(summary.as_deref(), "")));
+        if available == 0 {
+            return Err(ModelError::ParameterError)
+                .into_report()
+                .attach_printable("The summary model's context is too small");
+        }
+
+        let mut used = 0;
+        let mut count = 0;
+        for message in remaining {
+            let tokens = model.count_tokens(message);
+            if count > 0 && used + tokens > available {
+                break;
+            }
+            used += tokens;
+            count += 1;
+        }
+
+        let mut conversation = remaining[..count].concat();
+        if used > available {
+            // A single message that is too long on its own is cut short.
+            let end = chunk_text(
+                &conversation,
+                &ChunkOptions {
+                    max_tokens: available,
+                    overlap_tokens: 0,
+                },
+                |s| model.count_tokens(s),
+            )
+            .first()
+            .map(|chunk| chunk.end)
+            .unwrap_or(0);
+            conversation.truncate(end);
+        }
+
+        let output = model.complete(CompletionSubmission {
+            prompt: summary_prompt(summary.as_deref(), &conversation),
+            temperature: None,
+            sampling: SamplingParams {
+                max_tokens: Some(SUMMARY_TOKENS),
+                ..Default::default()
+            },
+        })?;
+
+        summary = Some(output.text.trim().to_string());
+        remaining = &remaining[count..];
+    }
+
+    Ok(summary.unwrap_or_default())
+}
+
+async fn summarize(
+    model: Arc<dyn CompletionModel>,
+    previous: Option<String>,
+    messages: &[ChatMessage],
+) -> Result<String, ApiReport> {
+    let messages = messages.iter().map(format_message).collect::<Vec<_>>();
+
+    let summary = tokio::task::spawn_blocking(move || {
+        summarize_messages(model.as_ref(), previous, &messages)
+            .change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
+
+    Ok(summary)
+}
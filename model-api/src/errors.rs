@@ -1,6 +1,8 @@
 use axum::{http::StatusCode, response::IntoResponse, Json};
 use error_stack::{FrameKind, IntoReport, Report, ResultExt};
-use maiven_search_store::db::DbError;
+use maiven_search_store::{
+    ask::AskError, db::DbError, models::ModelError, search::SearchError, tagging::TagError,
+};
 use serde::Serialize;
 use thiserror::Error;
 
@@ -35,6 +37,46 @@ impl ApiError {
     }
 }
 
+/// Status codes for model errors that are caused by something other than a server failure.
+fn model_error_status_code(err: &ModelError) -> Option<StatusCode> {
+    let code = match err {
+        ModelError::ParameterError => StatusCode::BAD_REQUEST,
+        ModelError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ModelError::RequestError | ModelError::AuthenticationError => StatusCode::BAD_GATEWAY,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+/// Status codes for search errors that are caused by the request.
+fn search_error_status_code(err: &SearchError) -> Option<StatusCode> {
+    let code = match err {
+        SearchError::InvalidQuery
+        | SearchError::ModelNotLoaded(..)
+        | SearchError::NoVectorIndex(_) => StatusCode::BAD_REQUEST,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+fn ask_error_status_code(err: &AskError) -> Option<StatusCode> {
+    match err {
+        AskError::ModelNotLoaded(_) | AskError::NoVectorIndex => Some(StatusCode::BAD_REQUEST),
+        AskError::NoSources => Some(StatusCode::NOT_FOUND),
+        _ => None,
+    }
+}
+
+fn tag_error_status_code(err: &TagError) -> Option<StatusCode> {
+    match err {
+        TagError::ItemNotFound(_) => Some(StatusCode::NOT_FOUND),
+        TagError::ModelNotLoaded(_) => Some(StatusCode::BAD_REQUEST),
+        _ => None,
+    }
+}
+
 impl From<ApiError> for ApiReport {
     fn from(value: ApiError) -> Self {
         ReportError(Report::new(value))
@@ -83,6 +125,16 @@ impl<T> IntoResponse for ReportError<T> {
             .find_map(|f| {
                 f.downcast_ref::<ApiError>()
                     .and_then(|e| e.status_code())
+                    .or_else(|| {
+                        f.downcast_ref::<ModelError>()
+                            .and_then(model_error_status_code)
+                    })
+                    .or_else(|| {
+                        f.downcast_ref::<SearchError>()
+                            .and_then(search_error_status_code)
+                    })
+                    .or_else(|| f.downcast_ref::<AskError>().and_then(ask_error_status_code))
+                    .or_else(|| f.downcast_ref::<TagError>().and_then(tag_error_status_code))
                     .or_else(|| f.downcast_ref::<StatusCode>().copied())
             })
             .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
@@ -8,7 +8,11 @@ use axum::{
 use base64::Engine;
 use error_stack::{IntoReport, ResultExt};
 use futures::StreamExt;
-use maiven_search_store::db::{self, items::ItemStatus};
+use maiven_search_store::{
+    db::{self, items::ItemStatus, jobs::JobPayload},
+    synthesis::{SynthesisRequest, SynthesisSource},
+    tagging::{TagSuggestionQuery, TagSuggestions},
+};
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 
@@ -44,6 +48,7 @@ struct ItemResponse {
     pub id: i64,
     pub source_id: i32,
     pub status: ItemStatus,
+    pub status_detail: Option<String>,
     pub content_type: String,
     pub external_id: String,
     pub version: i32,
@@ -70,6 +75,7 @@ impl From<maiven_search_store::db::items::ItemMetadata> for ItemResponse {
             id: item.id,
             source_id: item.source_id,
             status: item.status,
+            status_detail: item.status_detail,
             content_type: item.content_type,
             external_id: item.external_id,
             version: item.version,
@@ -129,8 +135,22 @@ async fn get_file_metadata() -> Result<impl IntoResponse, ApiReport> {
     Ok(StatusCode::NOT_IMPLEMENTED)
 }
 
-async fn update_file_metadata() -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+async fn update_file_metadata(
+    State(state): AppState,
+    Path(id): Path<i64>,
+    Json(update): Json<db::items::ItemUpdate>,
+) -> ApiResult<ItemResponse> {
+    let item = db::items::update_item(&state.pool, id, &update)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    state
+        .search_store
+        .update_text_index(id)
+        .await
+        .change_context(ApiError::Passthrough)?;
+
+    Ok(Json(ItemResponse::from(item)))
 }
 
 async fn new_file(
@@ -212,7 +232,10 @@ async fn upload_file(
     )
     .await?;
 
-    // TODO Enqueue for processing
+    state
+        .search_store
+        .enqueue_job(&JobPayload::ProcessItem { item_id: id })
+        .await?;
 
     if let Some(old_path) = item_data.saved_original_path.as_ref() {
         let old_filename = storage_dir.join(old_path);
@@ -223,8 +246,146 @@ async fn upload_file(
     Ok(StatusCode::OK)
 }
 
-async fn delete_file() -> Result<impl IntoResponse, ApiReport> {
-    Ok(StatusCode::NOT_IMPLEMENTED)
+#[derive(Deserialize, Debug, Default)]
+struct SummarizeBody {
+    /// The completion model to use. Defaults to the configured summary model.
+    model_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    job_id: i64,
+}
+
+/// Queue a job to regenerate an item's summary.
+async fn summarize_file(
+    State(state): AppState,
+    Path(id): Path<i64>,
+    body: Option<Json<SummarizeBody>>,
+) -> ApiResult<JobResponse> {
+    let body = body.map(|Json(body)| body).unwrap_or_default();
+
+    let item = db::items::lookup_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    if item.status != ItemStatus::Ready {
+        return Err(ApiError::ArgError("item has not been processed yet".to_string()).into());
+    }
+
+    let model_id = body
+        .model_id
+        .or(state.search_store.summary_options.model_id)
+        .ok_or_else(|| ApiError::ArgError("no summary model is configured".to_string()))?;
+    let loaded = state
+        .search_store
+        .loaded_completion_models
+        .read()
+        .iter()
+        .any(|m| m.id == model_id);
+    if !loaded {
+        return Err(ApiError::ModelNotLoaded("completion").into());
+    }
+
+    let job_id = state
+        .search_store
+        .enqueue_job(&JobPayload::SummarizeItem {
+            item_id: id,
+            model_id: Some(model_id),
+        })
+        .await?;
+
+    Ok(Json(JobResponse { job_id }))
+}
+
+/// Queue a job to summarize and compare a set of items. The result is saved with the job.
+async fn synthesize(
+    State(state): AppState,
+    Json(request): Json<SynthesisRequest>,
+) -> ApiResult<JobResponse> {
+    let empty = match &request.source {
+        SynthesisSource::Items { item_ids } => item_ids.is_empty(),
+        SynthesisSource::Tag { .. } => false,
+        SynthesisSource::Query { query, .. } => query.trim().is_empty(),
+    };
+    if empty || request.max_items == 0 {
+        return Err(ApiError::ArgError("no items to synthesize".to_string()).into());
+    }
+
+    let model_id = request
+        .model_id
+        .or(state.search_store.summary_options.model_id)
+        .ok_or_else(|| ApiError::ArgError("no summary model is configured".to_string()))?;
+    let loaded = state
+        .search_store
+        .loaded_completion_models
+        .read()
+        .iter()
+        .any(|m| m.id == model_id);
+    if !loaded {
+        return Err(ApiError::ModelNotLoaded("completion").into());
+    }
+
+    let job_id = state
+        .search_store
+        .enqueue_job(&JobPayload::Synthesize {
+            request: SynthesisRequest {
+                model_id: Some(model_id),
+                ..request
+            },
+        })
+        .await?;
+
+    Ok(Json(JobResponse { job_id }))
+}
+
+/// Suggest tags for an item, and optionally add the most confident ones to it.
+async fn suggest_tags(
+    State(state): AppState,
+    Path(id): Path<i64>,
+    body: Option<Json<TagSuggestionQuery>>,
+) -> ApiResult<TagSuggestions> {
+    let query = body.map(|Json(body)| body).unwrap_or_default();
+    if let Some(threshold) = query.apply_threshold {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(
+                ApiError::ArgError("apply_threshold must be between 0 and 1".to_string()).into(),
+            );
+        }
+    }
+
+    let suggestions = state
+        .search_store
+        .suggest_tags(id, &query)
+        .await
+        .change_context(ApiError::Passthrough)?;
+
+    Ok(Json(suggestions))
+}
+
+async fn delete_file(
+    State(state): AppState,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ApiReport> {
+    let item = db::items::lookup_by_id(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    db::items::delete_item(&state.pool, id).await?;
+
+    state.search_store.vector_indexes.remove_item(id);
+    state
+        .search_store
+        .remove_from_text_index(id)
+        .await
+        .change_context(ApiError::Passthrough)?;
+
+    if let Some(path) = item.saved_original_path.as_ref() {
+        let filename = std::path::Path::new(&state.search_store.file_storage_location).join(path);
+        // TODO report error some other way
+        tokio::fs::remove_file(filename).await.ok();
+    }
+
+    Ok(StatusCode::OK)
 }
 
 pub fn create_router() -> Router<AppStateContents> {
@@ -242,4 +403,7 @@ pub fn create_router() -> Router<AppStateContents> {
                 .delete(delete_file),
         )
         .route("/id/:id/upload", post(upload_file))
+        .route("/id/:id/summarize", post(summarize_file))
+        .route("/id/:id/suggest_tags", post(suggest_tags))
+        .route("/synthesize", post(synthesize))
 }
@@ -0,0 +1,24 @@
+use axum::{
+    extract::{Path, State},
+    routing::get,
+    Json, Router,
+};
+use maiven_search_store::db::{self, jobs::JobInfo};
+
+use crate::{
+    errors::{ApiError, ApiResult},
+    AppState, AppStateContents,
+};
+
+/// Get the status of a background job, and its result once it has finished.
+async fn get_job(State(state): AppState, Path(id): Path<i64>) -> ApiResult<JobInfo> {
+    let job = db::jobs::get_job(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(job))
+}
+
+pub fn create_router() -> Router<AppStateContents> {
+    Router::new().route("/:id", get(get_job))
+}
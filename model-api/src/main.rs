@@ -1,8 +1,14 @@
+mod ask;
 mod chat;
+mod chat_context;
 mod errors;
 mod items;
+mod jobs;
 mod models;
+mod search;
 mod sources;
+mod streaming;
+mod system_messages;
 mod tracing_config;
 
 use std::{path::PathBuf, sync::Arc};
@@ -12,12 +18,18 @@ use error_stack::{IntoReport, Report, ResultExt};
 use maiven_search_store::{models::download::ModelCache, SearchStore};
 use sqlx::postgres::PgPoolOptions;
 use thiserror::Error;
+use tracing::error;
 
 pub struct AppStateInner {
     pub pool: sqlx::PgPool,
     pub search_store: SearchStore,
 }
 
+/// The number of background jobs to run at once, unless set by `JOB_WORKERS`.
+const DEFAULT_JOB_WORKERS: usize = 2;
+/// Where to store search indexes, unless set by `INDEX_LOCATION`.
+const DEFAULT_INDEX_LOCATION: &str = "storage/indexes";
+
 pub type AppStateContents = Arc<AppStateInner>;
 pub type AppState = State<AppStateContents>;
 
@@ -37,6 +49,9 @@ async fn main() -> Result<(), Report<MainError>> {
         .attach_printable("FILE_STORAGE_LOCATION")
         .change_context(MainError {})?;
 
+    let index_dir =
+        std::env::var("INDEX_LOCATION").unwrap_or_else(|_| DEFAULT_INDEX_LOCATION.to_string());
+
     let model_cache_dir = std::env::var("MODEL_DIR")
         .into_report()
         .attach_printable("MODEL_DIR")
@@ -51,17 +66,56 @@ async fn main() -> Result<(), Report<MainError>> {
         .attach_printable("DATABASE_URL")
         .change_context(MainError {})?;
 
-    let app_state = AppStateInner {
-        pool: pool.clone(),
-        search_store: SearchStore::new(pool, file_storage_dir, model_cache),
-    };
+    let mut search_store = SearchStore::new(
+        pool.clone(),
+        file_storage_dir,
+        PathBuf::from(index_dir),
+        model_cache,
+    )
+    .change_context(MainError {})?;
+
+    search_store.summary_options.model_id = std::env::var("SUMMARY_MODEL_ID")
+        .ok()
+        .and_then(|id| id.parse::<i32>().ok());
+    search_store.tag_options.model_id = std::env::var("TAG_MODEL_ID")
+        .ok()
+        .and_then(|id| id.parse::<i32>().ok());
+    search_store.tag_options.auto_apply_threshold = std::env::var("TAG_AUTO_APPLY_THRESHOLD")
+        .ok()
+        .and_then(|t| t.parse::<f32>().ok());
+
+    let app_state = AppStateInner { pool, search_store };
+
+    let app_state = Arc::new(app_state);
+
+    {
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.search_store.sync_text_index().await {
+                error!(error=?e, "Failed to sync text index");
+            }
+        });
+    }
+
+    let job_workers = std::env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|w| w.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_JOB_WORKERS);
+    for _ in 0..job_workers {
+        let state = app_state.clone();
+        tokio::spawn(async move { state.search_store.run_job_worker().await });
+    }
 
     let app = Router::new()
         .nest("/models", models::create_router())
         .nest("/chats", chat::create_router())
+        .nest("/system_messages", system_messages::create_router())
         .nest("/items", items::create_router())
         .nest("/sources", sources::create_router())
-        .with_state(Arc::new(app_state));
+        .nest("/search", search::create_router())
+        .nest("/ask", ask::create_router())
+        .nest("/jobs", jobs::create_router())
+        .with_state(app_state);
 
     axum::Server::bind(&"127.0.0.1:9824".parse().unwrap())
         .serve(app.into_make_service())
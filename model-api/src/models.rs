@@ -1,22 +1,24 @@
 use axum::{
     extract::{Path, State},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use error_stack::{ensure, IntoReport, ResultExt};
 use maiven_search_store::{
-    check_temperature,
+    check_sampling_params, check_temperature,
     db::models,
     models::{
         chat::{ChatMessage, ChatRole, ChatSubmission},
-        completion::CompletionSubmission,
-        ModelDefinition,
+        completion::{CompletionSubmission, FinishReason, SamplingParams, TokenUsage},
+        ModelCategory, ModelDefinition,
     },
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    errors::{ApiError, ApiReport, ApiResult, IntoPassthrough, PassthroughReport, ReportError},
+    errors::{ApiError, ApiReport, ApiResult, IntoPassthrough, ReportError},
+    streaming::stream_generation,
     AppState, AppStateContents,
 };
 
@@ -56,14 +58,32 @@ async fn load_model(State(state): AppState, Path(id): Path<i32>) -> Result<(), A
         .into_report()?;
 
     if !state.search_store.is_loaded(model.id) {
+        let model_id = model.id;
+        let is_bi_encoder = model.category == ModelCategory::BiEncoder;
+        let load_state = state.clone();
         tokio::task::spawn_blocking(move || {
-            state
+            load_state
                 .search_store
                 .load_model(&model)
                 .change_context(ApiError::Passthrough)
         })
         .await
         .passthrough_error()??;
+
+        if is_bi_encoder {
+            state
+                .search_store
+                .load_vector_index(model_id)
+                .await
+                .change_context(ApiError::Passthrough)?;
+
+            // Items processed while the model was not loaded have no chunks for it.
+            state
+                .search_store
+                .enqueue_missing_embeddings(model_id)
+                .await
+                .change_context(ApiError::Passthrough)?;
+        }
     }
 
     Ok(())
@@ -72,6 +92,8 @@ async fn load_model(State(state): AppState, Path(id): Path<i32>) -> Result<(), A
 #[derive(Serialize)]
 struct ChatResult {
     response: String,
+    finish_reason: FinishReason,
+    usage: TokenUsage,
 }
 
 #[derive(Deserialize)]
@@ -79,18 +101,34 @@ struct ChatBody {
     system: Option<String>,
     prompt: String,
     temperature: Option<f32>,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    /// Stream the response as Server-Sent Events
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct CompletionBody {
+    #[serde(flatten)]
+    submission: CompletionSubmission,
+    /// Stream the response as Server-Sent Events
+    #[serde(default)]
+    stream: bool,
 }
 
 #[derive(Serialize)]
 struct CompletionResult {
     response: String,
+    finish_reason: FinishReason,
+    usage: TokenUsage,
 }
 
 async fn run_chat_model(
     State(state): AppState,
     Path(id): Path<i32>,
     Json(body): Json<ChatBody>,
-) -> Result<Json<ChatResult>, ApiReport> {
+) -> Result<Response, ApiReport> {
     let model = state
         .search_store
         .loaded_chat_models
@@ -103,6 +141,8 @@ async fn run_chat_model(
 
     check_temperature(&body.temperature)
         .change_context(ApiError::ArgError("temperature".to_string()))?;
+    check_sampling_params(&body.sampling)
+        .change_context(ApiError::ArgError("sampling parameters".to_string()))?;
 
     let mut messages = Vec::with_capacity(2);
     if let Some(system) = body.system {
@@ -119,23 +159,45 @@ async fn run_chat_model(
         name: None,
     });
 
-    let answer = model
-        .chat(ChatSubmission {
-            temperature: body.temperature,
-            messages,
-        })
-        .passthrough_error()?;
+    let submission = ChatSubmission {
+        temperature: body.temperature,
+        sampling: body.sampling,
+        messages,
+    };
+
+    if body.stream {
+        let response = stream_generation(move |on_token| {
+            model
+                .chat_streaming(submission, on_token)
+                .map(|answer| ChatResult {
+                    response: answer.message.content,
+                    finish_reason: answer.finish_reason,
+                    usage: answer.usage,
+                })
+        });
+
+        return Ok(response.into_response());
+    }
+
+    let answer = tokio::task::spawn_blocking(move || {
+        model.chat(submission).change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
 
     Ok(Json(ChatResult {
-        response: answer.content,
-    }))
+        response: answer.message.content,
+        finish_reason: answer.finish_reason,
+        usage: answer.usage,
+    })
+    .into_response())
 }
 
 async fn run_completion_model(
     State(state): AppState,
     Path(id): Path<i32>,
-    Json(body): Json<CompletionSubmission>,
-) -> ApiResult<CompletionResult> {
+    Json(body): Json<CompletionBody>,
+) -> Result<Response, ApiReport> {
     let model = state
         .search_store
         .loaded_completion_models
@@ -146,12 +208,139 @@ async fn run_completion_model(
         .model
         .clone();
 
-    check_temperature(&body.temperature)
+    let submission = body.submission;
+    check_temperature(&submission.temperature)
         .change_context(ApiError::ArgError("temperature".to_string()))?;
+    check_sampling_params(&submission.sampling)
+        .change_context(ApiError::ArgError("sampling parameters".to_string()))?;
+
+    if body.stream {
+        let response = stream_generation(move |on_token| {
+            model
+                .complete_streaming(submission, on_token)
+                .map(|answer| CompletionResult {
+                    response: answer.text,
+                    finish_reason: answer.finish_reason,
+                    usage: answer.usage,
+                })
+        });
+
+        return Ok(response.into_response());
+    }
+
+    let answer = tokio::task::spawn_blocking(move || {
+        model
+            .complete(submission)
+            .change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
+
+    Ok(Json(CompletionResult {
+        response: answer.text,
+        finish_reason: answer.finish_reason,
+        usage: answer.usage,
+    })
+    .into_response())
+}
+
+#[derive(Deserialize)]
+struct EmbedBody {
+    sentences: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct EmbedResult {
+    dimensions: i64,
+    embeddings: Vec<Vec<f32>>,
+}
+
+async fn run_bi_encoder_model(
+    State(state): AppState,
+    Path(id): Path<i32>,
+    Json(body): Json<EmbedBody>,
+) -> ApiResult<EmbedResult> {
+    let model = state
+        .search_store
+        .loaded_bi_encoders
+        .read()
+        .iter()
+        .find(|model| model.id == id)
+        .ok_or(ApiError::ModelNotLoaded("bi-encoder"))?
+        .model
+        .clone();
+
+    if body.sentences.is_empty() {
+        return Err(ApiError::ArgError("sentences must not be empty".to_string()).into());
+    }
+
+    let dimensions = model.dimensions();
+    let embeddings = tokio::task::spawn_blocking(move || {
+        model
+            .encode(&body.sentences)
+            .change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
+
+    Ok(Json(EmbedResult {
+        dimensions,
+        embeddings,
+    }))
+}
+
+#[derive(Deserialize)]
+struct RerankBody {
+    query: String,
+    passages: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RerankedPassage {
+    index: usize,
+    passage: String,
+    score: f32,
+}
+
+#[derive(Serialize)]
+struct RerankResult {
+    results: Vec<RerankedPassage>,
+}
+
+async fn run_cross_encoder_model(
+    State(state): AppState,
+    Path(id): Path<i32>,
+    Json(body): Json<RerankBody>,
+) -> ApiResult<RerankResult> {
+    let model = state
+        .search_store
+        .loaded_cross_encoders
+        .read()
+        .iter()
+        .find(|model| model.id == id)
+        .ok_or(ApiError::ModelNotLoaded("cross-encoder"))?
+        .model
+        .clone();
 
-    let answer = model.complete(body).passthrough_error()?;
+    let (ranked, mut passages) = tokio::task::spawn_blocking(move || {
+        model
+            .rank(&body.query, &body.passages)
+            .map(|ranked| (ranked, body.passages))
+            .change_context(ApiError::Passthrough)
+    })
+    .await
+    .passthrough_error()??;
+
+    let results = ranked
+        .into_iter()
+        .map(|r| RerankedPassage {
+            index: r.index,
+            passage: std::mem::take(&mut passages[r.index]),
+            score: r.score,
+        })
+        .collect();
 
-    Ok(Json(CompletionResult { response: answer }))
+    Ok(Json(RerankResult { results }))
 }
 
 pub fn create_router() -> Router<AppStateContents> {
@@ -160,4 +349,6 @@ pub fn create_router() -> Router<AppStateContents> {
         .route("/:id/load", post(load_model))
         .route("/:id/chat", post(run_chat_model))
         .route("/:id/complete", post(run_completion_model))
+        .route("/:id/embed", post(run_bi_encoder_model))
+        .route("/:id/rerank", post(run_cross_encoder_model))
 }
@@ -0,0 +1,35 @@
+use axum::{extract::State, routing::post, Json, Router};
+use error_stack::ResultExt;
+use maiven_search_store::search::{SearchQuery, SearchResult};
+use serde::Serialize;
+
+use crate::{
+    errors::{ApiError, ApiResult},
+    AppState, AppStateContents,
+};
+
+#[derive(Serialize)]
+struct SearchResponse {
+    results: Vec<SearchResult>,
+}
+
+async fn search(
+    State(state): AppState,
+    Json(query): Json<SearchQuery>,
+) -> ApiResult<SearchResponse> {
+    if query.query.trim().is_empty() {
+        return Err(ApiError::ArgError("query must not be empty".to_string()).into());
+    }
+
+    let results = state
+        .search_store
+        .search(&query)
+        .await
+        .change_context(ApiError::Passthrough)?;
+
+    Ok(Json(SearchResponse { results }))
+}
+
+pub fn create_router() -> Router<AppStateContents> {
+    Router::new().route("/", post(search))
+}
@@ -0,0 +1,56 @@
+use std::{convert::Infallible, ops::ControlFlow};
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use error_stack::Report;
+use futures::{channel::mpsc, Stream, StreamExt};
+use maiven_search_store::models::{completion::TokenCallback, ModelError};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct TokenEvent<'a> {
+    token: &'a str,
+}
+
+#[derive(Serialize)]
+struct ErrorEvent {
+    message: String,
+}
+
+/// Run a model on a blocking thread and stream its output as Server-Sent Events.
+///
+/// Each piece of generated text is sent as a `token` event. When the model finishes, the value
+/// returned from `run` is sent as a `done` event, or an `error` event if it failed.
+pub fn stream_generation<T, F>(run: F) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    T: Serialize,
+    F: FnOnce(TokenCallback) -> Result<T, Report<ModelError>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded();
+
+    tokio::task::spawn_blocking(move || {
+        let mut on_token = |token: &str| {
+            let event = Event::default()
+                .event("token")
+                .json_data(TokenEvent { token });
+
+            match event {
+                Ok(event) if tx.unbounded_send(event).is_ok() => ControlFlow::Continue(()),
+                // The client has disconnected, so there's no point in generating any more.
+                _ => ControlFlow::Break(()),
+            }
+        };
+
+        let final_event = match run(&mut on_token) {
+            Ok(value) => Event::default().event("done").json_data(value),
+            Err(e) => Event::default().event("error").json_data(ErrorEvent {
+                message: e.current_context().to_string(),
+            }),
+        };
+
+        if let Ok(event) = final_event {
+            tx.unbounded_send(event).ok();
+        }
+    });
+
+    Sse::new(rx.map(Ok)).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,80 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use maiven_search_store::db::chat_system_messages::{
+    self, ChatSystemMessage, ChatSystemMessageUpdate, NewChatSystemMessage,
+};
+use serde::Serialize;
+
+use crate::{
+    errors::{ApiError, ApiReport, ApiResult},
+    AppState, AppStateContents,
+};
+
+#[derive(Serialize)]
+struct SystemMessagesResult {
+    system_messages: Vec<ChatSystemMessage>,
+}
+
+async fn list_system_messages(State(state): AppState) -> ApiResult<SystemMessagesResult> {
+    let system_messages = chat_system_messages::list_chat_system_messages(&state.pool).await?;
+    Ok(Json(SystemMessagesResult { system_messages }))
+}
+
+async fn get_system_message(
+    State(state): AppState,
+    Path(id): Path<i64>,
+) -> ApiResult<ChatSystemMessage> {
+    let message = chat_system_messages::get_chat_system_message(&state.pool, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(message))
+}
+
+async fn new_system_message(
+    State(state): AppState,
+    Json(body): Json<NewChatSystemMessage>,
+) -> ApiResult<ChatSystemMessage> {
+    let message = chat_system_messages::create_chat_system_message(&state.pool, &body).await?;
+    Ok(Json(message))
+}
+
+async fn update_system_message(
+    State(state): AppState,
+    Path(id): Path<i64>,
+    Json(body): Json<ChatSystemMessageUpdate>,
+) -> ApiResult<ChatSystemMessage> {
+    let message = chat_system_messages::update_chat_system_message(&state.pool, id, &body)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(message))
+}
+
+async fn delete_system_message(
+    State(state): AppState,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ApiReport> {
+    let deleted = chat_system_messages::delete_chat_system_message(&state.pool, id).await?;
+    if !deleted {
+        return Err(ApiError::NotFound.into());
+    }
+
+    Ok(StatusCode::OK)
+}
+
+pub fn create_router() -> Router<AppStateContents> {
+    Router::new()
+        .route("/", get(list_system_messages).post(new_system_message))
+        .route(
+            "/:id",
+            get(get_system_message)
+                .put(update_system_message)
+                .delete(delete_system_message),
+        )
+}
@@ -0,0 +1,2 @@
+DROP INDEX chat_messages_parent_id_idx;
+ALTER TABLE chat_sessions DROP COLUMN parent_message_id;
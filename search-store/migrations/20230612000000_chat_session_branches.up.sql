@@ -0,0 +1,6 @@
+ALTER TABLE chat_sessions
+  ADD COLUMN parent_message_id BIGINT REFERENCES chat_messages(id) ON DELETE SET NULL;
+
+COMMENT ON COLUMN chat_sessions.parent_message_id IS 'If this chat session branched from a message in another session, this is the message it continues from.';
+
+CREATE INDEX ON chat_messages(parent_id);
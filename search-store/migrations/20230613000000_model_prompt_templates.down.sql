@@ -0,0 +1 @@
+UPDATE models SET params = params - 'prompt_template' WHERE params ? 'prompt_template';
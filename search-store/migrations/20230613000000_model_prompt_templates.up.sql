@@ -0,0 +1,3 @@
+UPDATE models
+  SET params = params || jsonb_build_object('prompt_template', 'redpajama')
+  WHERE name LIKE 'RedPajama-INCITE-%' AND category = 'chat' AND params->>'code' = 'ggml';
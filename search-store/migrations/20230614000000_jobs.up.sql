@@ -0,0 +1,21 @@
+CREATE TYPE job_status AS ENUM ('pending', 'running', 'done', 'failed');
+
+CREATE TABLE jobs (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  payload JSONB NOT NULL,
+  status job_status NOT NULL DEFAULT 'pending',
+  attempts INTEGER NOT NULL DEFAULT 0,
+  max_attempts INTEGER NOT NULL DEFAULT 3,
+  run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  locked_at TIMESTAMPTZ,
+  last_error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX jobs_pending ON jobs (run_at) WHERE status = 'pending';
+CREATE INDEX jobs_running ON jobs (locked_at) WHERE status = 'running';
+
+COMMENT ON COLUMN jobs.payload IS 'The type of job and its arguments';
+COMMENT ON COLUMN jobs.run_at IS 'The job will not run before this time. This is used to delay retries.';
+COMMENT ON COLUMN jobs.locked_at IS 'When a worker started running the job. Jobs that have been running for too long are assumed to have crashed and are retried.';
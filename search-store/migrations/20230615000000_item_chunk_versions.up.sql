@@ -0,0 +1,3 @@
+ALTER TABLE item_chunks ADD COLUMN item_version INTEGER NOT NULL DEFAULT 0;
+
+COMMENT ON COLUMN item_chunks.item_version IS 'The version of the item that the chunk was created from';
@@ -0,0 +1,3 @@
+ALTER TABLE jobs ADD COLUMN result JSONB;
+
+COMMENT ON COLUMN jobs.result IS 'The output of jobs that produce one, set when the job is done';
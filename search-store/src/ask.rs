@@ -0,0 +1,377 @@
+//! Answer questions from the content of the search store. The most relevant chunks are given to
+//! a chat model as numbered sources, and the answer cites them by number.
+
+use std::collections::HashMap;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    db::{self, items::ItemStatus},
+    filter::SearchFilter,
+    models::{
+        chat::{context::MESSAGE_OVERHEAD_TOKENS, ChatMessage, ChatRole, ChatSubmission},
+        completion::{FinishReason, SamplingParams, TokenUsage},
+    },
+    search::char_span,
+    SearchStore,
+};
+
+/// When reranking, the cross-encoder chooses from this many chunks for each source.
+const RERANK_CHUNKS_PER_SOURCE: usize = 3;
+/// The most sources to look for. Larger requests are clamped to this.
+const MAX_SOURCES: usize = 100;
+
+/// A citation of one or more sources, such as [1] or [2, 3]
+static CITATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[(\d+(?:\s*,\s*\d+)*)\]").expect("valid regex"));
+
+const SYSTEM_PROMPT: &str = "Answer the user's question using only the numbered sources that \
+they provide. Cite the sources that support each part of your answer inline, using their \
+numbers in square brackets, such as [1] or [2, 3]. If the sources do not contain the answer, \
+say that you do not know.";
+
+#[derive(Debug, Error)]
+pub enum AskError {
+    #[error("Database error")]
+    Db,
+    #[error("Search failed")]
+    Search,
+    #[error("Model {0} is not a loaded chat model")]
+    ModelNotLoaded(i32),
+    #[error("No vector index is loaded to find sources with")]
+    NoVectorIndex,
+    #[error("No sources were found for the question")]
+    NoSources,
+    #[error("Model failed")]
+    Model,
+    #[error("Background task failed")]
+    Task,
+}
+
+fn default_max_sources() -> usize {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AskQuery {
+    pub question: String,
+    pub chat_model_id: i32,
+    /// Limit the sources to a subset of items
+    #[serde(default)]
+    pub filter: SearchFilter,
+    /// The most sources to give the chat model, up to 100. Fewer are used if they do not all
+    /// fit in its context.
+    #[serde(default = "default_max_sources")]
+    pub max_sources: usize,
+    pub bi_encoder_id: Option<i32>,
+    pub cross_encoder_id: Option<i32>,
+    pub temperature: Option<f32>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+/// A chunk of an item that was given to the chat model.
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    /// The number that the answer uses to refer to this source
+    pub number: usize,
+    pub item_id: i64,
+    pub title: Option<String>,
+    /// The index of the first character of the chunk in the item's processed content
+    pub start_idx: i32,
+    /// The index of the last character of the chunk
+    pub end_idx: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AskAnswer {
+    pub answer: String,
+    /// The sources that the answer cites
+    pub citations: Vec<Citation>,
+    /// Every source that was given to the chat model
+    pub sources: Vec<Citation>,
+    pub finish_reason: FinishReason,
+    pub usage: TokenUsage,
+}
+
+/// A chunk that matches the question.
+struct SourceChunk {
+    item_id: i64,
+    title: Option<String>,
+    start_idx: i32,
+    end_idx: i32,
+    text: String,
+}
+
+fn format_source(number: usize, title: Option<&str>, text: &str) -> String {
+    format!("[{number}] {}\n{text}", title.unwrap_or("Untitled"))
+}
+
+/// Choose which sources to use, given their sizes in order of relevance. Sources that do not
+/// fit in the remaining budget are skipped, so that smaller ones after them can still be used.
+fn pack_sources(tokens: &[usize], budget: usize) -> Vec<usize> {
+    let mut remaining = budget;
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, &tokens)| {
+            if tokens > remaining {
+                return false;
+            }
+
+            remaining -= tokens;
+            true
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Find the source numbers cited in an answer, in the order they first appear.
+pub(crate) fn cited_sources(answer: &str) -> Vec<usize> {
+    let mut cited = Vec::new();
+    for m in CITATION.captures_iter(answer) {
+        for number in m[1].split(',') {
+            if let Ok(number) = number.trim().parse::<usize>() {
+                if !cited.contains(&number) {
+                    cited.push(number);
+                }
+            }
+        }
+    }
+
+    cited
+}
+
+impl SearchStore {
+    /// Find the chunks that best match the question, best first. Unlike a search, which returns
+    /// the best chunk of each item, this can return several chunks from one item.
+    async fn find_sources(&self, query: &AskQuery) -> Result<Vec<SourceChunk>, Report<AskError>> {
+        let search = self
+            .vector_search_for(query.bi_encoder_id)
+            .change_context(AskError::Search)?
+            .ok_or(AskError::NoVectorIndex)
+            .into_report()?;
+        let cross_encoder = query
+            .cross_encoder_id
+            .map(|id| self.cross_encoder(id))
+            .transpose()
+            .change_context(AskError::Search)?;
+
+        let filter = self
+            .compile_filter(&query.filter)
+            .await
+            .change_context(AskError::Db)?;
+        if filter.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let max_sources = query.max_sources.clamp(1, MAX_SOURCES);
+        let limit = if cross_encoder.is_some() {
+            max_sources * RERANK_CHUNKS_PER_SOURCE
+        } else {
+            max_sources
+        };
+        let matches = {
+            let model = search.model.clone();
+            let index = search.index.clone();
+            let question = query.question.clone();
+            tokio::task::spawn_blocking(move || {
+                let embedding = model
+                    .encode(&[question])
+                    .change_context(AskError::Model)?
+                    .pop()
+                    .unwrap_or_default();
+                Ok::<_, Report<AskError>>(index.search(&embedding, limit, Some(&filter)))
+            })
+            .await
+            .into_report()
+            .change_context(AskError::Task)??
+        };
+
+        let mut item_ids = matches.iter().map(|m| m.chunk.item_id).collect::<Vec<_>>();
+        item_ids.sort_unstable();
+        item_ids.dedup();
+
+        // An item may have changed since the filter was compiled, so skip any that are no
+        // longer ready.
+        let items = db::items::get_items_text(&self.pg, &item_ids)
+            .await
+            .change_context(AskError::Db)?
+            .into_iter()
+            .filter(|item| item.status == ItemStatus::Ready)
+            .map(|item| (item.id, item))
+            .collect::<HashMap<_, _>>();
+        let chunk_keys = matches
+            .iter()
+            .map(|m| (m.chunk.item_id, m.chunk.sequence_num))
+            .collect::<Vec<_>>();
+        let spans = db::item_chunks::get_chunk_spans(&self.pg, search.model_id, &chunk_keys)
+            .await
+            .change_context(AskError::Db)?
+            .into_iter()
+            .map(|c| ((c.item_id, c.sequence_num), (c.start_idx, c.end_idx)))
+            .collect::<HashMap<_, _>>();
+
+        let mut sources = matches
+            .into_iter()
+            .filter_map(|m| {
+                let item = items.get(&m.chunk.item_id)?;
+                let (start_idx, end_idx) = *spans.get(&(m.chunk.item_id, m.chunk.sequence_num))?;
+                Some(SourceChunk {
+                    item_id: item.id,
+                    title: item.title.clone().or_else(|| item.name.clone()),
+                    start_idx,
+                    end_idx,
+                    text: char_span(
+                        item.processed_content.as_deref().unwrap_or_default(),
+                        start_idx,
+                        end_idx,
+                    ),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(cross_encoder) = cross_encoder {
+            let passages = sources.iter().map(|s| s.text.clone()).collect::<Vec<_>>();
+            let question = query.question.clone();
+            let ranked =
+                tokio::task::spawn_blocking(move || cross_encoder.rank(&question, &passages))
+                    .await
+                    .into_report()
+                    .change_context(AskError::Task)?
+                    .change_context(AskError::Model)?;
+
+            let mut unranked = sources.into_iter().map(Some).collect::<Vec<_>>();
+            sources = ranked
+                .into_iter()
+                .filter_map(|r| unranked.get_mut(r.index)?.take())
+                .collect();
+        }
+
+        sources.truncate(max_sources);
+        Ok(sources)
+    }
+
+    /// Answer a question using the chunks that best match it.
+    pub async fn ask(&self, query: &AskQuery) -> Result<AskAnswer, Report<AskError>> {
+        let model = self
+            .loaded_chat_models
+            .read()
+            .iter()
+            .find(|m| m.id == query.chat_model_id)
+            .map(|m| m.model.clone())
+            .ok_or(AskError::ModelNotLoaded(query.chat_model_id))
+            .into_report()?;
+
+        let candidates = self.find_sources(query).await?;
+        if candidates.is_empty() {
+            return Err(AskError::NoSources).into_report();
+        }
+
+        let context_size = model.context_size();
+        let output_tokens = query.sampling.max_tokens.unwrap_or(context_size / 4);
+        let fixed_tokens = model.count_tokens(SYSTEM_PROMPT)
+            + model.count_tokens(&query.question)
+            + MESSAGE_OVERHEAD_TOKENS * 2;
+        let budget = context_size.saturating_sub(output_tokens + fixed_tokens);
+
+        let source_tokens = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                model.count_tokens(&format_source(i + 1, source.title.as_deref(), &source.text)) + 1
+            })
+            .collect::<Vec<_>>();
+
+        let mut sources = Vec::new();
+        let mut prompt = String::from("Sources:\n\n");
+        for (i, index) in pack_sources(&source_tokens, budget).into_iter().enumerate() {
+            let source = &candidates[index];
+            prompt.push_str(&format_source(i + 1, source.title.as_deref(), &source.text));
+            prompt.push_str("\n\n");
+
+            sources.push(Citation {
+                number: i + 1,
+                item_id: source.item_id,
+                title: source.title.clone(),
+                start_idx: source.start_idx,
+                end_idx: source.end_idx,
+            });
+        }
+
+        if sources.is_empty() {
+            return Err(AskError::NoSources)
+                .into_report()
+                .attach_printable("None of the sources fit in the model's context");
+        }
+
+        prompt.push_str("Question: ");
+        prompt.push_str(&query.question);
+
+        let submission = ChatSubmission {
+            messages: vec![
+                ChatMessage {
+                    role: ChatRole::System,
+                    content: SYSTEM_PROMPT.to_string(),
+                    name: None,
+                },
+                ChatMessage {
+                    role: ChatRole::User,
+                    content: prompt,
+                    name: None,
+                },
+            ],
+            temperature: query.temperature,
+            // The sources were packed assuming this much room for the answer, so the answer
+            // can't be allowed to run past it.
+            sampling: SamplingParams {
+                max_tokens: Some(output_tokens),
+                ..query.sampling.clone()
+            },
+        };
+
+        let output = tokio::task::spawn_blocking(move || model.chat(submission))
+            .await
+            .into_report()
+            .change_context(AskError::Task)?
+            .change_context(AskError::Model)?;
+
+        let citations = cited_sources(&output.message.content)
+            .into_iter()
+            .filter_map(|number| sources.iter().find(|s| s.number == number).cloned())
+            .collect();
+
+        Ok(AskAnswer {
+            answer: output.message.content,
+            citations,
+            sources,
+            finish_reason: output.finish_reason,
+            usage: output.usage,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cited_sources, pack_sources};
+
+    #[test]
+    fn pack() {
+        assert_eq!(pack_sources(&[10, 20, 30], 100), vec![0, 1, 2]);
+        assert_eq!(pack_sources(&[10, 50, 30, 5], 50), vec![0, 2, 3]);
+        assert!(pack_sources(&[10], 5).is_empty());
+    }
+
+    #[test]
+    fn citations() {
+        assert_eq!(
+            cited_sources("Rust has owners [2]. Values are dropped [1, 3][2]. See [x] and [4 ]."),
+            vec![2, 1, 3]
+        );
+        assert!(cited_sources("No citations here.").is_empty());
+    }
+}
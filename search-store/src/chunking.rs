@@ -0,0 +1,270 @@
+//! Split documents into overlapping chunks that fit within the input size of an embedding
+//! model, breaking on paragraph and sentence boundaries when possible.
+
+use std::ops::Range;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static PARAGRAPH_BREAK: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\s*\n").expect("valid regex"));
+static SENTENCE_BREAK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"[.!?]["')\]]*\s+"#).expect("valid regex"));
+static WORD: Lazy<Regex> = Lazy::new(|| Regex::new(r"\S+").expect("valid regex"));
+
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    /// The maximum number of tokens in a chunk
+    pub max_tokens: usize,
+    /// How many tokens from the end of each chunk to repeat at the start of the next one
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            overlap_tokens: 32,
+        }
+    }
+}
+
+/// A chunk of a document, as a range of byte offsets into the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub start: usize,
+    pub end: usize,
+}
+
+struct Segment {
+    range: Range<usize>,
+    tokens: usize,
+}
+
+struct Splitter<'a, F: Fn(&str) -> usize> {
+    text: &'a str,
+    max_tokens: usize,
+    count_tokens: F,
+    segments: Vec<Segment>,
+}
+
+impl<'a, F: Fn(&str) -> usize> Splitter<'a, F> {
+    /// Add a segment, splitting it further using `next_level` if it is too large.
+    fn add(&mut self, range: Range<usize>, next_level: Option<fn(&mut Self, Range<usize>)>) {
+        let segment = &self.text[range.clone()];
+        let trimmed_start = range.start + (segment.len() - segment.trim_start().len());
+        let trimmed_end = range.end - (segment.len() - segment.trim_end().len());
+        if trimmed_start >= trimmed_end {
+            return;
+        }
+
+        let range = trimmed_start..trimmed_end;
+        let tokens = (self.count_tokens)(&self.text[range.clone()]);
+        match next_level {
+            Some(split) if tokens > self.max_tokens => split(self, range),
+            _ => self.segments.push(Segment { range, tokens }),
+        }
+    }
+
+    fn split_on(
+        &mut self,
+        range: Range<usize>,
+        separator: &Regex,
+        next: fn(&mut Self, Range<usize>),
+    ) {
+        let text = self.text;
+        let mut start = range.start;
+        for sep in separator.find_iter(&text[range.clone()]) {
+            let end = range.start + sep.end();
+            self.add(start..end, Some(next));
+            start = end;
+        }
+        self.add(start..range.end, Some(next));
+    }
+
+    fn split_paragraphs(&mut self, range: Range<usize>) {
+        self.split_on(range, &PARAGRAPH_BREAK, Self::split_sentences);
+    }
+
+    fn split_sentences(&mut self, range: Range<usize>) {
+        self.split_on(range, &SENTENCE_BREAK, Self::split_words);
+    }
+
+    /// The last resort for text with no sentence breaks: group words until the limit is reached.
+    fn split_words(&mut self, range: Range<usize>) {
+        let text = self.text;
+        let words = WORD
+            .find_iter(&text[range.clone()])
+            .map(|word| range.start + word.start()..range.start + word.end())
+            .collect::<Vec<_>>();
+
+        let mut first = 0;
+        while first < words.len() {
+            // Binary search for the most words that fit, assuming that adding a word never
+            // lowers the count. Every word is at least one token, so no more than `max_tokens`
+            // words can fit.
+            let count =
+                |last: usize| (self.count_tokens)(&text[words[first].start..words[last].end]);
+            let mut low = first;
+            let mut high = (words.len() - 1).min(first + self.max_tokens.max(1) - 1);
+            while low < high {
+                let mid = (low + high).div_ceil(2);
+                if count(mid) <= self.max_tokens {
+                    low = mid;
+                } else {
+                    high = mid - 1;
+                }
+            }
+
+            self.add(words[first].start..words[low].end, None);
+            first = low + 1;
+        }
+    }
+}
+
+/// Split `text` into chunks, using `count_tokens` to measure the size of each piece.
+pub fn chunk_text(
+    text: &str,
+    options: &ChunkOptions,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<Chunk> {
+    let mut splitter = Splitter {
+        text,
+        max_tokens: options.max_tokens,
+        count_tokens,
+        segments: Vec::new(),
+    };
+    splitter.add(0..text.len(), Some(Splitter::split_paragraphs));
+    let segments = splitter.segments;
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut current_tokens = 0;
+
+    let make_chunk = |current: &[usize]| Chunk {
+        start: segments[current[0]].range.start,
+        end: segments[current[current.len() - 1]].range.end,
+    };
+
+    for (i, segment) in segments.iter().enumerate() {
+        if !current.is_empty() && current_tokens + segment.tokens > options.max_tokens {
+            chunks.push(make_chunk(&current));
+
+            // Carry the trailing segments over to the next chunk, as long as they fit in the
+            // overlap and leave room for the new segment.
+            let mut keep = 0;
+            let mut keep_tokens = 0;
+            for &j in current.iter().rev() {
+                let tokens = segments[j].tokens;
+                if keep + 1 >= current.len()
+                    || keep_tokens + tokens > options.overlap_tokens
+                    || keep_tokens + tokens + segment.tokens > options.max_tokens
+                {
+                    break;
+                }
+
+                keep += 1;
+                keep_tokens += tokens;
+            }
+
+            current.drain(..current.len() - keep);
+            current_tokens = keep_tokens;
+        }
+
+        current.push(i);
+        current_tokens += segment.tokens;
+    }
+
+    if !current.is_empty() {
+        chunks.push(make_chunk(&current));
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::{chunk_text, ChunkOptions};
+
+    fn word_count(s: &str) -> usize {
+        s.split_whitespace().count()
+    }
+
+    fn chunk_strings(text: &str, max_tokens: usize, overlap_tokens: usize) -> Vec<&str> {
+        let options = ChunkOptions {
+            max_tokens,
+            overlap_tokens,
+        };
+        chunk_text(text, &options, word_count)
+            .into_iter()
+            .map(|c| &text[c.start..c.end])
+            .collect()
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(
+            chunk_strings("  One two three.  ", 10, 2),
+            vec!["One two three."]
+        );
+        assert!(chunk_strings(" \n\n ", 10, 2).is_empty());
+    }
+
+    #[test]
+    fn splits_on_paragraphs() {
+        let text = "One two three.\n\nFour five six.\n\nSeven eight nine.";
+        assert_eq!(
+            chunk_strings(text, 6, 0),
+            vec!["One two three.\n\nFour five six.", "Seven eight nine."]
+        );
+    }
+
+    #[test]
+    fn long_paragraph_splits_on_sentences() {
+        let text = "One two three. Four five six! Seven eight nine? Ten.";
+        assert_eq!(
+            chunk_strings(text, 4, 0),
+            vec!["One two three.", "Four five six!", "Seven eight nine? Ten."]
+        );
+    }
+
+    #[test]
+    fn long_sentence_splits_on_words() {
+        let text = "one two three four five six seven";
+        assert_eq!(
+            chunk_strings(text, 3, 0),
+            vec!["one two three", "four five six", "seven"]
+        );
+    }
+
+    #[test]
+    fn long_sentence_counts_tokens_sparingly() {
+        let text = (0..2000)
+            .map(|i| format!("w{i}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let calls = Cell::new(0);
+        let count = |s: &str| {
+            calls.set(calls.get() + 1);
+            word_count(s)
+        };
+        let options = ChunkOptions {
+            max_tokens: 100,
+            overlap_tokens: 0,
+        };
+
+        let chunks = chunk_text(&text, &options, count);
+        assert_eq!(chunks.len(), 20);
+        assert!(calls.get() < 400, "{} calls", calls.get());
+    }
+
+    #[test]
+    fn overlap() {
+        let text = "A b. C d. E f. G h.";
+        assert_eq!(
+            chunk_strings(text, 4, 2),
+            vec!["A b. C d.", "C d. E f.", "E f. G h."]
+        );
+    }
+}
@@ -1,8 +1,12 @@
 use thiserror::Error;
 
 pub mod chat_sessions;
+pub mod chat_system_messages;
+pub mod item_chunks;
 pub mod items;
+pub mod jobs;
 pub mod models;
+pub mod tags;
 
 #[derive(Debug, Error)]
 #[error("Database error")]
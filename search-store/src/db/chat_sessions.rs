@@ -1,30 +1,45 @@
+use std::collections::HashMap;
+
 use error_stack::{IntoReport, Report, ResultExt};
 use serde::{Deserialize, Serialize};
-use sqlx::{query_as, FromRow, PgPool};
+use sqlx::{query, query_as, types::Json, FromRow, PgPool};
 use sqlx_transparent_json_decode::sqlx_json_decode;
 
 use super::DbError;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ChatSystemMessage {
-    pub id: i64,
-    pub name: Option<String>,
-    pub message: String,
-    pub hidden: bool,
-    pub created_at: time::OffsetDateTime,
-}
+use crate::models::completion::{FinishReason, SamplingParams, TokenUsage};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatSession {
     pub id: i64,
-    pub name: String,
+    pub name: Option<String>,
     pub system_message_id: Option<i64>,
     pub parent_session: Option<i64>,
+    /// The message in the parent session that this session continues from
+    pub parent_message_id: Option<i64>,
+    pub config: ChatSessionConfig,
     pub hidden: bool,
     pub created_at: time::OffsetDateTime,
     pub updated_at: time::OffsetDateTime,
 }
 
+/// How the AI responses in a chat session are generated.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSessionConfig {
+    /// The chat model that generates responses
+    pub model_id: Option<i32>,
+    /// The completion model that summarizes older messages once the conversation no longer
+    /// fits into the chat model's context. If not set, older messages are just left out.
+    pub summary_model_id: Option<i32>,
+    pub temperature: Option<f32>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    /// Values for the `{{variable}}` placeholders in the system message
+    pub variables: HashMap<String, String>,
+}
+
+sqlx_json_decode!(ChatSessionConfig);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: i64,
@@ -33,13 +48,249 @@ pub struct ChatMessage {
     pub important: bool,
     pub user_message: String,
     pub ai_message: Option<String>,
+    pub system_info: Option<ChatMessageSystemInfo>,
     pub created_at: time::OffsetDateTime,
 }
 
 sqlx_json_decode!(ChatMessage);
 
+/// Information about how the AI response to a chat message was generated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessageSystemInfo {
+    pub model_id: i32,
+    pub finish_reason: FinishReason,
+    pub usage: TokenUsage,
+}
+
+sqlx_json_decode!(ChatMessageSystemInfo);
+
+/// A summary of the conversation from `start_message_id` through `end_message_id`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatSummary {
+    pub id: i64,
+    /// The summary that this one extends
+    pub parent_id: Option<i64>,
+    pub session_id: i64,
+    pub summary: String,
+    pub start_message_id: i64,
+    pub end_message_id: i64,
+    pub created_at: time::OffsetDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewChatSession {
+    pub name: Option<String>,
+    pub system_message_id: Option<i64>,
+    pub parent_session: Option<i64>,
+    pub parent_message_id: Option<i64>,
+    #[serde(default)]
+    pub config: ChatSessionConfig,
+}
+
+/// Changes to a chat session. Fields that are `None` are left unchanged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChatSessionUpdate {
+    pub name: Option<String>,
+    pub system_message_id: Option<i64>,
+    pub config: Option<ChatSessionConfig>,
+    pub hidden: Option<bool>,
+}
+
+/// List the chat sessions that are not hidden, with the most recently updated first.
 pub async fn list_chat_sessions(pool: &PgPool) -> Result<Vec<ChatSession>, Report<DbError>> {
-    todo!()
+    query_as!(
+        ChatSession,
+        r##"SELECT
+            id,
+            name,
+            system_message_id,
+            parent_session,
+            parent_message_id,
+            COALESCE(NULLIF(config, 'null'::jsonb), '{}'::jsonb) as "config!: ChatSessionConfig",
+            hidden,
+            created_at,
+            updated_at
+        FROM chat_sessions
+        WHERE NOT hidden
+        ORDER BY updated_at DESC"##
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn get_chat_session(
+    pool: &PgPool,
+    session_id: i64,
+) -> Result<Option<ChatSession>, Report<DbError>> {
+    query_as!(
+        ChatSession,
+        r##"SELECT
+            id,
+            name,
+            system_message_id,
+            parent_session,
+            parent_message_id,
+            COALESCE(NULLIF(config, 'null'::jsonb), '{}'::jsonb) as "config!: ChatSessionConfig",
+            hidden,
+            created_at,
+            updated_at
+        FROM chat_sessions
+        WHERE id = $1"##,
+        session_id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn create_chat_session(
+    pool: &PgPool,
+    session: &NewChatSession,
+) -> Result<ChatSession, Report<DbError>> {
+    query_as!(
+        ChatSession,
+        r##"INSERT INTO chat_sessions
+            (name, system_message_id, parent_session, parent_message_id, config)
+        VALUES
+            ($1, $2, $3, $4, $5)
+        RETURNING
+            id,
+            name,
+            system_message_id,
+            parent_session,
+            parent_message_id,
+            COALESCE(NULLIF(config, 'null'::jsonb), '{}'::jsonb) as "config!: ChatSessionConfig",
+            hidden,
+            created_at,
+            updated_at
+        "##,
+        session.name,
+        session.system_message_id,
+        session.parent_session,
+        session.parent_message_id,
+        Json(&session.config) as _
+    )
+    .fetch_one(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Update a chat session, returning `None` if it does not exist.
+pub async fn update_chat_session(
+    pool: &PgPool,
+    session_id: i64,
+    update: &ChatSessionUpdate,
+) -> Result<Option<ChatSession>, Report<DbError>> {
+    query_as!(
+        ChatSession,
+        r##"UPDATE chat_sessions
+        SET
+            name = COALESCE($2, name),
+            system_message_id = COALESCE($3, system_message_id),
+            config = COALESCE($4, config),
+            hidden = COALESCE($5, hidden),
+            updated_at = NOW()
+        WHERE id = $1
+        RETURNING
+            id,
+            name,
+            system_message_id,
+            parent_session,
+            parent_message_id,
+            COALESCE(NULLIF(config, 'null'::jsonb), '{}'::jsonb) as "config!: ChatSessionConfig",
+            hidden,
+            created_at,
+            updated_at
+        "##,
+        session_id,
+        update.name,
+        update.system_message_id,
+        update.config.as_ref().map(Json) as _,
+        update.hidden
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Delete a chat session along with its messages and summaries. Returns false if the session
+/// did not exist.
+pub async fn delete_chat_session(pool: &PgPool, session_id: i64) -> Result<bool, Report<DbError>> {
+    let result = query!("DELETE FROM chat_sessions WHERE id = $1", session_id)
+        .execute(pool)
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Get the most recently added message in a session.
+pub async fn get_latest_chat_message(
+    pool: &PgPool,
+    session_id: i64,
+) -> Result<Option<ChatMessage>, Report<DbError>> {
+    query_as!(
+        ChatMessage,
+        r##"SELECT
+            id,
+            session_id,
+            parent_id,
+            important,
+            user_message,
+            ai_message,
+            system_info as "system_info: ChatMessageSystemInfo",
+            created_at
+        FROM chat_messages
+        WHERE session_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1"##,
+        session_id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Get a message and all of its ancestors, oldest first.
+pub async fn get_chat_message_ancestry(
+    pool: &PgPool,
+    message_id: i64,
+) -> Result<Vec<ChatMessage>, Report<DbError>> {
+    query_as!(
+        ChatMessage,
+        r##"WITH RECURSIVE ancestry AS (
+            SELECT chat_messages.*, 0 AS depth
+            FROM chat_messages
+            WHERE id = $1
+            UNION ALL
+            SELECT parent.*, ancestry.depth + 1
+            FROM chat_messages parent
+            JOIN ancestry ON parent.id = ancestry.parent_id
+        )
+        SELECT
+            id as "id!",
+            session_id as "session_id!",
+            parent_id,
+            important as "important!",
+            user_message as "user_message!",
+            ai_message,
+            system_info as "system_info: ChatMessageSystemInfo",
+            created_at as "created_at!"
+        FROM ancestry
+        ORDER BY depth DESC"##,
+        message_id
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
 }
 
 pub async fn get_chat_session_messages(
@@ -55,6 +306,7 @@ pub async fn get_chat_session_messages(
             important,
             user_message,
             ai_message,
+            system_info as "system_info: ChatMessageSystemInfo",
             created_at
         FROM chat_messages
         WHERE session_id = $1
@@ -88,6 +340,7 @@ pub async fn add_chat_message(
             important,
             user_message,
             ai_message,
+            system_info as "system_info: ChatMessageSystemInfo",
             created_at
         "##,
         session_id,
@@ -106,11 +359,12 @@ pub async fn add_ai_response_to_chat_message(
     pool: &PgPool,
     message_id: i64,
     ai_message: String,
+    system_info: &ChatMessageSystemInfo,
 ) -> Result<ChatMessage, Report<DbError>> {
     query_as!(
         ChatMessage,
         r##"UPDATE chat_messages
-        SET ai_message = $2
+        SET ai_message = $2, system_info = $3
         WHERE id = $1
         RETURNING
             id,
@@ -119,10 +373,72 @@ pub async fn add_ai_response_to_chat_message(
             important,
             user_message,
             ai_message,
+            system_info as "system_info: ChatMessageSystemInfo",
             created_at
         "##,
         message_id,
-        ai_message
+        ai_message,
+        Json(system_info) as _
+    )
+    .fetch_one(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn get_chat_summaries(
+    pool: &PgPool,
+    session_id: i64,
+) -> Result<Vec<ChatSummary>, Report<DbError>> {
+    query_as!(
+        ChatSummary,
+        r##"SELECT
+            id,
+            parent_id,
+            session_id,
+            summary,
+            start_message_id,
+            end_message_id,
+            created_at
+        FROM chat_summaries
+        WHERE session_id = $1
+        ORDER BY created_at"##,
+        session_id
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn add_chat_summary(
+    pool: &PgPool,
+    session_id: i64,
+    parent_id: Option<i64>,
+    summary: String,
+    start_message_id: i64,
+    end_message_id: i64,
+) -> Result<ChatSummary, Report<DbError>> {
+    query_as!(
+        ChatSummary,
+        r##"INSERT INTO chat_summaries
+            (session_id, parent_id, summary, start_message_id, end_message_id)
+        VALUES
+            ($1, $2, $3, $4, $5)
+        RETURNING
+            id,
+            parent_id,
+            session_id,
+            summary,
+            start_message_id,
+            end_message_id,
+            created_at
+        "##,
+        session_id,
+        parent_id,
+        summary,
+        start_message_id,
+        end_message_id
     )
     .fetch_one(pool)
     .await
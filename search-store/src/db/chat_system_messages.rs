@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, PgPool};
+
+use super::DbError;
+
+/// A reusable system prompt for chat sessions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatSystemMessage {
+    pub id: i64,
+    pub name: Option<String>,
+    pub message: String,
+    pub hidden: bool,
+    pub created_at: time::OffsetDateTime,
+}
+
+impl ChatSystemMessage {
+    /// Get the message text with its `{{variable}}` placeholders filled in.
+    pub fn render(&self, variables: &HashMap<String, String>) -> String {
+        fill_variables(&self.message, variables)
+    }
+}
+
+/// Replace each `{{name}}` in the text with the value of that variable. Placeholders for
+/// variables that are not set are left as they are.
+pub fn fill_variables(text: &str, variables: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+
+        let end = start + 2 + len + 2;
+        let name = rest[start + 2..end - 2].trim();
+        output.push_str(&rest[..start]);
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rest[start..end]),
+        }
+
+        rest = &rest[end..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewChatSystemMessage {
+    pub name: Option<String>,
+    pub message: String,
+}
+
+/// Changes to a system message. Fields that are `None` are left unchanged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChatSystemMessageUpdate {
+    pub name: Option<String>,
+    pub message: Option<String>,
+    pub hidden: Option<bool>,
+}
+
+/// List the system messages that are not hidden.
+pub async fn list_chat_system_messages(
+    pool: &PgPool,
+) -> Result<Vec<ChatSystemMessage>, Report<DbError>> {
+    query_as!(
+        ChatSystemMessage,
+        r##"SELECT id, name, message, hidden, created_at
+        FROM chat_system_messages
+        WHERE NOT hidden
+        ORDER BY name, id"##
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn get_chat_system_message(
+    pool: &PgPool,
+    id: i64,
+) -> Result<Option<ChatSystemMessage>, Report<DbError>> {
+    query_as!(
+        ChatSystemMessage,
+        r##"SELECT id, name, message, hidden, created_at
+        FROM chat_system_messages
+        WHERE id = $1"##,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn create_chat_system_message(
+    pool: &PgPool,
+    message: &NewChatSystemMessage,
+) -> Result<ChatSystemMessage, Report<DbError>> {
+    query_as!(
+        ChatSystemMessage,
+        r##"INSERT INTO chat_system_messages (name, message)
+        VALUES ($1, $2)
+        RETURNING id, name, message, hidden, created_at"##,
+        message.name,
+        message.message
+    )
+    .fetch_one(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Update a system message, returning `None` if it does not exist.
+pub async fn update_chat_system_message(
+    pool: &PgPool,
+    id: i64,
+    update: &ChatSystemMessageUpdate,
+) -> Result<Option<ChatSystemMessage>, Report<DbError>> {
+    query_as!(
+        ChatSystemMessage,
+        r##"UPDATE chat_system_messages
+        SET
+            name = COALESCE($2, name),
+            message = COALESCE($3, message),
+            hidden = COALESCE($4, hidden)
+        WHERE id = $1
+        RETURNING id, name, message, hidden, created_at"##,
+        id,
+        update.name,
+        update.message,
+        update.hidden
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Delete a system message. Sessions that used it are left without a system message.
+/// Returns false if the system message did not exist.
+pub async fn delete_chat_system_message(pool: &PgPool, id: i64) -> Result<bool, Report<DbError>> {
+    let result = query!("DELETE FROM chat_system_messages WHERE id = $1", id)
+        .execute(pool)
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::fill_variables;
+
+    #[test]
+    fn fills_variables() {
+        let variables = HashMap::from([
+            ("name".to_string(), "Maiven".to_string()),
+            ("tone".to_string(), "friendly".to_string()),
+        ]);
+
+        assert_eq!(
+            fill_variables("You are {{name}}, a {{ tone }} assistant.", &variables),
+            "You are Maiven, a friendly assistant."
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_variables() {
+        let variables = HashMap::new();
+        assert_eq!(
+            fill_variables("Hello {{user}} {{", &variables),
+            "Hello {{user}} {{"
+        );
+    }
+}
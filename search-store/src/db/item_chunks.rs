@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use sqlx::{query, query_as, PgPool};
+
+use super::DbError;
+
+/// A chunk of an item's processed content, along with its embedding.
+#[derive(Debug)]
+pub struct NewItemChunk {
+    /// The index of the first character of the chunk
+    pub start_idx: i32,
+    /// The index of the last character of the chunk
+    pub end_idx: i32,
+    pub embedding: Vec<f32>,
+}
+
+/// A stored chunk embedding.
+#[derive(Debug)]
+pub struct ItemChunkEmbedding {
+    pub item_id: i64,
+    pub sequence_num: i32,
+    pub item_version: i32,
+    pub start_idx: i32,
+    pub end_idx: i32,
+    pub embedding: Vec<u8>,
+}
+
+/// Where a stored chunk is in its item's processed content.
+#[derive(Debug)]
+pub struct ItemChunkSpan {
+    pub item_id: i64,
+    pub sequence_num: i32,
+    pub start_idx: i32,
+    pub end_idx: i32,
+}
+
+/// Embeddings are stored as little-endian `f32` values.
+pub fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub fn embedding_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Get the item version that a model's chunks were created from, if the model has embedded the
+/// item at all.
+pub async fn get_chunks_version(
+    pool: &PgPool,
+    item_id: i64,
+    model_id: i32,
+) -> Result<Option<i32>, Report<DbError>> {
+    let result = query!(
+        "SELECT MAX(item_version) as version FROM item_chunks WHERE item_id = $1 AND model_id = $2",
+        item_id,
+        model_id
+    )
+    .fetch_one(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(result.version)
+}
+
+/// Get the item version of every item that a model has embedded.
+pub async fn get_item_versions(
+    pool: &PgPool,
+    model_id: i32,
+) -> Result<HashMap<i64, i32>, Report<DbError>> {
+    let rows = query!(
+        r##"SELECT item_id, MAX(item_version) as "version!"
+        FROM item_chunks
+        WHERE model_id = $1
+        GROUP BY item_id"##,
+        model_id
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(rows.into_iter().map(|r| (r.item_id, r.version)).collect())
+}
+
+/// List the processed items that a model has not embedded, or has only embedded an older
+/// version of.
+pub async fn list_unembedded_items(
+    pool: &PgPool,
+    model_id: i32,
+) -> Result<Vec<i64>, Report<DbError>> {
+    let rows = query!(
+        "SELECT items.id
+        FROM items
+        LEFT JOIN (
+            SELECT item_id, MAX(item_version) AS version
+            FROM item_chunks
+            WHERE model_id = $1
+            GROUP BY item_id
+        ) chunks ON chunks.item_id = items.id
+        WHERE items.status = 'ready'
+            AND items.processed_content <> ''
+            AND (chunks.version IS NULL OR chunks.version <> items.version)
+        ORDER BY items.id",
+        model_id
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(rows.into_iter().map(|r| r.id).collect())
+}
+
+/// Get a model's embeddings for some items, ordered by item and then by sequence number.
+pub async fn get_item_embeddings(
+    pool: &PgPool,
+    model_id: i32,
+    item_ids: &[i64],
+) -> Result<Vec<ItemChunkEmbedding>, Report<DbError>> {
+    query_as!(
+        ItemChunkEmbedding,
+        "SELECT item_id, sequence_num, item_version, start_idx, end_idx, embedding
+        FROM item_chunks
+        WHERE model_id = $1 AND item_id = ANY($2)
+        ORDER BY item_id, sequence_num",
+        model_id,
+        item_ids
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Get the spans of some of a model's chunks, given as `(item_id, sequence_num)` pairs.
+pub async fn get_chunk_spans(
+    pool: &PgPool,
+    model_id: i32,
+    chunks: &[(i64, i32)],
+) -> Result<Vec<ItemChunkSpan>, Report<DbError>> {
+    let (item_ids, sequence_nums): (Vec<i64>, Vec<i32>) = chunks.iter().copied().unzip();
+    query_as!(
+        ItemChunkSpan,
+        r#"SELECT item_id, sequence_num, start_idx, end_idx
+        FROM item_chunks
+        WHERE model_id = $1
+            AND (item_id, sequence_num) IN (SELECT * FROM UNNEST($2::bigint[], $3::int[]))"#,
+        model_id,
+        &item_ids,
+        &sequence_nums
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Replace a model's chunks for an item.
+pub async fn replace_item_chunks(
+    pool: &PgPool,
+    item_id: i64,
+    model_id: i32,
+    item_version: i32,
+    chunks: &[NewItemChunk],
+) -> Result<(), Report<DbError>> {
+    let mut tx = pool
+        .begin()
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    query!(
+        "DELETE FROM item_chunks WHERE item_id = $1 AND model_id = $2",
+        item_id,
+        model_id
+    )
+    .execute(&mut tx)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    for (sequence_num, chunk) in chunks.iter().enumerate() {
+        query!(
+            "INSERT INTO item_chunks
+                (item_id, sequence_num, model_id, start_idx, end_idx, embedding, item_version)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            item_id,
+            sequence_num as i32,
+            model_id,
+            chunk.start_idx,
+            chunk.end_idx,
+            embedding_to_bytes(&chunk.embedding),
+            item_version
+        )
+        .execute(&mut tx)
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+    }
+
+    tx.commit().await.into_report().change_context(DbError {})?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{embedding_from_bytes, embedding_to_bytes};
+
+    #[test]
+    fn embedding_round_trip() {
+        let embedding = vec![0.5, -1.25, 3.0e-7, f32::MAX];
+        assert_eq!(
+            embedding_from_bytes(&embedding_to_bytes(&embedding)),
+            embedding
+        );
+    }
+}
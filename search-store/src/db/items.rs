@@ -1,10 +1,13 @@
+use std::collections::HashMap;
+
 use error_stack::{IntoReport, Report, ResultExt};
 use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as, FromRow, PgPool};
 
 use super::DbError;
+use crate::filter::SearchFilter;
 
-#[derive(Serialize, Deserialize, Debug, sqlx::Type)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
 #[serde(rename_all = "snake_case")]
 #[sqlx(type_name = "item_status", rename_all = "snake_case")]
 pub enum ItemStatus {
@@ -43,6 +46,7 @@ pub struct ItemMetadata {
     pub id: i64,
     pub source_id: i32,
     pub status: ItemStatus,
+    pub status_detail: Option<String>,
     pub content_type: String,
     pub external_id: String,
     pub version: i32,
@@ -61,6 +65,34 @@ pub struct ItemMetadata {
     pub hidden: bool,
 }
 
+/// Changes to an item's metadata. Fields that are `None` are left unchanged.
+#[derive(Debug, Default, Deserialize)]
+pub struct ItemUpdate {
+    pub tags: Option<Vec<i32>>,
+    pub name: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub hidden: Option<bool>,
+}
+
+/// The fields of an item that go into the full-text index.
+#[derive(Debug)]
+pub struct ItemText {
+    pub id: i64,
+    pub status: ItemStatus,
+    pub hidden: bool,
+    pub name: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub processed_content: Option<String>,
+    pub generated_summary: Option<String>,
+    /// The names of the item's tags
+    pub tags: Vec<String>,
+    pub updated_at: time::OffsetDateTime,
+}
+
 pub async fn add_new_item(
     pool: &PgPool,
     item: &ItemPayload,
@@ -93,7 +125,7 @@ pub async fn add_new_item(
             false
         )
         RETURNING
-            id, source_id, status as "status: ItemStatus", content_type, external_id, version, hash,
+            id, source_id, status as "status: ItemStatus", status_detail, content_type, external_id, version, hash,
             saved_original_path, original_location, tags, name, title, author,
             description, generated_summary, updated_at, hidden"#,
         item.source_id,
@@ -123,7 +155,7 @@ pub async fn lookup_by_id(pool: &PgPool, id: i64) -> Result<Option<ItemMetadata>
         ItemMetadata,
         r#"
         SELECT
-            id, source_id, status as "status: ItemStatus", content_type, external_id, version, hash,
+            id, source_id, status as "status: ItemStatus", status_detail, content_type, external_id, version, hash,
             saved_original_path, original_location, tags, name, title, author,
             description, generated_summary, updated_at, hidden
         FROM items
@@ -145,7 +177,7 @@ pub async fn lookup_by_hash(
         ItemMetadata,
         r#"
         SELECT
-            id, source_id, status as "status: ItemStatus", content_type, external_id, version, hash,
+            id, source_id, status as "status: ItemStatus", status_detail, content_type, external_id, version, hash,
             saved_original_path, original_location, tags, name, title, author,
             description, generated_summary, updated_at, hidden
         FROM items
@@ -167,7 +199,7 @@ pub async fn lookup_by_external_id(
         ItemMetadata,
         r#"
         SELECT
-            id, source_id, status as "status: ItemStatus", content_type, external_id, version, hash,
+            id, source_id, status as "status: ItemStatus", status_detail, content_type, external_id, version, hash,
             saved_original_path, original_location, tags, name, title, author,
             description, generated_summary, updated_at, hidden
         FROM items
@@ -204,3 +236,211 @@ pub async fn update_item_after_upload(
 
     Ok(())
 }
+
+pub async fn set_item_status(
+    pool: &PgPool,
+    id: i64,
+    status: ItemStatus,
+    status_detail: Option<&str>,
+) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE items
+        SET status = $2, status_detail = $3, updated_at = NOW()
+        WHERE id = $1",
+        id,
+        status as _,
+        status_detail
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+/// Save the text extracted from an item. Metadata that is already set on the item is kept, so
+/// that values provided at upload take precedence over those found in the document.
+pub async fn update_item_content(
+    pool: &PgPool,
+    id: i64,
+    processed_content: &str,
+    title: Option<&str>,
+    author: Option<&str>,
+    description: Option<&str>,
+) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE items
+        SET processed_content = $2,
+            title = COALESCE(title, $3),
+            author = COALESCE(author, $4),
+            description = COALESCE(description, $5),
+            updated_at = NOW()
+        WHERE id = $1",
+        id,
+        processed_content,
+        title,
+        author,
+        description
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+pub async fn set_generated_summary(
+    pool: &PgPool,
+    id: i64,
+    summary: &str,
+) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE items SET generated_summary = $2 WHERE id = $1",
+        id,
+        summary
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+/// Add tags to an item, skipping any that it already has.
+pub async fn add_item_tags(pool: &PgPool, id: i64, tags: &[i32]) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE items
+        SET tags = tags || ARRAY(SELECT unnest($2::int[]) EXCEPT SELECT unnest(tags)),
+            updated_at = NOW()
+        WHERE id = $1",
+        id,
+        tags
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+pub async fn update_item(
+    pool: &PgPool,
+    id: i64,
+    update: &ItemUpdate,
+) -> Result<Option<ItemMetadata>, Report<DbError>> {
+    query_as!(
+        ItemMetadata,
+        r#"
+        UPDATE items
+        SET
+            tags = COALESCE($2, tags),
+            name = COALESCE($3, name),
+            title = COALESCE($4, title),
+            author = COALESCE($5, author),
+            description = COALESCE($6, description),
+            hidden = COALESCE($7, hidden),
+            updated_at = NOW()
+        WHERE id = $1
+        RETURNING
+            id, source_id, status as "status: ItemStatus", status_detail, content_type, external_id, version, hash,
+            saved_original_path, original_location, tags, name, title, author,
+            description, generated_summary, updated_at, hidden"#,
+        id,
+        update.tags.as_deref(),
+        update.name,
+        update.title,
+        update.author,
+        update.description,
+        update.hidden
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Delete an item along with its chunks. Returns false if the item did not exist.
+pub async fn delete_item(pool: &PgPool, id: i64) -> Result<bool, Report<DbError>> {
+    let result = query!("DELETE FROM items WHERE id = $1", id)
+        .execute(pool)
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Get the text of some items for the full-text index.
+pub async fn get_items_text(pool: &PgPool, ids: &[i64]) -> Result<Vec<ItemText>, Report<DbError>> {
+    query_as!(
+        ItemText,
+        r#"
+        SELECT
+            id, status as "status: ItemStatus", hidden, name, title, author, description,
+            processed_content, generated_summary,
+            ARRAY(SELECT tags.name FROM tags WHERE tags.id = ANY(items.tags)) as "tags!",
+            updated_at
+        FROM items
+        WHERE id = ANY($1)"#,
+        ids
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// List the items that can be searched, with the time that each was last updated. This
+/// includes hidden items, which are only searched when a filter asks for them.
+pub async fn list_searchable_items(
+    pool: &PgPool,
+) -> Result<HashMap<i64, time::OffsetDateTime>, Report<DbError>> {
+    let rows = query!("SELECT id, updated_at FROM items WHERE status = 'ready'")
+        .fetch_all(pool)
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    Ok(rows.into_iter().map(|r| (r.id, r.updated_at)).collect())
+}
+
+/// List the IDs of the searchable items that match a filter.
+pub async fn filter_item_ids(
+    pool: &PgPool,
+    filter: &SearchFilter,
+) -> Result<Vec<i64>, Report<DbError>> {
+    let non_empty = |v: &[i32]| (!v.is_empty()).then(|| v.to_vec());
+    let content_types = filter.content_type_patterns();
+
+    let rows = query!(
+        r#"SELECT id
+        FROM items
+        WHERE status = 'ready'
+            AND ($1::int[] IS NULL OR tags && $1)
+            AND ($2::int[] IS NULL OR tags @> $2)
+            AND ($3::int[] IS NULL OR NOT tags && $3)
+            AND ($4::int[] IS NULL OR source_id = ANY($4))
+            AND ($5::text[] IS NULL OR content_type LIKE ANY($5))
+            AND ($6::timestamptz IS NULL OR updated_at >= $6)
+            AND ($7::timestamptz IS NULL OR updated_at < $7)
+            AND ($8::boolean IS NULL OR hidden = $8)"#,
+        non_empty(&filter.tags.any),
+        non_empty(&filter.tags.all),
+        non_empty(&filter.tags.none),
+        non_empty(&filter.source_ids),
+        (!content_types.is_empty()).then_some(content_types),
+        filter.updated_after,
+        filter.updated_before,
+        filter.visibility.hidden()
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(rows.into_iter().map(|r| r.id).collect())
+}
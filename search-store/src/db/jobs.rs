@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, types::Json, PgPool};
+use sqlx_transparent_json_decode::sqlx_json_decode;
+
+use super::DbError;
+use crate::synthesis::SynthesisRequest;
+
+/// The work that a background job does.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobPayload {
+    /// Extract and index the content of an uploaded item
+    ProcessItem { item_id: i64 },
+    /// Generate a summary of an item. Uses the configured summary model if `model_id` is not set.
+    SummarizeItem { item_id: i64, model_id: Option<i32> },
+    /// Summarize and compare a set of items. The result is saved on the job.
+    Synthesize { request: SynthesisRequest },
+    /// Add the suggested tags that meet the configured threshold to an item.
+    TagItem { item_id: i64 },
+    /// Embed a processed item with the loaded bi-encoders that have not embedded its current
+    /// version yet.
+    EmbedItem { item_id: i64 },
+}
+
+sqlx_json_decode!(JobPayload);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "job_status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// A job that a worker has claimed.
+#[derive(Debug)]
+pub struct Job {
+    pub id: i64,
+    pub payload: JobPayload,
+    /// The number of times the job has been started, including this one
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+impl Job {
+    /// If this attempt fails, the job will not be retried.
+    pub fn is_last_attempt(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+}
+
+/// The state of a job, for reporting its progress.
+#[derive(Debug, Serialize)]
+pub struct JobInfo {
+    pub id: i64,
+    pub payload: JobPayload,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    pub result: Option<serde_json::Value>,
+    pub created_at: time::OffsetDateTime,
+    pub updated_at: time::OffsetDateTime,
+}
+
+pub async fn enqueue_job(pool: &PgPool, payload: &JobPayload) -> Result<i64, Report<DbError>> {
+    let result = query!(
+        "INSERT INTO jobs (payload) VALUES ($1) RETURNING id",
+        Json(payload) as _
+    )
+    .fetch_one(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(result.id)
+}
+
+/// Claim the next job that is ready to run. `SKIP LOCKED` lets any number of workers, including
+/// those in other processes, poll the queue at once without claiming the same job.
+pub async fn claim_job(pool: &PgPool) -> Result<Option<Job>, Report<DbError>> {
+    query_as!(
+        Job,
+        r##"UPDATE jobs
+        SET status = 'running', attempts = attempts + 1, locked_at = NOW(), updated_at = NOW()
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE status = 'pending' AND run_at <= NOW()
+            ORDER BY run_at, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, payload as "payload: JobPayload", attempts, max_attempts"##
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+pub async fn get_job(pool: &PgPool, id: i64) -> Result<Option<JobInfo>, Report<DbError>> {
+    query_as!(
+        JobInfo,
+        r##"SELECT id, payload as "payload: JobPayload", status as "status: JobStatus",
+            attempts, max_attempts, last_error, result, created_at, updated_at
+        FROM jobs
+        WHERE id = $1"##,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})
+}
+
+/// Mark a job as done, saving its output if it has one.
+pub async fn complete_job(
+    pool: &PgPool,
+    id: i64,
+    result: Option<&serde_json::Value>,
+) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE jobs
+        SET status = 'done', locked_at = NULL, last_error = NULL, result = $2, updated_at = NOW()
+        WHERE id = $1",
+        id,
+        result
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+/// Record a failed attempt. The job is retried after a delay that grows with each attempt,
+/// unless it has run out of attempts.
+pub async fn fail_job(pool: &PgPool, id: i64, error: &str) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE jobs
+        SET
+            status = CASE WHEN attempts >= max_attempts THEN 'failed'::job_status ELSE 'pending' END,
+            run_at = NOW() + attempts * attempts * INTERVAL '30 seconds',
+            locked_at = NULL,
+            last_error = $2,
+            updated_at = NOW()
+        WHERE id = $1",
+        id,
+        error
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+/// Show that a running job's worker is still alive, so that the job is not reset while it runs.
+pub async fn touch_job(pool: &PgPool, id: i64) -> Result<(), Report<DbError>> {
+    query!(
+        "UPDATE jobs SET locked_at = NOW() WHERE id = $1 AND status = 'running'",
+        id
+    )
+    .execute(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(())
+}
+
+/// Return jobs whose worker has not touched them for longer than `timeout` to the queue, on the
+/// assumption that the worker crashed. Items that were being processed by those jobs are
+/// reset as well. Returns the number of jobs that were reset.
+pub async fn reset_stale_jobs(pool: &PgPool, timeout: Duration) -> Result<u64, Report<DbError>> {
+    let timeout_secs = timeout.as_secs_f64();
+    let mut tx = pool
+        .begin()
+        .await
+        .into_report()
+        .change_context(DbError {})?;
+
+    query!(
+        "UPDATE items
+        SET
+            status = CASE
+                WHEN jobs.attempts >= jobs.max_attempts THEN 'error'::item_status
+                ELSE 'pending_processing'
+            END,
+            status_detail = 'Processing was interrupted',
+            updated_at = NOW()
+        FROM jobs
+        WHERE items.status = 'processing'
+            AND jobs.status = 'running'
+            AND jobs.payload->>'type' = 'process_item'
+            AND (jobs.payload->>'item_id')::bigint = items.id
+            AND jobs.locked_at < NOW() - make_interval(secs => $1)",
+        timeout_secs
+    )
+    .execute(&mut tx)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    let result = query!(
+        "UPDATE jobs
+        SET
+            status = CASE WHEN attempts >= max_attempts THEN 'failed'::job_status ELSE 'pending' END,
+            locked_at = NULL,
+            last_error = 'Job timed out',
+            updated_at = NOW()
+        WHERE status = 'running' AND locked_at < NOW() - make_interval(secs => $1)",
+        timeout_secs
+    )
+    .execute(&mut tx)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    tx.commit().await.into_report().change_context(DbError {})?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,77 @@
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::Serialize;
+use sqlx::{query, query_as, PgPool};
+
+use super::DbError;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tag {
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+}
+
+/// An item that has tags, for building tag centroids.
+#[derive(Debug)]
+pub struct TaggedItem {
+    pub id: i64,
+    pub tags: Vec<i32>,
+}
+
+pub async fn list_tags(pool: &PgPool) -> Result<Vec<Tag>, Report<DbError>> {
+    query_as!(Tag, "SELECT id, name, color FROM tags ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .into_report()
+        .change_context(DbError {})
+}
+
+/// List the processed items that have a tag and are not hidden, most recently updated first.
+pub async fn list_tag_item_ids(
+    pool: &PgPool,
+    tag_id: i32,
+    limit: i64,
+) -> Result<Vec<i64>, Report<DbError>> {
+    let rows = query!(
+        "SELECT id
+        FROM items
+        WHERE status = 'ready' AND NOT hidden AND $1 = ANY(tags)
+        ORDER BY updated_at DESC
+        LIMIT $2",
+        tag_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(rows.into_iter().map(|r| r.id).collect())
+}
+
+/// List the processed items that have at least one tag, most recently updated first, leaving
+/// out `exclude_id`.
+pub async fn list_tagged_items(
+    pool: &PgPool,
+    exclude_id: i64,
+) -> Result<Vec<TaggedItem>, Report<DbError>> {
+    let rows = query!(
+        "SELECT id, tags
+        FROM items
+        WHERE status = 'ready' AND tags <> '{}' AND id <> $1
+        ORDER BY updated_at DESC",
+        exclude_id
+    )
+    .fetch_all(pool)
+    .await
+    .into_report()
+    .change_context(DbError {})?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| TaggedItem {
+            id: r.id,
+            tags: r.tags,
+        })
+        .collect())
+}
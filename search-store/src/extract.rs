@@ -0,0 +1,148 @@
+mod epub;
+mod html;
+mod pdf;
+mod text;
+
+use std::{path::Path, sync::Arc};
+
+use error_stack::Report;
+use thiserror::Error;
+
+pub use self::{
+    epub::EpubExtractor,
+    html::HtmlExtractor,
+    pdf::PdfExtractor,
+    text::{MarkdownExtractor, PlainTextExtractor},
+};
+
+#[derive(Debug, Error)]
+pub enum ExtractError {
+    #[error("No extractor for content type {0}")]
+    UnsupportedContentType(String),
+    #[error("Failed to read file")]
+    ReadError,
+    #[error("Failed to parse document")]
+    ParseError,
+}
+
+/// The text and metadata extracted from a document.
+#[derive(Debug, Default)]
+pub struct ExtractedContent {
+    pub text: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+}
+
+pub trait Extractor: Send + Sync {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>>;
+}
+
+/// Finds the extractor for an item based on its content type.
+pub struct ExtractorRegistry {
+    extractors: Vec<(String, Arc<dyn Extractor>)>,
+}
+
+impl Default for ExtractorRegistry {
+    /// Create a registry with the built-in extractors.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register("text/plain", PlainTextExtractor);
+        registry.register("text/markdown", MarkdownExtractor);
+        registry.register("text/x-markdown", MarkdownExtractor);
+        registry.register("text/html", HtmlExtractor);
+        registry.register("application/xhtml+xml", HtmlExtractor);
+        registry.register("application/pdf", PdfExtractor);
+        registry.register("application/epub+zip", EpubExtractor);
+        registry
+    }
+}
+
+impl ExtractorRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self {
+            extractors: Vec::new(),
+        }
+    }
+
+    /// Add an extractor for a content type, replacing any existing extractor for it.
+    pub fn register(&mut self, content_type: &str, extractor: impl Extractor + 'static) {
+        let content_type = content_type.to_ascii_lowercase();
+        self.extractors.retain(|(t, _)| *t != content_type);
+        self.extractors.push((content_type, Arc::new(extractor)));
+    }
+
+    /// Get the extractor for a content type. Parameters such as `charset` are ignored, and
+    /// any `text/*` type without its own extractor is treated as plain text.
+    pub fn get(&self, content_type: &str) -> Option<Arc<dyn Extractor>> {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        let lookup = |t: &str| {
+            self.extractors
+                .iter()
+                .find(|(registered, _)| registered == t)
+                .map(|(_, extractor)| extractor.clone())
+        };
+
+        lookup(&content_type).or_else(|| {
+            content_type
+                .starts_with("text/")
+                .then(|| lookup("text/plain"))
+                .flatten()
+        })
+    }
+}
+
+/// Trim each line, collapse runs of spaces, and remove repeated blank lines.
+pub(crate) fn normalize_whitespace(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+
+        if !output.is_empty() {
+            output.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+        }
+
+        output.push_str(&line);
+        blank_lines = 0;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize_whitespace, ExtractorRegistry};
+
+    #[test]
+    fn lookup_by_content_type() {
+        let registry = ExtractorRegistry::default();
+        assert!(registry.get("text/html; charset=utf-8").is_some());
+        assert!(registry.get("Application/PDF").is_some());
+        assert!(
+            registry.get("text/csv").is_some(),
+            "text types fall back to plain text"
+        );
+        assert!(registry.get("image/png").is_none());
+    }
+
+    #[test]
+    fn normalizes_whitespace() {
+        assert_eq!(
+            normalize_whitespace("  one   two \n\n\n\tthree\nfour  "),
+            "one two\n\nthree\nfour"
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use epub::doc::EpubDoc;
+use error_stack::{IntoReport, Report, ResultExt};
+
+use super::{html::extract_html, ExtractError, ExtractedContent, Extractor};
+
+/// Extracts the chapters of an EPUB book in reading order.
+pub struct EpubExtractor;
+
+impl Extractor for EpubExtractor {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>> {
+        let mut doc = EpubDoc::new(path)
+            .into_report()
+            .change_context(ExtractError::ParseError)
+            .attach_printable_lazy(|| path.display().to_string())?;
+
+        let mut chapters = Vec::with_capacity(doc.spine.len());
+        loop {
+            if let Some((content, _mime)) = doc.get_current_str() {
+                let chapter = extract_html(&content).text;
+                if !chapter.is_empty() {
+                    chapters.push(chapter);
+                }
+            }
+
+            if !doc.go_next() {
+                break;
+            }
+        }
+
+        Ok(ExtractedContent {
+            text: chapters.join("\n\n"),
+            title: doc.mdata("title"),
+            author: doc.mdata("creator"),
+            description: doc.mdata("description"),
+        })
+    }
+}
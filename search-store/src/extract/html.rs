@@ -0,0 +1,193 @@
+use std::path::Path;
+
+use error_stack::Report;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+
+use super::{normalize_whitespace, text::read_text, ExtractError, ExtractedContent, Extractor};
+
+/// Elements that never contain the main content of a page.
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "iframe", "form", "button", "nav", "header",
+    "footer", "aside",
+];
+
+/// Elements that start a new line of text.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "br",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "tr",
+    "table",
+    "blockquote",
+    "pre",
+    "figure",
+    "figcaption",
+    "hr",
+];
+
+/// Extracts the main text from an HTML page, leaving out navigation, sidebars, and other
+/// boilerplate in the manner of Readability.
+pub struct HtmlExtractor;
+
+impl Extractor for HtmlExtractor {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>> {
+        Ok(extract_html(&read_text(path)?))
+    }
+}
+
+fn selector(s: &str) -> Selector {
+    Selector::parse(s).expect("valid selector")
+}
+
+fn meta_content(doc: &Html, selectors: &[&str]) -> Option<String> {
+    selectors.iter().find_map(|s| {
+        doc.select(&selector(s))
+            .filter_map(|el| el.value().attr("content"))
+            .map(|content| content.trim().to_string())
+            .find(|content| !content.is_empty())
+    })
+}
+
+struct TextCollector {
+    boilerplate: Regex,
+}
+
+impl TextCollector {
+    fn is_boilerplate(&self, element: &ElementRef) -> bool {
+        let el = element.value();
+        if SKIPPED_ELEMENTS.contains(&el.name()) {
+            return true;
+        }
+
+        let class_and_id = format!(
+            "{} {}",
+            el.attr("class").unwrap_or_default(),
+            el.id().unwrap_or_default()
+        );
+        self.boilerplate.is_match(&class_and_id)
+    }
+
+    fn collect(&self, element: ElementRef, output: &mut String) {
+        for child in element.children() {
+            if let Some(text) = child.value().as_text() {
+                output.push_str(text);
+            } else if let Some(child) = ElementRef::wrap(child) {
+                if self.is_boilerplate(&child) {
+                    continue;
+                }
+
+                let block = BLOCK_ELEMENTS.contains(&child.value().name());
+                if block {
+                    output.push('\n');
+                }
+
+                self.collect(child, output);
+
+                if block {
+                    output.push('\n');
+                }
+            }
+        }
+    }
+}
+
+/// Extract the text and metadata from an HTML document.
+pub(super) fn extract_html(source: &str) -> ExtractedContent {
+    let doc = Html::parse_document(source);
+
+    let title = meta_content(&doc, &["meta[property='og:title']"]).or_else(|| {
+        doc.select(&selector("title"))
+            .next()
+            .map(|el| el.text().collect::<String>().trim().to_string())
+            .filter(|title| !title.is_empty())
+    });
+
+    let author = meta_content(
+        &doc,
+        &["meta[name='author']", "meta[property='article:author']"],
+    );
+    let description = meta_content(
+        &doc,
+        &[
+            "meta[name='description']",
+            "meta[property='og:description']",
+        ],
+    );
+
+    // Prefer an element that marks the main content, and fall back to the whole body.
+    let root = ["article", "main", "[role='main']", "body"]
+        .iter()
+        .find_map(|s| doc.select(&selector(s)).next())
+        .unwrap_or_else(|| doc.root_element());
+
+    let collector = TextCollector {
+        boilerplate: Regex::new(
+            r"(?i)\b(sidebar|comments?|advert\w*|ads?|promo\w*|cookie\w*|banner|share|social|related|newsletter|breadcrumbs?)\b",
+        )
+        .expect("valid regex"),
+    };
+
+    let mut text = String::new();
+    collector.collect(root, &mut text);
+
+    ExtractedContent {
+        text: normalize_whitespace(&text),
+        title,
+        author,
+        description,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::extract_html;
+
+    #[test]
+    fn extracts_main_content() {
+        let doc = extract_html(
+            r#"<html>
+            <head>
+                <title>Page Title</title>
+                <meta name="author" content="An Author">
+                <meta name="description" content="About the page">
+                <style>body { color: red; }</style>
+            </head>
+            <body>
+                <nav><a href="/">Home</a></nav>
+                <article>
+                    <h1>Heading</h1>
+                    <p>First   paragraph with <b>bold</b> text.</p>
+                    <div class="share-buttons">Share this</div>
+                    <div class="comments">A comment</div>
+                    <p>Second paragraph.</p>
+                </article>
+                <footer>Copyright</footer>
+            </body>
+            </html>"#,
+        );
+
+        assert_eq!(doc.title.as_deref(), Some("Page Title"));
+        assert_eq!(doc.author.as_deref(), Some("An Author"));
+        assert_eq!(doc.description.as_deref(), Some("About the page"));
+        assert_eq!(
+            doc.text,
+            "Heading\n\nFirst paragraph with bold text.\n\nSecond paragraph."
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use lopdf::{Document, Object};
+
+use super::{normalize_whitespace, ExtractError, ExtractedContent, Extractor};
+
+pub struct PdfExtractor;
+
+impl Extractor for PdfExtractor {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>> {
+        let text = pdf_extract::extract_text(path)
+            .into_report()
+            .change_context(ExtractError::ParseError)
+            .attach_printable_lazy(|| path.display().to_string())?;
+
+        let doc = Document::load(path)
+            .into_report()
+            .change_context(ExtractError::ParseError)
+            .attach_printable_lazy(|| path.display().to_string())?;
+
+        Ok(ExtractedContent {
+            text: normalize_whitespace(&text),
+            title: info_string(&doc, b"Title"),
+            author: info_string(&doc, b"Author"),
+            description: info_string(&doc, b"Subject"),
+        })
+    }
+}
+
+/// Read a string from the document information dictionary.
+fn info_string(doc: &Document, key: &[u8]) -> Option<String> {
+    let info = doc.trailer.get(b"Info").ok()?;
+    let info = match info {
+        Object::Reference(id) => doc.get_object(*id).ok()?,
+        _ => info,
+    };
+
+    let value = info.as_dict().ok()?.get(key).ok()?.as_str().ok()?;
+    let value = decode_pdf_string(value);
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// PDF text strings are either UTF-16BE with a byte order mark, or PDFDocEncoding, which
+/// matches Latin-1 for the printable characters.
+fn decode_pdf_string(bytes: &[u8]) -> String {
+    match bytes.strip_prefix(&[0xfe, 0xff]) {
+        Some(utf16) => {
+            let units = utf16
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        }
+        None => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode_pdf_string;
+
+    #[test]
+    fn decode_strings() {
+        assert_eq!(decode_pdf_string(b"Caf\xe9"), "Café");
+        assert_eq!(
+            decode_pdf_string(&[0xfe, 0xff, 0x00, 0x48, 0x00, 0x69, 0x20, 0x14]),
+            "Hi—"
+        );
+    }
+}
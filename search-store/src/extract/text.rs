@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use error_stack::{IntoReport, Report, ResultExt};
+
+use super::{ExtractError, ExtractedContent, Extractor};
+
+pub(super) fn read_text(path: &Path) -> Result<String, Report<ExtractError>> {
+    let bytes = std::fs::read(path)
+        .into_report()
+        .change_context(ExtractError::ReadError)
+        .attach_printable_lazy(|| path.display().to_string())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+pub struct PlainTextExtractor;
+
+impl Extractor for PlainTextExtractor {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>> {
+        Ok(ExtractedContent {
+            text: read_text(path)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Extracts Markdown documents. The metadata comes from YAML front matter if present, and
+/// otherwise the title is taken from the first top-level heading.
+pub struct MarkdownExtractor;
+
+impl Extractor for MarkdownExtractor {
+    fn extract(&self, path: &Path) -> Result<ExtractedContent, Report<ExtractError>> {
+        Ok(parse_markdown(&read_text(path)?))
+    }
+}
+
+fn front_matter_value(front_matter: &str, key: &str) -> Option<String> {
+    front_matter.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+    })
+}
+
+fn parse_markdown(source: &str) -> ExtractedContent {
+    let (front_matter, body) = source
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.split_once("\n---\n"))
+        .unwrap_or(("", source));
+
+    let title = front_matter_value(front_matter, "title").or_else(|| {
+        body.lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(|title| title.trim().to_string())
+    });
+
+    ExtractedContent {
+        text: body.trim().to_string(),
+        title,
+        author: front_matter_value(front_matter, "author"),
+        description: front_matter_value(front_matter, "description"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_markdown;
+
+    #[test]
+    fn markdown_front_matter() {
+        let doc = parse_markdown(
+            "---\ntitle: \"A Post\"\nauthor: Someone\n---\n# Heading\n\nSome text\n",
+        );
+        assert_eq!(doc.title.as_deref(), Some("A Post"));
+        assert_eq!(doc.author.as_deref(), Some("Someone"));
+        assert_eq!(doc.description, None);
+        assert_eq!(doc.text, "# Heading\n\nSome text");
+    }
+
+    #[test]
+    fn markdown_heading_title() {
+        let doc = parse_markdown("Intro\n# The Title\nBody");
+        assert_eq!(doc.title.as_deref(), Some("The Title"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Filters that limit a search to a subset of items. A filter is compiled into a bitmap of the
+//! IDs of the matching items, which the text and vector indexes check as they search.
+
+use std::sync::Arc;
+
+use error_stack::Report;
+use parking_lot::Mutex;
+use roaring::RoaringTreemap;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    db::{self, DbError},
+    SearchStore,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TagFilter {
+    /// Items must have at least one of these tags
+    pub any: Vec<i32>,
+    /// Items must have all of these tags
+    pub all: Vec<i32>,
+    /// Items must have none of these tags
+    pub none: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Visible,
+    Hidden,
+    All,
+}
+
+impl Visibility {
+    /// The value of `items.hidden` to match, or `None` to match both
+    pub fn hidden(&self) -> Option<bool> {
+        match self {
+            Self::Visible => Some(false),
+            Self::Hidden => Some(true),
+            Self::All => None,
+        }
+    }
+}
+
+/// Limits on which items a search covers. Empty lists and missing values match everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchFilter {
+    pub tags: TagFilter,
+    pub source_ids: Vec<i32>,
+    /// Content type prefixes, such as `text/` or `application/pdf`
+    pub content_types: Vec<String>,
+    pub updated_after: Option<time::OffsetDateTime>,
+    pub updated_before: Option<time::OffsetDateTime>,
+    pub visibility: Visibility,
+}
+
+impl SearchFilter {
+    /// Check if the filter matches every visible item, as a filter with no options set does.
+    pub fn is_default(&self) -> bool {
+        self.tags.any.is_empty()
+            && self.tags.all.is_empty()
+            && self.tags.none.is_empty()
+            && self.source_ids.is_empty()
+            && self.content_types.is_empty()
+            && self.updated_after.is_none()
+            && self.updated_before.is_none()
+            && self.visibility == Visibility::Visible
+    }
+
+    /// `LIKE` patterns that match each of the content type prefixes.
+    pub(crate) fn content_type_patterns(&self) -> Vec<String> {
+        self.content_types
+            .iter()
+            .map(|prefix| {
+                let escaped = prefix
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                format!("{escaped}%")
+            })
+            .collect()
+    }
+
+    /// Find the searchable items that match the filter.
+    pub async fn compile(&self, pool: &PgPool) -> Result<RoaringTreemap, Report<DbError>> {
+        let ids = db::items::filter_item_ids(pool, self).await?;
+        Ok(ids.into_iter().map(|id| id as u64).collect())
+    }
+}
+
+/// The compiled default filter, which almost every search uses. Keeping it saves a scan of
+/// every item on each search. It is cleared whenever an item's searchable state changes.
+#[derive(Default)]
+pub(crate) struct DefaultFilterCache {
+    /// The number of times the cache has been cleared, and the cached filter
+    state: Mutex<(u64, Option<Arc<RoaringTreemap>>)>,
+}
+
+impl DefaultFilterCache {
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.0 += 1;
+        state.1 = None;
+    }
+}
+
+impl SearchStore {
+    /// Find the searchable items that match a filter. The default filter is cached.
+    pub async fn compile_filter(
+        &self,
+        filter: &SearchFilter,
+    ) -> Result<Arc<RoaringTreemap>, Report<DbError>> {
+        if !filter.is_default() {
+            return Ok(Arc::new(filter.compile(&self.pg).await?));
+        }
+
+        let generation = {
+            let state = self.default_filter.state.lock();
+            if let Some(compiled) = &state.1 {
+                return Ok(compiled.clone());
+            }
+            state.0
+        };
+
+        let compiled = Arc::new(filter.compile(&self.pg).await?);
+
+        // Don't save the result if the cache was cleared while the query ran, since the query
+        // may have missed the change.
+        let mut state = self.default_filter.state.lock();
+        if state.0 == generation {
+            state.1 = Some(compiled.clone());
+        }
+
+        Ok(compiled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SearchFilter, TagFilter, Visibility};
+
+    #[test]
+    fn deserialize() {
+        let filter: SearchFilter = serde_json::from_str(
+            r#"{
+                "tags": { "any": [1, 2], "none": [3] },
+                "content_types": ["text/", "application/pdf"],
+                "visibility": "all"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(filter.tags.any, vec![1, 2]);
+        assert!(filter.tags.all.is_empty());
+        assert_eq!(filter.tags.none, vec![3]);
+        assert!(filter.source_ids.is_empty());
+        assert_eq!(filter.visibility, Visibility::All);
+        assert_eq!(filter.visibility.hidden(), None);
+        assert_eq!(SearchFilter::default().visibility.hidden(), Some(false));
+    }
+
+    #[test]
+    fn is_default() {
+        assert!(SearchFilter::default().is_default());
+
+        let tagged = SearchFilter {
+            tags: TagFilter {
+                none: vec![1],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!tagged.is_default());
+
+        let all = SearchFilter {
+            visibility: Visibility::All,
+            ..Default::default()
+        };
+        assert!(!all.is_default());
+    }
+
+    #[test]
+    fn content_type_patterns() {
+        let filter = SearchFilter {
+            content_types: vec!["text/".to_string(), "application/x_50%".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter.content_type_patterns(),
+            vec!["text/%".to_string(), "application/x\\_50\\%%".to_string()]
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use error_stack::{IntoReport, Report, ResultExt};
+use thiserror::Error;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    chunking::{chunk_text, ChunkOptions},
+    db::{
+        self,
+        item_chunks::NewItemChunk,
+        items::ItemStatus,
+        jobs::{Job, JobPayload},
+        DbError,
+    },
+    models::{bi_encoder::BiEncoderModel, ModelError},
+    summarize::summarize_text,
+    synthesis::SynthesisRequest,
+    tagging::TagSuggestionQuery,
+    SearchStore,
+};
+
+/// How long to wait before checking the queue again when it is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a worker updates the lock on the job it is running.
+const JOB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+/// Jobs whose worker has not updated their lock for this long are assumed to have crashed.
+const JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often to check for stale jobs and save changed vector indexes.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// The number of chunks to send to a bi-encoder at once.
+const EMBED_BATCH_SIZE: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Database error")]
+    Db,
+    #[error("Item {0} not found")]
+    ItemNotFound(i64),
+    #[error("Item has no content")]
+    NoContent,
+    #[error("Unsupported content type {0}")]
+    UnsupportedContentType(String),
+    #[error("Failed to extract item content")]
+    Extract,
+    #[error("Failed to embed item content")]
+    Embed,
+    #[error("No summary model is configured")]
+    NoSummaryModel,
+    #[error("Model {0} is not a loaded completion model")]
+    ModelNotLoaded(i32),
+    #[error("Failed to summarize item")]
+    Summarize,
+    #[error("Failed to synthesize items")]
+    Synthesize,
+    #[error("Failed to tag item")]
+    Tag,
+}
+
+impl SearchStore {
+    /// Add a job to the queue and wake up a worker to run it.
+    pub async fn enqueue_job(&self, payload: &JobPayload) -> Result<i64, Report<DbError>> {
+        let id = db::jobs::enqueue_job(&self.pg, payload).await?;
+        self.job_notify.notify_one();
+        Ok(id)
+    }
+
+    /// Run jobs from the queue until the process exits. Multiple workers can run at once,
+    /// in this process or others.
+    pub async fn run_job_worker(&self) {
+        let mut last_maintenance: Option<Instant> = None;
+
+        loop {
+            if last_maintenance
+                .map(|t| t.elapsed() >= MAINTENANCE_INTERVAL)
+                .unwrap_or(true)
+            {
+                match db::jobs::reset_stale_jobs(&self.pg, JOB_TIMEOUT).await {
+                    Ok(0) => {}
+                    Ok(count) => warn!(%count, "Reset stale jobs"),
+                    Err(e) => error!(error=?e, "Failed to reset stale jobs"),
+                }
+
+                if let Err(e) = self.compact_vector_indexes().await {
+                    error!(error=?e, "Failed to compact vector indexes");
+                }
+
+                if let Err(e) = self.save_vector_indexes().await {
+                    error!(error=?e, "Failed to save vector indexes");
+                }
+
+                last_maintenance = Some(Instant::now());
+            }
+
+            match db::jobs::claim_job(&self.pg).await {
+                Ok(Some(job)) => self.run_job(job).await,
+                Ok(None) => {
+                    tokio::select! {
+                        _ = self.job_notify.notified() => {},
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {},
+                    }
+                }
+                Err(e) => {
+                    error!(error=?e, "Failed to claim job");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    #[instrument(skip(self, job), fields(id = job.id, attempt = job.attempts))]
+    async fn run_job(&self, job: Job) {
+        // Jobs on local models can run for a long time, so keep the lock fresh while the job
+        // runs. Only jobs whose worker has died are reset.
+        let work = self.run_job_payload(&job);
+        tokio::pin!(work);
+        let mut heartbeat = tokio::time::interval(JOB_HEARTBEAT_INTERVAL);
+        // The first tick completes right away.
+        heartbeat.tick().await;
+        let result = loop {
+            tokio::select! {
+                result = &mut work => break result,
+                _ = heartbeat.tick() => {
+                    if let Err(e) = db::jobs::touch_job(&self.pg, job.id).await {
+                        warn!(error=?e, "Failed to update job lock");
+                    }
+                }
+            }
+        };
+
+        let recorded = match result {
+            Ok(output) => {
+                info!("Job finished");
+                db::jobs::complete_job(&self.pg, job.id, output.as_ref()).await
+            }
+            Err(e) => {
+                error!(error=?e, "Job failed");
+                db::jobs::fail_job(&self.pg, job.id, &format!("{e:?}")).await
+            }
+        };
+
+        if let Err(e) = recorded {
+            error!(error=?e, "Failed to record job result");
+        }
+    }
+
+    async fn run_job_payload(
+        &self,
+        job: &Job,
+    ) -> Result<Option<serde_json::Value>, Report<JobError>> {
+        match &job.payload {
+            JobPayload::ProcessItem { item_id } => {
+                self.run_process_item_job(job, *item_id).await.map(|_| None)
+            }
+            JobPayload::SummarizeItem { item_id, model_id } => {
+                self.summarize_item(*item_id, *model_id).await.map(|_| None)
+            }
+            JobPayload::Synthesize { request } => self.run_synthesis_job(request).await.map(Some),
+            JobPayload::TagItem { item_id } => self.tag_item(*item_id).await.map(|_| None),
+            JobPayload::EmbedItem { item_id } => {
+                self.run_embed_item_job(*item_id).await.map(|_| None)
+            }
+        }
+    }
+
+    /// Process an item, keeping its status up to date. If processing fails and the job will be
+    /// retried, the item goes back to `pending_processing`.
+    async fn run_process_item_job(&self, job: &Job, item_id: i64) -> Result<(), Report<JobError>> {
+        db::items::set_item_status(&self.pg, item_id, ItemStatus::Processing, None)
+            .await
+            .change_context(JobError::Db)?;
+
+        let result = self.process_item(item_id).await;
+
+        let (status, detail) = match &result {
+            Ok(()) => (ItemStatus::Ready, None),
+            Err(e) if job.is_last_attempt() => (ItemStatus::Error, Some(e.to_string())),
+            Err(e) => (
+                ItemStatus::PendingProcessing,
+                Some(format!("Attempt {} failed: {e}", job.attempts)),
+            ),
+        };
+
+        db::items::set_item_status(&self.pg, item_id, status, detail.as_deref())
+            .await
+            .change_context(JobError::Db)?;
+
+        // The item has already been processed at this point, so a failure here is logged
+        // instead of retrying the job.
+        if let Err(e) = self.update_text_index(item_id).await {
+            error!(error=?e, "Failed to update text index");
+        }
+
+        if result.is_ok() && self.summary_options.model_id.is_some() {
+            let payload = JobPayload::SummarizeItem {
+                item_id,
+                model_id: None,
+            };
+            if let Err(e) = self.enqueue_job(&payload).await {
+                error!(error=?e, "Failed to enqueue summary job");
+            }
+        }
+
+        if result.is_ok() && self.tag_options.auto_apply_threshold.is_some() {
+            if let Err(e) = self.enqueue_job(&JobPayload::TagItem { item_id }).await {
+                error!(error=?e, "Failed to enqueue tagging job");
+            }
+        }
+
+        result
+    }
+
+    /// Summarize an item's content and save the summary on the item.
+    async fn summarize_item(
+        &self,
+        item_id: i64,
+        model_id: Option<i32>,
+    ) -> Result<(), Report<JobError>> {
+        let model_id = model_id
+            .or(self.summary_options.model_id)
+            .ok_or(JobError::NoSummaryModel)
+            .into_report()?;
+
+        let model = self
+            .loaded_completion_models
+            .read()
+            .iter()
+            .find(|m| m.id == model_id)
+            .map(|m| m.model.clone())
+            .ok_or(JobError::ModelNotLoaded(model_id))
+            .into_report()?;
+
+        let text = db::items::get_items_text(&self.pg, &[item_id])
+            .await
+            .change_context(JobError::Db)?
+            .pop()
+            .ok_or(JobError::ItemNotFound(item_id))
+            .into_report()?
+            .processed_content
+            .filter(|text| !text.trim().is_empty())
+            .ok_or(JobError::NoContent)
+            .into_report()?;
+
+        let options = self.summary_options.clone();
+        let summary =
+            tokio::task::spawn_blocking(move || summarize_text(model.as_ref(), &text, &options))
+                .await
+                .into_report()
+                .change_context(JobError::Summarize)?
+                .change_context(JobError::Summarize)
+                .attach_printable_lazy(|| format!("Model {model_id}"))?;
+
+        db::items::set_generated_summary(&self.pg, item_id, &summary)
+            .await
+            .change_context(JobError::Db)
+    }
+
+    /// Synthesize a set of items, returning the result to store with the job.
+    async fn run_synthesis_job(
+        &self,
+        request: &SynthesisRequest,
+    ) -> Result<serde_json::Value, Report<JobError>> {
+        let synthesis = self
+            .synthesize(request)
+            .await
+            .change_context(JobError::Synthesize)?;
+
+        serde_json::to_value(synthesis)
+            .into_report()
+            .change_context(JobError::Synthesize)
+    }
+
+    /// Add the suggested tags that meet the auto-apply threshold to an item.
+    async fn tag_item(&self, item_id: i64) -> Result<(), Report<JobError>> {
+        let Some(threshold) = self.tag_options.auto_apply_threshold else {
+            return Ok(());
+        };
+
+        let query = TagSuggestionQuery {
+            apply_threshold: Some(threshold),
+            ..Default::default()
+        };
+        let suggestions = self
+            .suggest_tags(item_id, &query)
+            .await
+            .change_context(JobError::Tag)?;
+        info!(applied=?suggestions.applied, "Tagged item");
+
+        Ok(())
+    }
+
+    async fn process_item(&self, item_id: i64) -> Result<(), Report<JobError>> {
+        let item = db::items::lookup_by_id(&self.pg, item_id)
+            .await
+            .change_context(JobError::Db)?
+            .ok_or(JobError::ItemNotFound(item_id))
+            .into_report()?;
+
+        let path = item
+            .saved_original_path
+            .as_ref()
+            .ok_or(JobError::NoContent)
+            .into_report()?;
+        let full_path = Path::new(&self.file_storage_location).join(path);
+
+        let extractor = self
+            .extractors
+            .get(&item.content_type)
+            .ok_or_else(|| JobError::UnsupportedContentType(item.content_type.clone()))
+            .into_report()?;
+
+        let content = tokio::task::spawn_blocking(move || extractor.extract(&full_path))
+            .await
+            .into_report()
+            .change_context(JobError::Extract)?
+            .change_context(JobError::Extract)?;
+
+        db::items::update_item_content(
+            &self.pg,
+            item_id,
+            &content.text,
+            content.title.as_deref(),
+            content.author.as_deref(),
+            content.description.as_deref(),
+        )
+        .await
+        .change_context(JobError::Db)?;
+
+        self.embed_item(item_id, item.version, content.text.into())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Queue an embedding job for each processed item that a bi-encoder has not embedded yet.
+    /// Items are only embedded while they are processed, so this catches up on the items that
+    /// were processed before the model was loaded. Returns the number of jobs queued.
+    pub async fn enqueue_missing_embeddings(
+        &self,
+        model_id: i32,
+    ) -> Result<usize, Report<DbError>> {
+        let item_ids = db::item_chunks::list_unembedded_items(&self.pg, model_id).await?;
+        if !item_ids.is_empty() {
+            info!(%model_id, count = item_ids.len(), "Queueing items to embed");
+        }
+
+        for item_id in &item_ids {
+            self.enqueue_job(&JobPayload::EmbedItem { item_id: *item_id })
+                .await?;
+        }
+
+        Ok(item_ids.len())
+    }
+
+    /// Embed an item that has already been processed. Items that are not ready are skipped,
+    /// since they will be embedded when they are processed.
+    async fn run_embed_item_job(&self, item_id: i64) -> Result<(), Report<JobError>> {
+        let item = db::items::lookup_by_id(&self.pg, item_id)
+            .await
+            .change_context(JobError::Db)?
+            .ok_or(JobError::ItemNotFound(item_id))
+            .into_report()?;
+        if item.status != ItemStatus::Ready {
+            return Ok(());
+        }
+
+        let text = db::items::get_items_text(&self.pg, &[item_id])
+            .await
+            .change_context(JobError::Db)?
+            .pop()
+            .ok_or(JobError::ItemNotFound(item_id))
+            .into_report()?
+            .processed_content
+            .filter(|text| !text.trim().is_empty())
+            .ok_or(JobError::NoContent)
+            .into_report()?;
+
+        self.embed_item(item_id, item.version, text.into()).await
+    }
+
+    /// Chunk and embed an item's content with every loaded bi-encoder, and add the chunks to
+    /// the model's vector index. Models that have already embedded this version of the item are
+    /// skipped.
+    async fn embed_item(
+        &self,
+        item_id: i64,
+        version: i32,
+        text: Arc<str>,
+    ) -> Result<(), Report<JobError>> {
+        let models = self
+            .loaded_bi_encoders
+            .read()
+            .iter()
+            .map(|m| (m.id, m.model.clone()))
+            .collect::<Vec<_>>();
+
+        for (model_id, model) in models {
+            let current_version = db::item_chunks::get_chunks_version(&self.pg, item_id, model_id)
+                .await
+                .change_context(JobError::Db)?;
+            if current_version == Some(version) {
+                continue;
+            }
+
+            let options = ChunkOptions {
+                max_tokens: self.chunk_options.max_tokens.min(model.max_input_tokens()),
+                ..self.chunk_options.clone()
+            };
+
+            let text = text.clone();
+            let chunks = tokio::task::spawn_blocking(move || embed_chunks(&model, &text, &options))
+                .await
+                .into_report()
+                .change_context(JobError::Embed)?
+                .change_context(JobError::Embed)
+                .attach_printable_lazy(|| format!("Model {model_id}"))?;
+
+            db::item_chunks::replace_item_chunks(&self.pg, item_id, model_id, version, &chunks)
+                .await
+                .change_context(JobError::Db)?;
+
+            if let Some(index) = self.vector_indexes.get(model_id) {
+                tokio::task::spawn_blocking(move || {
+                    index.set_item(
+                        item_id,
+                        version,
+                        chunks.iter().map(|c| c.embedding.as_slice()),
+                    )
+                })
+                .await
+                .into_report()
+                .change_context(JobError::Embed)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn embed_chunks(
+    model: &BiEncoderModel,
+    text: &str,
+    options: &ChunkOptions,
+) -> Result<Vec<NewItemChunk>, Report<ModelError>> {
+    let chunks = chunk_text(text, options, |s| model.count_tokens(s));
+
+    // Chunk boundaries are byte offsets, but the database stores character offsets. Chunks
+    // are in order, so count characters incrementally instead of from the start each time.
+    let mut byte_pos = 0;
+    let mut char_pos = 0;
+
+    let mut output = Vec::with_capacity(chunks.len());
+    for batch in chunks.chunks(EMBED_BATCH_SIZE) {
+        let texts = batch
+            .iter()
+            .map(|chunk| &text[chunk.start..chunk.end])
+            .collect::<Vec<_>>();
+        let embeddings = model.encode(&texts)?;
+
+        for ((chunk, chunk_text), embedding) in batch.iter().zip(texts).zip(embeddings) {
+            char_pos += text[byte_pos..chunk.start].chars().count();
+            byte_pos = chunk.start;
+
+            let start_idx = char_pos;
+            let end_idx = start_idx + chunk_text.chars().count() - 1;
+            output.push(NewItemChunk {
+                start_idx: start_idx as i32,
+                end_idx: end_idx as i32,
+                embedding,
+            });
+        }
+    }
+
+    Ok(output)
+}
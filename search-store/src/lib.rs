@@ -1,24 +1,46 @@
+pub mod ask;
+pub mod chunking;
 pub mod db;
+pub mod extract;
+pub mod filter;
+pub mod jobs;
 pub mod models;
+pub mod search;
+pub mod summarize;
+pub mod synthesis;
+pub mod tagging;
+pub mod text_index;
+pub mod vector_index;
 
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
 };
 
+use chunking::ChunkOptions;
 use error_stack::{IntoReport, Report, ResultExt};
+use extract::ExtractorRegistry;
+use filter::DefaultFilterCache;
 use models::{
     bi_encoder::BiEncoderModel,
     chat::ChatModel,
-    completion::CompletionModel,
+    completion::{CompletionModel, SamplingParams},
+    cross_encoder::CrossEncoderModel,
     download::{DownloadError, ModelCache},
-    CrossEncoderModel, ModelDefinition, ModelError,
+    ModelDefinition, ModelError,
 };
 use parking_lot::RwLock;
 use sqlx::PgPool;
+use summarize::SummaryOptions;
+use tagging::TagOptions;
+use text_index::{TextIndex, TextIndexError};
+use tokio::sync::Notify;
+use vector_index::{VectorIndexOptions, VectorIndexes};
 
 use crate::models::{
-    chat::ggml_chat::GgmlChatModel, completion::ggml_completion::GgmlCompletionModel,
+    chat::{ggml_chat::GgmlChatModel, openai_chat::OpenAiChatModel},
+    completion::{ggml_completion::GgmlCompletionModel, openai_completion::OpenAiCompletionModel},
+    openai::OpenAiConfig,
     GgmlModelParams, ModelParams,
 };
 
@@ -36,13 +58,39 @@ pub struct SearchStore {
     pub loaded_chat_models: RwLock<Vec<LoadedModel<dyn ChatModel>>>,
     pub loaded_completion_models: RwLock<Vec<LoadedModel<dyn CompletionModel>>>,
 
-    loaded_bi_encoders: RwLock<Vec<LoadedModel<BiEncoderModel>>>,
-    loaded_cross_encoders: RwLock<Vec<LoadedModel<CrossEncoderModel>>>,
+    pub loaded_bi_encoders: RwLock<Vec<LoadedModel<BiEncoderModel>>>,
+    pub loaded_cross_encoders: RwLock<Vec<LoadedModel<CrossEncoderModel>>>,
+
+    /// Extracts text from uploaded items. Register additional extractors here to support
+    /// more content types.
+    pub extractors: ExtractorRegistry,
+    /// How to split item content for embedding. The chunk size is also limited by the input
+    /// size of each bi-encoder.
+    pub chunk_options: ChunkOptions,
+    /// How to summarize items after they are processed.
+    pub summary_options: SummaryOptions,
+    /// How to suggest tags for items.
+    pub tag_options: TagOptions,
+    /// Nearest neighbor indexes over the chunk embeddings of each loaded bi-encoder.
+    pub vector_indexes: VectorIndexes,
+    /// Full-text index over searchable items.
+    pub text_index: Arc<TextIndex>,
+    default_filter: DefaultFilterCache,
+
+    /// Wakes up a job worker when a job is added.
+    job_notify: Notify,
 }
 
 impl SearchStore {
-    pub fn new(pg: PgPool, file_storage_location: String, model_cache: ModelCache) -> Self {
-        Self {
+    pub fn new(
+        pg: PgPool,
+        file_storage_location: String,
+        index_location: PathBuf,
+        model_cache: ModelCache,
+    ) -> Result<Self, Report<TextIndexError>> {
+        let text_index = TextIndex::open(&index_location.join("text"))?;
+
+        Ok(Self {
             pg,
             model_cache,
             file_storage_location,
@@ -50,7 +98,15 @@ impl SearchStore {
             loaded_completion_models: RwLock::new(Vec::new()),
             loaded_bi_encoders: RwLock::new(Vec::new()),
             loaded_cross_encoders: RwLock::new(Vec::new()),
-        }
+            extractors: ExtractorRegistry::default(),
+            chunk_options: ChunkOptions::default(),
+            summary_options: SummaryOptions::default(),
+            tag_options: TagOptions::default(),
+            vector_indexes: VectorIndexes::new(index_location, VectorIndexOptions::default()),
+            text_index: Arc::new(text_index),
+            default_filter: DefaultFilterCache::default(),
+            job_notify: Notify::new(),
+        })
     }
 
     pub fn load_model(&self, model: &ModelDefinition) -> Result<(), Report<ModelError>> {
@@ -76,15 +132,25 @@ impl SearchStore {
     ) -> Result<(), Report<ModelError>> {
         assert_eq!(model.category, models::ModelCategory::Chat);
 
-        let loaded = match &model.params {
-            ModelParams::OpenaiChat | ModelParams::OpenaiCompletions => {
-                todo!()
+        match &model.params {
+            ModelParams::OpenaiChat(params) => self.add_chat_model(
+                model.id,
+                Arc::new(OpenAiChatModel::new(
+                    model.name.clone(),
+                    params.model.clone().unwrap_or_else(|| model.name.clone()),
+                    OpenAiConfig::for_model(params),
+                )),
+            ),
+            ModelParams::OpenaiCompletions(_) => {
+                return Err(ModelError::LoadingError)
+                    .into_report()
+                    .attach_printable("OpenAI completions models do not support chat");
             }
             ModelParams::Ggml(GgmlModelParams {
                 model: model_name,
                 location,
                 tokenizer,
-                ..
+                prompt_template,
             }) => {
                 let model_dir = model_dir
                     .ok_or(ModelError::LoadingError)
@@ -94,27 +160,33 @@ impl SearchStore {
                 let weights_path = self.model_cache.get_cache_path_for_model(location);
                 let tokenizer = tokenizer.as_ref().map(|_| model_dir.join("tokenizer.json"));
 
-                Arc::new(GgmlChatModel::new(
-                    model.name.clone(),
-                    model_name,
-                    &weights_path,
-                    tokenizer,
-                )?)
+                self.add_chat_model(
+                    model.id,
+                    Arc::new(GgmlChatModel::new(
+                        model.name.clone(),
+                        model_name,
+                        &weights_path,
+                        tokenizer,
+                        prompt_template,
+                    )?),
+                )
             }
             ModelParams::RustBert(location) => todo!(),
         };
 
-        self.loaded_completion_models.write().push(LoadedModel {
-            id: model.id,
-            model: loaded.clone(),
-        });
+        Ok(())
+    }
 
-        self.loaded_chat_models.write().push(LoadedModel {
-            id: model.id,
-            model: loaded,
+    /// Chat models can also run completions, so add the model to both lists.
+    fn add_chat_model<T: ChatModel + 'static>(&self, id: i32, model: Arc<T>) {
+        self.loaded_completion_models.write().push(LoadedModel {
+            id,
+            model: model.clone(),
         });
 
-        Ok(())
+        self.loaded_chat_models
+            .write()
+            .push(LoadedModel { id, model });
     }
 
     fn load_completion_model(
@@ -128,10 +200,17 @@ impl SearchStore {
                 || model.category == models::ModelCategory::Instruct
         );
 
-        let loaded = match &model.params {
-            ModelParams::OpenaiChat | ModelParams::OpenaiCompletions => {
-                todo!()
-            }
+        let loaded: Arc<dyn CompletionModel> = match &model.params {
+            ModelParams::OpenaiChat(params) => Arc::new(OpenAiChatModel::new(
+                model.name.clone(),
+                params.model.clone().unwrap_or_else(|| model.name.clone()),
+                OpenAiConfig::for_model(params),
+            )),
+            ModelParams::OpenaiCompletions(params) => Arc::new(OpenAiCompletionModel::new(
+                model.name.clone(),
+                params.model.clone().unwrap_or_else(|| model.name.clone()),
+                OpenAiConfig::for_model(params),
+            )),
             ModelParams::Ggml(GgmlModelParams {
                 model: model_name,
                 location,
@@ -167,19 +246,36 @@ impl SearchStore {
     fn load_bi_encoder_model(
         &self,
         model: &ModelDefinition,
-        weights_path: Option<PathBuf>,
+        model_dir: Option<PathBuf>,
     ) -> Result<(), Report<ModelError>> {
         assert_eq!(model.category, models::ModelCategory::BiEncoder);
-        todo!()
+
+        let loaded = BiEncoderModel::new(model.name.clone(), &model.params, model_dir.as_deref())?;
+
+        self.loaded_bi_encoders.write().push(LoadedModel {
+            id: model.id,
+            model: Arc::new(loaded),
+        });
+
+        Ok(())
     }
 
     fn load_cross_encoder_model(
         &self,
         model: &ModelDefinition,
-        weights_path: Option<PathBuf>,
+        model_dir: Option<PathBuf>,
     ) -> Result<(), Report<ModelError>> {
         assert_eq!(model.category, models::ModelCategory::CrossEncoder);
-        todo!()
+
+        let loaded =
+            CrossEncoderModel::new(model.name.clone(), &model.params, model_dir.as_deref())?;
+
+        self.loaded_cross_encoders.write().push(LoadedModel {
+            id: model.id,
+            model: Arc::new(loaded),
+        });
+
+        Ok(())
     }
 
     /// A quick lookup for if a particular model is loaded.
@@ -221,3 +317,41 @@ pub fn check_temperature(temperature: &Option<f32>) -> Result<(), Report<ModelEr
 
     Ok(())
 }
+
+pub fn check_sampling_params(params: &SamplingParams) -> Result<(), Report<ModelError>> {
+    if params.top_k == Some(0) {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("top_k must be greater than 0");
+    }
+
+    if let Some(top_p) = params.top_p {
+        if top_p <= 0.0 || top_p > 1.0 {
+            return Err(ModelError::ParameterError)
+                .into_report()
+                .attach_printable("top_p must be greater than 0 and at most 1");
+        }
+    }
+
+    if let Some(repeat_penalty) = params.repeat_penalty {
+        if repeat_penalty <= 0.0 {
+            return Err(ModelError::ParameterError)
+                .into_report()
+                .attach_printable("repeat_penalty must be greater than 0");
+        }
+    }
+
+    if params.max_tokens == Some(0) {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("max_tokens must be greater than 0");
+    }
+
+    if params.stop.iter().any(|s| s.is_empty()) {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("stop sequences must not be empty");
+    }
+
+    Ok(())
+}
@@ -66,6 +66,16 @@ impl BiEncoderModel {
         self.num_dimensions
     }
 
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.tokenizer.count_tokens(text)
+    }
+
+    /// The largest number of tokens that can be encoded without truncation, leaving room for
+    /// the special tokens that the tokenizer adds.
+    pub fn max_input_tokens(&self) -> usize {
+        self.tokenizer.max_seq_length().saturating_sub(2)
+    }
+
     pub fn encode<S: AsRef<str> + Sync>(
         &self,
         sentences: &[S],
@@ -1,10 +1,15 @@
+pub mod context;
 pub mod ggml_chat;
 pub mod openai_chat;
+pub mod prompt_template;
 
 use error_stack::Report;
 use serde::{Deserialize, Serialize};
 
-use super::{completion::CompletionModel, ModelError};
+use super::{
+    completion::{CompletionModel, FinishReason, SamplingParams, TokenCallback, TokenUsage},
+    ModelError,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
@@ -18,30 +23,39 @@ pub enum ChatRole {
 pub struct ChatMessage {
     pub role: ChatRole,
     pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
 }
 
-impl ChatMessage {
-    pub fn format_chatml(&self) -> String {
-        let role = match self.role {
-            ChatRole::System => "system",
-            ChatRole::User => "user",
-            ChatRole::Assistant => "assistant",
-        };
-
-        match &self.name {
-            Some(name) => format!("{} name={}\n{}", role, name, self.content),
-            None => format!("{}\n{}", role, self.content),
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ChatSubmission {
     pub messages: Vec<ChatMessage>,
     pub temperature: Option<f32>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+#[derive(Debug)]
+pub struct ChatOutput {
+    pub message: ChatMessage,
+    pub finish_reason: FinishReason,
+    pub usage: TokenUsage,
 }
 
 pub trait ChatModel: CompletionModel + Send + Sync {
-    fn chat(&self, submission: ChatSubmission) -> Result<ChatMessage, Report<ModelError>>;
+    fn chat(&self, submission: ChatSubmission) -> Result<ChatOutput, Report<ModelError>>;
+
+    /// Run a chat, passing the response text to `on_token` as it is generated. The full
+    /// message is returned once the generation is done.
+    ///
+    /// Models that can not stream send the entire response as a single token.
+    fn chat_streaming(
+        &self,
+        submission: ChatSubmission,
+        on_token: TokenCallback,
+    ) -> Result<ChatOutput, Report<ModelError>> {
+        let response = self.chat(submission)?;
+        let _ = on_token(&response.message.content);
+        Ok(response)
+    }
 }
@@ -0,0 +1,140 @@
+/// Tokens added to each message for the role markers and separators.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// A message from the conversation history, as seen by the context planner.
+#[derive(Debug, Clone, Copy)]
+pub struct ContextMessage {
+    pub tokens: usize,
+    pub important: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ContextPlan {
+    /// The indexes of the messages to send to the model, oldest first.
+    pub included: Vec<usize>,
+    /// When older messages had to be left out, this is the index of the newest one. Everything
+    /// up to this point that is not included should be covered by a summary instead.
+    pub summarized_through: Option<usize>,
+}
+
+/// Choose the messages from a conversation history that fit into `budget` tokens.
+///
+/// Messages marked as important are always kept. The remaining space goes to the most recent
+/// messages, and once a message is left out everything older than it is left out too. If any
+/// messages are left out, `summary_tokens` are set aside for a summary of them.
+///
+/// Returns `None` if the important messages and the summary don't fit into the budget.
+pub fn plan_context(
+    messages: &[ContextMessage],
+    budget: usize,
+    summary_tokens: usize,
+) -> Option<ContextPlan> {
+    let total: usize = messages.iter().map(|m| m.tokens).sum();
+    if total <= budget {
+        return Some(ContextPlan {
+            included: (0..messages.len()).collect(),
+            summarized_through: None,
+        });
+    }
+
+    let important: usize = messages
+        .iter()
+        .filter(|m| m.important)
+        .map(|m| m.tokens)
+        .sum();
+    let mut remaining = budget.checked_sub(summary_tokens + important)?;
+
+    let mut summarized_through = None;
+    for (i, message) in messages.iter().enumerate().rev() {
+        if message.important {
+            continue;
+        }
+
+        if message.tokens > remaining {
+            summarized_through = Some(i);
+            break;
+        }
+
+        remaining -= message.tokens;
+    }
+
+    let included = messages
+        .iter()
+        .enumerate()
+        .filter(|(i, m)| m.important || summarized_through.map(|s| *i > s).unwrap_or(true))
+        .map(|(i, _)| i)
+        .collect();
+
+    Some(ContextPlan {
+        included,
+        summarized_through,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{plan_context, ContextMessage, ContextPlan};
+
+    fn messages(tokens: &[(usize, bool)]) -> Vec<ContextMessage> {
+        tokens
+            .iter()
+            .map(|&(tokens, important)| ContextMessage { tokens, important })
+            .collect()
+    }
+
+    #[test]
+    fn everything_fits() {
+        let history = messages(&[(10, false), (20, false), (30, false)]);
+        assert_eq!(
+            plan_context(&history, 60, 10),
+            Some(ContextPlan {
+                included: vec![0, 1, 2],
+                summarized_through: None
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_newest_messages() {
+        let history = messages(&[(10, false), (20, false), (30, false), (10, false)]);
+        // 50 budget - 10 reserved for the summary leaves room for the last two messages.
+        assert_eq!(
+            plan_context(&history, 50, 10),
+            Some(ContextPlan {
+                included: vec![2, 3],
+                summarized_through: Some(1)
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_important_messages() {
+        let history = messages(&[(10, true), (20, false), (30, false), (10, false)]);
+        assert_eq!(
+            plan_context(&history, 50, 10),
+            Some(ContextPlan {
+                included: vec![0, 3],
+                summarized_through: Some(2)
+            })
+        );
+    }
+
+    #[test]
+    fn stops_at_first_message_that_does_not_fit() {
+        // The first message would fit, but it's older than one that was left out.
+        let history = messages(&[(5, false), (40, false), (10, false)]);
+        assert_eq!(
+            plan_context(&history, 30, 5),
+            Some(ContextPlan {
+                included: vec![2],
+                summarized_through: Some(1)
+            })
+        );
+    }
+
+    #[test]
+    fn important_messages_do_not_fit() {
+        let history = messages(&[(30, true), (20, false), (10, true)]);
+        assert_eq!(plan_context(&history, 45, 10), None);
+    }
+}
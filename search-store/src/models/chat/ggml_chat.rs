@@ -1,23 +1,29 @@
 use std::{
+    ops::ControlFlow,
     path::{Path, PathBuf},
-    sync::Arc,
+    time::Instant,
 };
 
 use error_stack::{IntoReport, Report, ResultExt};
-use llm::{InferenceParameters, InferenceSessionConfig, OutputRequest};
-use rayon::prelude::*;
+use llm::{InferenceSessionConfig, OutputRequest, TokenId};
 use tracing::{info, instrument};
 
-use crate::models::{completion::CompletionModel, ggml, ModelError};
+use crate::models::{
+    completion::{CompletionModel, CompletionOutput, TokenCallback},
+    ggml, ModelError,
+};
 
-use super::{ChatModel, ChatRole, ChatSubmission};
+use super::{
+    prompt_template::{PromptFormat, PromptTemplate},
+    ChatModel, ChatOutput, ChatRole, ChatSubmission,
+};
 
 pub struct GgmlChatModel {
     name: String,
     model: Box<dyn llm::Model>,
-    start_token: Option<llm::TokenId>,
-    end_token: Option<llm::TokenId>,
-    newline_token: Option<llm::TokenId>,
+    format: PromptFormat,
+    /// The template's special markers that the vocabulary has as single tokens
+    special_tokens: Vec<(String, TokenId)>,
 }
 
 impl GgmlChatModel {
@@ -26,117 +32,204 @@ impl GgmlChatModel {
         model_type: &str,
         weights_path: &Path,
         tokenizer_path: Option<PathBuf>,
+        template: &PromptTemplate,
     ) -> Result<Self, Report<ModelError>> {
         let model = ggml::load_ggml_model(&name, model_type, weights_path, tokenizer_path)?;
+        let format = template.format();
         let vocab = model.vocabulary();
-        let start_token = vocab.id("<|im_start|>".as_bytes());
-        let end_token = vocab.id("<|im_end|>".as_bytes());
-        let newline_token = vocab.tokenize("\n", false).unwrap();
+        let special_tokens = format
+            .special_tokens
+            .iter()
+            .filter_map(|marker| {
+                vocab
+                    .id(marker.as_bytes())
+                    .map(|token| (marker.clone(), token))
+            })
+            .collect();
+
         Ok(Self {
             name,
             model,
-            start_token,
-            end_token,
-            newline_token: newline_token.get(0).map(|(_, token)| *token),
+            format,
+            special_tokens,
         })
     }
 }
 
-impl ChatModel for GgmlChatModel {
-    #[instrument(skip(self), fields(name = %self.name))]
-    fn chat(&self, submission: ChatSubmission) -> Result<super::ChatMessage, Report<ModelError>> {
-        let vocab = self.model.vocabulary();
-
-        let token_list = submission
-            .messages
-            .par_iter()
-            .map(|message| vocab.tokenize(&message.content, false))
-            .collect::<Result<Vec<_>, _>>()
-            .into_report()
-            .change_context(ModelError::ModelFailure)?;
-
-        let padding_count = match (self.start_token.is_some(), self.end_token.is_some()) {
-            (true, true) => 2,
-            (true, false) | (false, true) => 1,
-            (false, false) => 0,
-        };
-
-        let token_count: usize = token_list
+/// Tokenize a prompt, adding each special marker as its single token instead of splitting it
+/// into ordinary text tokens. Only text at the very start of the prompt gets a BOS token.
+fn tokenize_prompt<E>(
+    prompt: &str,
+    special_tokens: &[(String, TokenId)],
+    mut tokenize: impl FnMut(&str, bool) -> Result<Vec<TokenId>, E>,
+) -> Result<Vec<TokenId>, E> {
+    let mut tokens = Vec::new();
+    let mut rest = prompt;
+
+    loop {
+        let next = special_tokens
             .iter()
-            .map(|tokens| tokens.len() + padding_count + 1)
-            .sum();
-
-        let mut tokens = Vec::with_capacity(token_count);
-        // if let Some(bot_token) = self.model.bot_token_id() {
-        //     tokens.push(bot_token);
-        // }
+            .filter_map(|(marker, token)| {
+                rest.find(marker.as_str()).map(|pos| (pos, marker, *token))
+            })
+            .min_by_key(|(pos, marker, _)| (*pos, std::cmp::Reverse(marker.len())));
+
+        let text = next.map(|(pos, _, _)| &rest[..pos]).unwrap_or(rest);
+        if !text.is_empty() {
+            let bos = tokens.is_empty();
+            tokens.extend(tokenize(text, bos)?);
+        }
 
-        for t in token_list {
-            if let Some(start_token) = self.start_token {
-                tokens.push(start_token);
-            }
+        let Some((pos, marker, token)) = next else {
+            break;
+        };
+        tokens.push(token);
+        rest = &rest[pos + marker.len()..];
+    }
 
-            tokens.extend(t.iter().map(|(_, token)| token));
+    Ok(tokens)
+}
 
-            if let Some(end_token) = self.end_token {
-                tokens.push(end_token);
-            }
+impl ChatModel for GgmlChatModel {
+    fn chat(&self, submission: ChatSubmission) -> Result<ChatOutput, Report<ModelError>> {
+        self.chat_streaming(submission, &mut |_| ControlFlow::Continue(()))
+    }
 
-            if let Some(newline_token) = self.newline_token {
-                tokens.push(newline_token);
-            }
+    #[instrument(skip(self, on_token), fields(name = %self.name))]
+    fn chat_streaming(
+        &self,
+        submission: ChatSubmission,
+        on_token: TokenCallback,
+    ) -> Result<ChatOutput, Report<ModelError>> {
+        let prompt = self.format.render(&submission.messages);
+        let vocab = self.model.vocabulary();
+        let tokens = tokenize_prompt(&prompt, &self.special_tokens, |text, bos| {
+            vocab
+                .tokenize(text, bos)
+                .map(|tokens| tokens.into_iter().map(|(_, token)| token).collect())
+        })
+        .into_report()
+        .change_context(ModelError::ModelFailure)?;
+
+        if tokens.len() >= self.model.context_size() {
+            return Err(ModelError::ParameterError)
+                .into_report()
+                .attach_printable_lazy(|| {
+                    format!(
+                        "The prompt has {} tokens, which does not fit in the model's context of {}",
+                        tokens.len(),
+                        self.model.context_size()
+                    )
+                });
         }
 
-        let temperature = submission.temperature.unwrap_or(0.3);
-
-        let params = InferenceParameters {
-            sampler: Arc::new(llm::samplers::TopPTopK {
-                temperature,
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
-
-        let mut output_tokens = Vec::new();
+        let params = ggml::inference_parameters(submission.temperature, &submission.sampling);
 
         let mut session = self.model.start_session(InferenceSessionConfig::default());
-        let mut output = OutputRequest {
-            all_logits: None,
-            embeddings: None,
-        };
+        let mut output = OutputRequest::default();
+        let start = Instant::now();
         self.model
             .evaluate(&mut session, &params, &tokens, &mut output);
+        let prompt_eval_time = start.elapsed();
         info!(input_tokens=%tokens.len(), "Evaluated input");
 
-        let mut num_output_tokens = 0;
-        while let Ok(token) = session.infer_next_token(
+        // Stop if the model tries to end its turn or start a new message.
+        let stop = submission
+            .sampling
+            .stop
+            .iter()
+            .chain(self.format.stop.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let generation = ggml::generate(
             self.model.as_ref(),
+            &mut session,
             &params,
-            &mut output,
-            &mut rand::thread_rng(),
-        ) {
-            num_output_tokens += 1;
-            if token != "<|im_end|>".as_bytes() && token != "<|im_start|>".as_bytes() {
-                output_tokens.extend(token);
-            }
-        }
-
-        info!(output_tokens=%num_output_tokens, "Done");
-
-        Ok(super::ChatMessage {
-            role: ChatRole::Assistant,
-            content: String::from_utf8_lossy(&output_tokens).to_string(),
-            name: None,
+            &submission.sampling,
+            &stop,
+            on_token,
+        )?;
+
+        info!(output_tokens=%generation.output_tokens, finish_reason=?generation.finish_reason, "Done");
+
+        Ok(ChatOutput {
+            usage: generation.usage(tokens.len(), prompt_eval_time),
+            message: super::ChatMessage {
+                role: ChatRole::Assistant,
+                content: generation.text.trim().to_string(),
+                name: None,
+            },
+            finish_reason: generation.finish_reason,
         })
     }
 }
 
 impl CompletionModel for GgmlChatModel {
+    fn context_size(&self) -> usize {
+        self.model.context_size()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.count_tokens(text)
+    }
+
     #[instrument(skip(self), fields(name = %self.name))]
     fn complete(
         &self,
         submission: crate::models::completion::CompletionSubmission,
-    ) -> Result<String, Report<ModelError>> {
+    ) -> Result<CompletionOutput, Report<ModelError>> {
         self.model.complete(submission)
     }
+
+    #[instrument(skip(self, on_token), fields(name = %self.name))]
+    fn complete_streaming(
+        &self,
+        submission: crate::models::completion::CompletionSubmission,
+        on_token: TokenCallback,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        self.model.complete_streaming(submission, on_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use super::tokenize_prompt;
+    use crate::models::chat::{prompt_template::PromptTemplate, ChatMessage, ChatRole};
+
+    const IM_START: u32 = 1000;
+    const IM_END: u32 = 1001;
+
+    #[test]
+    fn chatml_special_tokens() {
+        let messages = [ChatRole::System, ChatRole::User]
+            .into_iter()
+            .map(|role| ChatMessage {
+                role,
+                content: "Hi".to_string(),
+                name: None,
+            })
+            .collect::<Vec<_>>();
+        let prompt = PromptTemplate::ChatMl.format().render(&messages);
+
+        let special = [
+            ("<|im_start|>".to_string(), IM_START),
+            ("<|im_end|>".to_string(), IM_END),
+        ];
+        // Every character is its own token.
+        let tokens = tokenize_prompt(&prompt, &special, |text, _| {
+            Ok::<_, Infallible>(text.chars().map(|c| c as u32).collect())
+        })
+        .unwrap();
+
+        assert_eq!(tokens.iter().filter(|&&t| t == IM_START).count(), 3);
+        assert_eq!(tokens.iter().filter(|&&t| t == IM_END).count(), 2);
+        assert!(
+            !tokens.contains(&('|' as u32)),
+            "markers were tokenized as text"
+        );
+        assert_eq!(tokens[0], IM_START);
+    }
 }
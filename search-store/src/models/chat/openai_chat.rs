@@ -0,0 +1,227 @@
+use std::time::Instant;
+
+use error_stack::{IntoReport, Report};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::models::{
+    completion::{CompletionModel, CompletionOutput, CompletionSubmission},
+    openai::{self, ApiUsage, OpenAiClient, OpenAiConfig},
+    ModelError,
+};
+
+use super::{ChatMessage, ChatModel, ChatOutput, ChatRole, ChatSubmission};
+
+/// A chat model served by an OpenAI-compatible `/v1/chat/completions` endpoint.
+pub struct OpenAiChatModel {
+    name: String,
+    model: String,
+    client: OpenAiClient,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    #[serde(default)]
+    usage: ApiUsage,
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChoice {
+    message: ChatMessage,
+    finish_reason: Option<String>,
+}
+
+impl OpenAiChatModel {
+    pub fn new(name: String, model: String, config: OpenAiConfig) -> Self {
+        Self {
+            name,
+            model,
+            client: OpenAiClient::new(config),
+        }
+    }
+}
+
+impl ChatModel for OpenAiChatModel {
+    #[instrument(skip(self), fields(name = %self.name))]
+    fn chat(&self, submission: ChatSubmission) -> Result<ChatOutput, Report<ModelError>> {
+        let request = ChatRequest {
+            model: &self.model,
+            messages: &submission.messages,
+            temperature: submission.temperature,
+            top_p: submission.sampling.top_p,
+            max_tokens: submission.sampling.max_tokens,
+            seed: submission.sampling.seed,
+            stop: &submission.sampling.stop,
+        };
+
+        let start = Instant::now();
+        let response: ChatResponse = self.client.post("/v1/chat/completions", &request)?;
+
+        response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| ChatOutput {
+                message: choice.message,
+                finish_reason: openai::finish_reason(choice.finish_reason.as_deref()),
+                usage: response.usage.token_usage(start.elapsed()),
+            })
+            .ok_or(ModelError::ModelFailure)
+            .into_report()
+            .attach_printable("Response contained no choices")
+    }
+}
+
+impl CompletionModel for OpenAiChatModel {
+    fn context_size(&self) -> usize {
+        self.client.config().context_size
+    }
+
+    #[instrument(skip(self), fields(name = %self.name))]
+    fn complete(
+        &self,
+        submission: CompletionSubmission,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        let output = self.chat(ChatSubmission {
+            messages: vec![ChatMessage {
+                role: ChatRole::User,
+                content: submission.prompt,
+                name: None,
+            }],
+            temperature: submission.temperature,
+            sampling: submission.sampling,
+        })?;
+
+        Ok(CompletionOutput {
+            text: output.message.content,
+            finish_reason: output.finish_reason,
+            usage: output.usage,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use crate::models::{
+        chat::{ChatMessage, ChatModel, ChatRole, ChatSubmission},
+        completion::FinishReason,
+        openai::OpenAiConfig,
+    };
+
+    #[test]
+    fn chat() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/v1/chat/completions")
+            .match_header("authorization", "Bearer test-key")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "gpt-3.5-turbo",
+                "messages": [
+                    { "role": "system", "content": "Be brief" },
+                    { "role": "user", "content": "Hello" }
+                ],
+            })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "id": "chatcmpl-123",
+                    "object": "chat.completion",
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": "Hi there" },
+                        "finish_reason": "stop"
+                    }],
+                    "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+                })
+                .to_string(),
+            )
+            .create();
+
+        let model = super::OpenAiChatModel::new(
+            "test".to_string(),
+            "gpt-3.5-turbo".to_string(),
+            OpenAiConfig {
+                base_url: server.url(),
+                api_key: Some("test-key".to_string()),
+                context_size: 4096,
+            },
+        );
+
+        let response = model
+            .chat(ChatSubmission {
+                messages: vec![
+                    ChatMessage {
+                        role: ChatRole::System,
+                        content: "Be brief".to_string(),
+                        name: None,
+                    },
+                    ChatMessage {
+                        role: ChatRole::User,
+                        content: "Hello".to_string(),
+                        name: None,
+                    },
+                ],
+                temperature: None,
+                sampling: Default::default(),
+            })
+            .expect("running chat");
+
+        mock.assert();
+        assert_eq!(response.message.content, "Hi there");
+        assert_eq!(response.finish_reason, FinishReason::Stop);
+        assert_eq!(response.usage.prompt_tokens, 12);
+        assert_eq!(response.usage.completion_tokens, 3);
+    }
+
+    #[test]
+    fn server_error() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/v1/chat/completions")
+            .with_status(500)
+            .create();
+
+        let model = super::OpenAiChatModel::new(
+            "test".to_string(),
+            "gpt-3.5-turbo".to_string(),
+            OpenAiConfig {
+                base_url: server.url(),
+                api_key: None,
+                context_size: 4096,
+            },
+        );
+
+        let result = model.chat(ChatSubmission {
+            messages: vec![ChatMessage {
+                role: ChatRole::User,
+                content: "Hello".to_string(),
+                name: None,
+            }],
+            temperature: None,
+            sampling: Default::default(),
+        });
+
+        assert!(result.is_err(), "HTTP errors should be returned");
+    }
+}
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ChatMessage, ChatRole};
+
+/// The placeholder in a message template that is replaced by the message text.
+const CONTENT_PLACEHOLDER: &str = "{{content}}";
+
+/// How a chat conversation is formatted into a prompt for a model.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptTemplate {
+    /// `<|im_start|>role ... <|im_end|>`, used by MPT Chat and others
+    #[default]
+    ChatMl,
+    /// `### Instruction:` / `### Response:`
+    Alpaca,
+    /// `USER:` / `ASSISTANT:`
+    Vicuna,
+    /// `[INST] ... [/INST]`, with the system message inside the first instruction
+    Llama2,
+    /// `<human>:` / `<bot>:`
+    RedPajama,
+    Custom(PromptFormat),
+}
+
+/// The text that surrounds each message in a prompt. In each message template,
+/// `{{content}}` is replaced by the text of the message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptFormat {
+    /// Text at the start of the prompt
+    #[serde(default)]
+    pub prefix: String,
+    pub system: String,
+    pub user: String,
+    pub assistant: String,
+    /// Text that starts the assistant's response at the end of the prompt
+    pub response_prefix: String,
+    /// Add the system message to the start of the first user message instead of giving it
+    /// its own turn.
+    #[serde(default)]
+    pub merge_system: bool,
+    /// Sequences that indicate the model has finished its turn
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Markers in the templates that the model's vocabulary has as single special tokens, such
+    /// as `<|im_start|>`. These are added to the prompt directly instead of being tokenized.
+    #[serde(default)]
+    pub special_tokens: Vec<String>,
+}
+
+fn builtin(
+    system: &str,
+    user: &str,
+    assistant: &str,
+    response_prefix: &str,
+    stop: &[&str],
+    special_tokens: &[&str],
+) -> PromptFormat {
+    PromptFormat {
+        prefix: String::new(),
+        system: system.to_string(),
+        user: user.to_string(),
+        assistant: assistant.to_string(),
+        response_prefix: response_prefix.to_string(),
+        merge_system: false,
+        stop: stop.iter().map(|s| s.to_string()).collect(),
+        special_tokens: special_tokens.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+impl PromptTemplate {
+    pub fn format(&self) -> PromptFormat {
+        match self {
+            PromptTemplate::ChatMl => builtin(
+                "<|im_start|>system\n{{content}}<|im_end|>\n",
+                "<|im_start|>user\n{{content}}<|im_end|>\n",
+                "<|im_start|>assistant\n{{content}}<|im_end|>\n",
+                "<|im_start|>assistant\n",
+                &["<|im_end|>", "<|im_start|>"],
+                &["<|im_start|>", "<|im_end|>"],
+            ),
+            PromptTemplate::Alpaca => builtin(
+                "{{content}}\n\n",
+                "### Instruction:\n{{content}}\n\n",
+                "### Response:\n{{content}}\n\n",
+                "### Response:\n",
+                &["### Instruction:"],
+                &[],
+            ),
+            PromptTemplate::Vicuna => builtin(
+                "{{content}}\n\n",
+                "USER: {{content}}\n",
+                "ASSISTANT: {{content}}</s>\n",
+                "ASSISTANT:",
+                &["USER:", "</s>"],
+                &["</s>"],
+            ),
+            PromptTemplate::Llama2 => PromptFormat {
+                merge_system: true,
+                ..builtin(
+                    "<<SYS>>\n{{content}}\n<</SYS>>\n\n",
+                    "[INST] {{content}} [/INST]",
+                    " {{content}} </s><s>",
+                    "",
+                    &["[INST]", "</s>"],
+                    &["<s>", "</s>"],
+                )
+            },
+            PromptTemplate::RedPajama => builtin(
+                "{{content}}\n",
+                "<human>: {{content}}\n",
+                "<bot>: {{content}}\n",
+                "<bot>:",
+                &["<human>:"],
+                &[],
+            ),
+            PromptTemplate::Custom(format) => format.clone(),
+        }
+    }
+}
+
+impl PromptFormat {
+    /// Build the prompt for a conversation, ending with the start of the assistant's response.
+    pub fn render(&self, messages: &[ChatMessage]) -> String {
+        let mut prompt = self.prefix.clone();
+        let mut pending_system = String::new();
+
+        for message in messages {
+            match message.role {
+                ChatRole::System if self.merge_system => {
+                    pending_system
+                        .push_str(&self.system.replace(CONTENT_PLACEHOLDER, &message.content));
+                }
+                ChatRole::System => {
+                    prompt.push_str(&self.system.replace(CONTENT_PLACEHOLDER, &message.content));
+                }
+                ChatRole::User => {
+                    let content = if pending_system.is_empty() {
+                        message.content.clone()
+                    } else {
+                        std::mem::take(&mut pending_system) + &message.content
+                    };
+                    prompt.push_str(&self.user.replace(CONTENT_PLACEHOLDER, &content));
+                }
+                ChatRole::Assistant => {
+                    prompt.push_str(
+                        &self
+                            .assistant
+                            .replace(CONTENT_PLACEHOLDER, &message.content),
+                    );
+                }
+            }
+        }
+
+        // A system message with no user message after it still needs to be included.
+        prompt.push_str(&pending_system);
+        prompt.push_str(&self.response_prefix);
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PromptTemplate;
+    use crate::models::chat::{ChatMessage, ChatRole};
+
+    fn message(role: ChatRole, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+            name: None,
+        }
+    }
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            message(ChatRole::System, "Be brief."),
+            message(ChatRole::User, "Hi"),
+            message(ChatRole::Assistant, "Hello!"),
+            message(ChatRole::User, "How are you?"),
+        ]
+    }
+
+    #[test]
+    fn chatml() {
+        let prompt = PromptTemplate::ChatMl.format().render(&conversation());
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+            <|im_start|>user\nHi<|im_end|>\n\
+            <|im_start|>assistant\nHello!<|im_end|>\n\
+            <|im_start|>user\nHow are you?<|im_end|>\n\
+            <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn llama2_merges_system_message() {
+        let prompt = PromptTemplate::Llama2.format().render(&conversation());
+        assert_eq!(
+            prompt,
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] How are you? [/INST]"
+        );
+    }
+
+    #[test]
+    fn custom_template() {
+        let template: PromptTemplate = serde_json::from_value(serde_json::json!({
+            "custom": {
+                "system": "",
+                "user": "Q: {{content}}\n",
+                "assistant": "A: {{content}}\n",
+                "response_prefix": "A:",
+                "stop": ["Q:"]
+            }
+        }))
+        .expect("parsing template");
+
+        let format = template.format();
+        assert_eq!(
+            format.render(&conversation()),
+            "Q: Hi\nA: Hello!\nQ: How are you?\nA:"
+        );
+        assert_eq!(format.stop, vec!["Q:".to_string()]);
+    }
+}
@@ -1,16 +1,112 @@
+#[cfg(test)]
+pub(crate) mod fake;
 pub mod ggml_completion;
+pub mod openai_completion;
+
+use std::ops::ControlFlow;
 
 use error_stack::Report;
 use serde::{Deserialize, Serialize};
 
 use super::ModelError;
 
+/// A callback that receives each piece of text as a model generates it.
+/// Returning `ControlFlow::Break` stops the generation early.
+pub type TokenCallback<'a> = &'a mut dyn FnMut(&str) -> ControlFlow<()>;
+
+/// Parameters that control how tokens are sampled and when generation stops. Values that are
+/// not set use the model's defaults.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SamplingParams {
+    /// Only sample from the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Only sample from the most likely tokens whose cumulative probability is within `top_p`.
+    pub top_p: Option<f32>,
+    /// Penalize tokens that have already appeared. 1.0 applies no penalty.
+    pub repeat_penalty: Option<f32>,
+    /// How many of the most recent tokens to consider for the repeat penalty.
+    pub repeat_last_n: Option<usize>,
+    /// The maximum number of tokens to generate.
+    pub max_tokens: Option<usize>,
+    /// Seed the random number generator, for reproducible output.
+    pub seed: Option<u64>,
+    /// Stop generating when any of these strings is produced. The stop string itself is not
+    /// included in the output.
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct CompletionSubmission {
     pub prompt: String,
     pub temperature: Option<f32>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+/// Why a model stopped generating.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model generated a stop sequence.
+    Stop,
+    /// The generation reached the token limit or filled the context window.
+    Length,
+    /// The model generated its end-of-text token.
+    Eot,
+}
+
+/// Token counts and timings for a single generation.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TokenUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Time spent evaluating the prompt, in milliseconds. Remote APIs do not report this
+    /// separately, so it is included in `generation_ms` for those models.
+    pub prompt_eval_ms: Option<u64>,
+    /// Time spent generating the response, in milliseconds.
+    pub generation_ms: u64,
+}
+
+#[derive(Debug)]
+pub struct CompletionOutput {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub usage: TokenUsage,
+}
+
+/// Estimate the number of tokens in some text, for models that can not tokenize it locally.
+/// English text averages about four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.chars().count() + 3) / 4
 }
 
 pub trait CompletionModel: Send + Sync {
-    fn complete(&self, submission: CompletionSubmission) -> Result<String, Report<ModelError>>;
+    /// The maximum number of tokens that the model can handle, including both the prompt and
+    /// the generated output.
+    fn context_size(&self) -> usize;
+
+    /// Count the tokens in some text. Models that can not tokenize locally return an estimate.
+    fn count_tokens(&self, text: &str) -> usize {
+        estimate_tokens(text)
+    }
+
+    fn complete(
+        &self,
+        submission: CompletionSubmission,
+    ) -> Result<CompletionOutput, Report<ModelError>>;
+
+    /// Run a completion, passing the text to `on_token` as it is generated. The full
+    /// text is returned once the generation is done.
+    ///
+    /// Models that can not stream send the entire response as a single token.
+    fn complete_streaming(
+        &self,
+        submission: CompletionSubmission,
+        on_token: TokenCallback,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        let response = self.complete(submission)?;
+        let _ = on_token(&response.text);
+        Ok(response)
+    }
 }
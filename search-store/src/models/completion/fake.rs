@@ -0,0 +1,59 @@
+//! A completion model for tests, which counts each word as a token.
+
+use std::sync::Mutex;
+
+use error_stack::Report;
+
+use super::{CompletionModel, CompletionOutput, CompletionSubmission, FinishReason};
+use crate::models::ModelError;
+
+type Respond = Box<dyn Fn(&CompletionSubmission) -> String + Send + Sync>;
+
+/// Counts words as tokens, checks that each prompt leaves room for the output, and answers
+/// with whatever `respond` returns.
+pub struct FakeModel {
+    context_size: usize,
+    respond: Respond,
+    /// Every prompt that the model was given
+    pub prompts: Mutex<Vec<String>>,
+}
+
+impl FakeModel {
+    pub fn new(
+        context_size: usize,
+        respond: impl Fn(&CompletionSubmission) -> String + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            context_size,
+            respond: Box::new(respond),
+            prompts: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl CompletionModel for FakeModel {
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn complete(
+        &self,
+        submission: CompletionSubmission,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        let max_tokens = submission.sampling.max_tokens.unwrap();
+        assert!(self.count_tokens(&submission.prompt) + max_tokens <= self.context_size);
+
+        let text = (self.respond)(&submission);
+        self.prompts.lock().unwrap().push(submission.prompt);
+
+        Ok(CompletionOutput {
+            text,
+            finish_reason: FinishReason::Stop,
+            usage: Default::default(),
+        })
+    }
+}
@@ -1,15 +1,16 @@
 use std::{
+    ops::ControlFlow,
     path::{Path, PathBuf},
-    sync::Arc,
+    time::Instant,
 };
 
 use error_stack::{IntoReport, Report, ResultExt};
-use llm::{InferenceParameters, InferenceSessionConfig, OutputRequest};
+use llm::{InferenceSessionConfig, OutputRequest};
 use tracing::{info, instrument};
 
 use crate::models::{ggml, ModelError};
 
-use super::CompletionModel;
+use super::{estimate_tokens, CompletionModel, CompletionOutput, TokenCallback};
 
 pub struct GgmlCompletionModel {
     name: String,
@@ -29,20 +30,56 @@ impl GgmlCompletionModel {
 }
 
 impl CompletionModel for GgmlCompletionModel {
+    fn context_size(&self) -> usize {
+        self.model.context_size()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.count_tokens(text)
+    }
+
     #[instrument(skip(self), fields(name = %self.name))]
     fn complete(
         &self,
         submission: super::CompletionSubmission,
-    ) -> Result<String, Report<ModelError>> {
+    ) -> Result<CompletionOutput, Report<ModelError>> {
         self.model.complete(submission)
     }
+
+    #[instrument(skip(self, on_token), fields(name = %self.name))]
+    fn complete_streaming(
+        &self,
+        submission: super::CompletionSubmission,
+        on_token: TokenCallback,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        self.model.complete_streaming(submission, on_token)
+    }
 }
 
 impl CompletionModel for dyn llm::Model {
+    fn context_size(&self) -> usize {
+        self.n_context_tokens()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.vocabulary()
+            .tokenize(text, false)
+            .map(|tokens| tokens.len())
+            .unwrap_or_else(|_| estimate_tokens(text))
+    }
+
     fn complete(
         &self,
         submission: super::CompletionSubmission,
-    ) -> Result<String, Report<ModelError>> {
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        self.complete_streaming(submission, &mut |_| ControlFlow::Continue(()))
+    }
+
+    fn complete_streaming(
+        &self,
+        submission: super::CompletionSubmission,
+        on_token: TokenCallback,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
         let tokens = self
             .vocabulary()
             .tokenize(&submission.prompt, true)
@@ -55,29 +92,27 @@ impl CompletionModel for dyn llm::Model {
         let mut session = self.start_session(InferenceSessionConfig::default());
         let mut output = OutputRequest::default();
 
-        let temperature = submission.temperature.unwrap_or(0.3);
-        let params = InferenceParameters {
-            sampler: Arc::new(llm::samplers::TopPTopK {
-                temperature,
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
-
-        let mut output_tokens = Vec::new();
+        let params = ggml::inference_parameters(submission.temperature, &submission.sampling);
 
+        let start = Instant::now();
         self.evaluate(&mut session, &params, &tokens, &mut output);
+        let prompt_eval_time = start.elapsed();
         info!(input_tokens=%tokens.len(), "Evaluated input");
 
-        let mut num_output_tokens = 0;
-        while let Ok(token) =
-            session.infer_next_token(self, &params, &mut output, &mut rand::thread_rng())
-        {
-            output_tokens.extend(token);
-            num_output_tokens += 1;
-        }
-        info!(output_tokens=%num_output_tokens, "Done");
+        let generation = ggml::generate(
+            self,
+            &mut session,
+            &params,
+            &submission.sampling,
+            &submission.sampling.stop,
+            on_token,
+        )?;
+        info!(output_tokens=%generation.output_tokens, finish_reason=?generation.finish_reason, "Done");
 
-        Ok(String::from_utf8_lossy(&output_tokens).to_string())
+        Ok(CompletionOutput {
+            usage: generation.usage(tokens.len(), prompt_eval_time),
+            text: generation.text,
+            finish_reason: generation.finish_reason,
+        })
     }
 }
@@ -0,0 +1,197 @@
+use std::time::Instant;
+
+use error_stack::{IntoReport, Report};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::models::{
+    openai::{self, ApiUsage, OpenAiClient, OpenAiConfig},
+    ModelError,
+};
+
+use super::{CompletionModel, CompletionOutput, CompletionSubmission};
+
+/// The OpenAI API generates only 16 tokens if this is omitted, which is rarely what we want.
+const DEFAULT_MAX_TOKENS: usize = 256;
+
+/// A completion model served by an OpenAI-compatible `/v1/completions` endpoint.
+pub struct OpenAiCompletionModel {
+    name: String,
+    model: String,
+    client: OpenAiClient,
+}
+
+#[derive(Serialize, Debug)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    max_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    #[serde(default)]
+    usage: ApiUsage,
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    text: String,
+    finish_reason: Option<String>,
+}
+
+impl OpenAiCompletionModel {
+    pub fn new(name: String, model: String, config: OpenAiConfig) -> Self {
+        Self {
+            name,
+            model,
+            client: OpenAiClient::new(config),
+        }
+    }
+}
+
+impl CompletionModel for OpenAiCompletionModel {
+    fn context_size(&self) -> usize {
+        self.client.config().context_size
+    }
+
+    #[instrument(skip(self), fields(name = %self.name))]
+    fn complete(
+        &self,
+        submission: CompletionSubmission,
+    ) -> Result<CompletionOutput, Report<ModelError>> {
+        let request = CompletionRequest {
+            model: &self.model,
+            prompt: &submission.prompt,
+            max_tokens: submission.sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: submission.temperature,
+            top_p: submission.sampling.top_p,
+            seed: submission.sampling.seed,
+            stop: &submission.sampling.stop,
+        };
+
+        let start = Instant::now();
+        let response: CompletionResponse = self.client.post("/v1/completions", &request)?;
+
+        response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| CompletionOutput {
+                text: choice.text,
+                finish_reason: openai::finish_reason(choice.finish_reason.as_deref()),
+                usage: response.usage.token_usage(start.elapsed()),
+            })
+            .ok_or(ModelError::ModelFailure)
+            .into_report()
+            .attach_printable("Response contained no choices")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+    use serde_json::json;
+
+    use crate::models::{
+        completion::{CompletionModel, CompletionSubmission, FinishReason},
+        openai::OpenAiConfig,
+        ModelError,
+    };
+
+    fn create_model(base_url: String) -> super::OpenAiCompletionModel {
+        super::OpenAiCompletionModel::new(
+            "test".to_string(),
+            "text-davinci-003".to_string(),
+            OpenAiConfig {
+                base_url,
+                api_key: Some("test-key".to_string()),
+                context_size: 4096,
+            },
+        )
+    }
+
+    #[test]
+    fn complete() {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/v1/completions")
+            .match_header("authorization", "Bearer test-key")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "text-davinci-003",
+                "prompt": "Once upon a time",
+                "temperature": 0.5,
+            })))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "id": "cmpl-123",
+                    "object": "text_completion",
+                    "choices": [{
+                        "index": 0,
+                        "text": " there was a model",
+                        "finish_reason": "length"
+                    }]
+                })
+                .to_string(),
+            )
+            .create();
+
+        let model = create_model(server.url());
+        let response = model
+            .complete(CompletionSubmission {
+                prompt: "Once upon a time".to_string(),
+                temperature: Some(0.5),
+                sampling: Default::default(),
+            })
+            .expect("running completion");
+
+        mock.assert();
+        assert_eq!(response.text, " there was a model");
+        assert_eq!(response.finish_reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn error_classes() {
+        let mut server = mockito::Server::new();
+        let model = create_model(server.url());
+
+        for (status, expected) in [
+            (400, ModelError::ParameterError),
+            (401, ModelError::AuthenticationError),
+            (429, ModelError::RateLimited),
+            (503, ModelError::ModelFailure),
+        ] {
+            let mock = server
+                .mock("POST", "/v1/completions")
+                .with_status(status)
+                .create();
+
+            let err = model
+                .complete(CompletionSubmission {
+                    prompt: "test".to_string(),
+                    temperature: None,
+                    sampling: Default::default(),
+                })
+                .expect_err("request should fail");
+
+            assert_eq!(
+                std::mem::discriminant(err.current_context()),
+                std::mem::discriminant(&expected),
+                "status {status} should map to {expected:?}"
+            );
+
+            mock.remove();
+        }
+    }
+}
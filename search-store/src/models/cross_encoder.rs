@@ -0,0 +1,327 @@
+use std::path::Path;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use rust_bert::{
+    pipelines::{
+        common::{ConfigOption, ModelType, TokenizerOption},
+        sequence_classification::SequenceClassificationOption,
+    },
+    RustBertError,
+};
+use rust_tokenizers::{tokenizer::TruncationStrategy, TokenizedInput};
+use serde::Deserialize;
+use tch::{nn, Kind, Tensor};
+
+use super::{error::ModelError, rust_bert_sentence_embeddings::get_model_type, ModelParams};
+
+/// The longest input that the cross-encoder models we use can accept.
+const DEFAULT_MAX_LENGTH: usize = 512;
+
+/// A model that scores how relevant a passage is to a query, by running them through
+/// a sequence classification model together.
+pub struct CrossEncoderModel {
+    pub name: String,
+    tokenizer: TokenizerOption,
+    max_length: usize,
+
+    worker_thread: std::thread::JoinHandle<()>,
+    worker_tx: flume::Sender<WorkerMessage>,
+}
+
+/// A passage index and its score, as returned from [CrossEncoderModel::rank].
+#[derive(Debug, Clone, Copy)]
+pub struct RankedPassage {
+    pub index: usize,
+    pub score: f32,
+}
+
+struct CrossEncoder {
+    var_store: nn::VarStore,
+    classifier: SequenceClassificationOption,
+}
+
+struct CrossEncoderInput {
+    input_ids: Tensor,
+    attention_mask: Tensor,
+    token_type_ids: Tensor,
+}
+
+enum WorkerMessage {
+    Score(WorkerScoreMessage),
+    Close,
+}
+
+struct WorkerScoreMessage {
+    input: CrossEncoderInput,
+    result: oneshot::Sender<Result<Vec<f32>, Report<RustBertError>>>,
+}
+
+/// The parts of tokenizer_config.json that we need to set up the tokenizer.
+#[derive(Deserialize, Default)]
+struct TokenizerConfig {
+    do_lower_case: Option<bool>,
+    strip_accents: Option<bool>,
+    add_prefix_space: Option<bool>,
+    model_max_length: Option<usize>,
+}
+
+impl CrossEncoderModel {
+    pub fn new(
+        name: String,
+        _params: &ModelParams,
+        model_dir: Option<&Path>,
+    ) -> Result<Self, Report<ModelError>> {
+        let Some(model_dir) = model_dir else {
+            return Err(ModelError::LoadingError)
+                .into_report()
+                .attach_printable("Model directory not provided");
+        };
+
+        let model_type = get_model_type(model_dir)?;
+        let tokenizer_config = read_tokenizer_config(model_dir);
+        let tokenizer = create_tokenizer(model_type, model_dir, &tokenizer_config)
+            .into_report()
+            .change_context(ModelError::LoadingError)?;
+        let model = create_model(model_type, model_dir)
+            .into_report()
+            .change_context(ModelError::LoadingError)
+            .attach_printable_lazy(|| model_dir.display().to_string())?;
+
+        let max_length = tokenizer_config
+            .model_max_length
+            .unwrap_or(DEFAULT_MAX_LENGTH)
+            .min(DEFAULT_MAX_LENGTH);
+
+        let (tx, rx) = flume::bounded(10);
+        let worker_thread = std::thread::spawn(|| worker_thread(rx, model));
+
+        Ok(Self {
+            name,
+            tokenizer,
+            max_length,
+            worker_tx: tx,
+            worker_thread,
+        })
+    }
+
+    /// Score each (query, passage) pair. Higher scores indicate a more relevant passage.
+    pub fn score<S: AsRef<str> + Sync>(
+        &self,
+        pairs: &[(S, S)],
+    ) -> Result<Vec<f32>, Report<ModelError>> {
+        if pairs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let tokenized = self.tokenizer.encode_pair_list(
+            pairs,
+            self.max_length,
+            &TruncationStrategy::LongestFirst,
+            0,
+        );
+        let input = self.generate_token_tensors(&tokenized);
+
+        let (tx, rx) = oneshot::channel();
+        let msg = WorkerScoreMessage { input, result: tx };
+
+        self.worker_tx
+            .send(WorkerMessage::Score(msg))
+            .map_err(|_| ModelError::WorkerClosed)
+            .into_report()?;
+
+        let result = rx
+            .recv()
+            .into_report()
+            .change_context(ModelError::WorkerClosed)?;
+
+        result.change_context(ModelError::ModelFailure)
+    }
+
+    /// Score each passage against the query, and return them sorted from most to least relevant.
+    pub fn rank<S: AsRef<str> + Sync>(
+        &self,
+        query: &str,
+        passages: &[S],
+    ) -> Result<Vec<RankedPassage>, Report<ModelError>> {
+        let pairs = passages
+            .iter()
+            .map(|passage| (query, passage.as_ref()))
+            .collect::<Vec<_>>();
+
+        let mut ranked = self
+            .score(&pairs)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, score)| RankedPassage { index, score })
+            .collect::<Vec<_>>();
+
+        ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        Ok(ranked)
+    }
+
+    fn generate_token_tensors(&self, tokenized: &[TokenizedInput]) -> CrossEncoderInput {
+        let max_len = tokenized
+            .iter()
+            .map(|input| input.token_ids.len())
+            .max()
+            .unwrap_or(0);
+
+        let pad_token_id = self.tokenizer.get_pad_id().unwrap_or(0);
+
+        let mut input_ids = Vec::with_capacity(tokenized.len());
+        let mut attention_mask = Vec::with_capacity(tokenized.len());
+        let mut token_type_ids = Vec::with_capacity(tokenized.len());
+
+        for input in tokenized {
+            let padding = max_len - input.token_ids.len();
+
+            let mut ids = input.token_ids.clone();
+            ids.extend(std::iter::repeat(pad_token_id).take(padding));
+            input_ids.push(Tensor::from_slice(&ids));
+
+            let mut mask = vec![1i64; input.token_ids.len()];
+            mask.extend(std::iter::repeat(0).take(padding));
+            attention_mask.push(Tensor::from_slice(&mask));
+
+            let mut segments = input
+                .segment_ids
+                .iter()
+                .map(|&s| i64::from(s))
+                .collect::<Vec<_>>();
+            segments.resize(max_len, 0);
+            token_type_ids.push(Tensor::from_slice(&segments));
+        }
+
+        CrossEncoderInput {
+            input_ids: Tensor::stack(&input_ids, 0),
+            attention_mask: Tensor::stack(&attention_mask, 0),
+            token_type_ids: Tensor::stack(&token_type_ids, 0),
+        }
+    }
+}
+
+impl Drop for CrossEncoderModel {
+    fn drop(&mut self) {
+        self.worker_tx.send(WorkerMessage::Close).ok();
+    }
+}
+
+impl CrossEncoder {
+    fn score(&self, input: CrossEncoderInput) -> Result<Vec<f32>, RustBertError> {
+        let device = self.var_store.device();
+        let logits = tch::no_grad(|| {
+            self.classifier.forward_t(
+                Some(&input.input_ids.to(device)),
+                Some(&input.attention_mask.to(device)),
+                Some(&input.token_type_ids.to(device)),
+                None,
+                None,
+                false,
+            )
+        });
+
+        // Single-label models output a relevance logit. Multi-label models put the
+        // "relevant" class last.
+        let scores = if logits.size()[1] == 1 {
+            logits.squeeze_dim(1).sigmoid()
+        } else {
+            logits.softmax(-1, Kind::Float).select(1, -1)
+        };
+
+        Vec::try_from(scores.to_kind(Kind::Float).to_device(tch::Device::Cpu))
+            .map_err(RustBertError::from)
+    }
+}
+
+fn read_tokenizer_config(model_dir: &Path) -> TokenizerConfig {
+    std::fs::File::open(model_dir.join("tokenizer_config.json"))
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+fn create_tokenizer(
+    model_type: ModelType,
+    model_dir: &Path,
+    config: &TokenizerConfig,
+) -> Result<TokenizerOption, RustBertError> {
+    let merges_path = model_dir.join("merges.txt");
+    let merges_path = merges_path
+        .exists()
+        .then(|| merges_path.to_string_lossy().into_owned());
+
+    TokenizerOption::from_file(
+        model_type,
+        model_dir.join("vocab.txt").to_string_lossy().as_ref(),
+        merges_path.as_deref(),
+        config.do_lower_case.unwrap_or(true),
+        config.strip_accents,
+        config.add_prefix_space,
+    )
+}
+
+fn create_model(model_type: ModelType, model_dir: &Path) -> Result<CrossEncoder, RustBertError> {
+    let mut var_store = nn::VarStore::new(tch::Device::cuda_if_available());
+    let config = ConfigOption::from_file(model_type, model_dir.join("config.json"));
+    let classifier = SequenceClassificationOption::new(model_type, var_store.root(), &config)?;
+    var_store
+        .load(model_dir.join("rust_model.ot"))
+        .map_err(|e| RustBertError::TchError(e.to_string()))?;
+
+    #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+    var_store.set_device(tch::Device::Mps);
+
+    Ok(CrossEncoder {
+        var_store,
+        classifier,
+    })
+}
+
+fn worker_thread(worker_rx: flume::Receiver<WorkerMessage>, model: CrossEncoder) {
+    for msg in worker_rx {
+        match msg {
+            WorkerMessage::Score(msg) => {
+                let scores = model.score(msg.input).into_report();
+                msg.result.send(scores).ok();
+            }
+            WorkerMessage::Close => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::{download::ModelCache, ModelLocation, ModelParams};
+
+    #[test]
+    fn test_model() {
+        dotenvy::dotenv().ok();
+        let cache = ModelCache::from_env().expect("Creating model cache");
+
+        let params = ModelParams::RustBert(ModelLocation {
+            location: "huggingface:cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+        });
+
+        let model_path = cache
+            .download_if_needed(&params)
+            .expect("Downloading model");
+        let model =
+            super::CrossEncoderModel::new("test".to_string(), &params, model_path.as_deref())
+                .expect("loading model");
+
+        let ranked = model
+            .rank(
+                "How many people live in Berlin?",
+                &[
+                    "New York City is famous for the Metropolitan Museum of Art.",
+                    "Berlin has a population of 3,520,031 registered inhabitants.",
+                ],
+            )
+            .expect("ranking");
+
+        assert_eq!(ranked.len(), 2, "one score per passage");
+        assert_eq!(ranked[0].index, 1, "relevant passage should rank first");
+        assert!(ranked[0].score > ranked[1].score, "sorted by score");
+    }
+}
@@ -12,4 +12,10 @@ pub enum ModelError {
     LoadingError,
     #[error("Unsupported model type {0}")]
     UnknownModelType(String),
+    #[error("Failed to reach model API")]
+    RequestError,
+    #[error("Model API rejected the credentials")]
+    AuthenticationError,
+    #[error("Model API rate limit exceeded")]
+    RateLimited,
 }
@@ -1,7 +1,21 @@
 use error_stack::{IntoReport, Report, ResultExt};
-use std::path::{Path, PathBuf};
+use llm::{InferenceError, InferenceParameters, InferenceSession, OutputRequest, TokenUtf8Buffer};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use super::ModelError;
+use super::{
+    completion::{FinishReason, SamplingParams, TokenCallback, TokenUsage},
+    stop_sequences::{StopCheck, StopSequenceMatcher},
+    ModelError,
+};
+
+const DEFAULT_TEMPERATURE: f32 = 0.3;
+/// The most tokens that a single request can generate, regardless of what it asks for.
+pub const MAX_OUTPUT_TOKENS: usize = 2048;
 
 pub fn load_ggml_model(
     model_name: &str,
@@ -40,3 +54,128 @@ pub fn load_ggml_model(
     .attach_printable(weights_path.display().to_string())
     .change_context(ModelError::LoadingError)
 }
+
+/// Build the inference parameters for a request, using the sampler defaults for anything not provided.
+pub fn inference_parameters(
+    temperature: Option<f32>,
+    sampling: &SamplingParams,
+) -> InferenceParameters {
+    let defaults = llm::samplers::TopPTopK::default();
+    InferenceParameters {
+        sampler: Arc::new(llm::samplers::TopPTopK {
+            temperature: temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            top_k: sampling.top_k.unwrap_or(defaults.top_k),
+            top_p: sampling.top_p.unwrap_or(defaults.top_p),
+            repeat_penalty: sampling.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repetition_penalty_last_n: sampling
+                .repeat_last_n
+                .unwrap_or(defaults.repetition_penalty_last_n),
+            ..defaults
+        }),
+        ..Default::default()
+    }
+}
+
+/// Create the random number generator used for sampling. A seed makes the output reproducible.
+pub fn sampling_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+pub struct Generation {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub output_tokens: usize,
+    pub generation_time: Duration,
+}
+
+impl Generation {
+    pub fn usage(&self, prompt_tokens: usize, prompt_eval_time: Duration) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens: self.output_tokens,
+            prompt_eval_ms: Some(prompt_eval_time.as_millis() as u64),
+            generation_ms: self.generation_time.as_millis() as u64,
+        }
+    }
+}
+
+/// Generate text from a session that has already evaluated the prompt. This runs until the model
+/// emits its end-of-text token, a stop sequence is generated, or the token limit is reached.
+pub fn generate(
+    model: &dyn llm::Model,
+    session: &mut InferenceSession,
+    params: &InferenceParameters,
+    sampling: &SamplingParams,
+    stop: &[String],
+    on_token: TokenCallback,
+) -> Result<Generation, Report<ModelError>> {
+    let start = Instant::now();
+    let mut rng = sampling_rng(sampling.seed);
+    let max_tokens = sampling
+        .max_tokens
+        .unwrap_or(MAX_OUTPUT_TOKENS)
+        .min(MAX_OUTPUT_TOKENS);
+
+    let mut output = OutputRequest::default();
+    let mut token_buffer = TokenUtf8Buffer::new();
+    let mut stop_matcher = StopSequenceMatcher::new(stop);
+    let mut text = String::new();
+    let mut output_tokens = 0;
+
+    let finish_reason = loop {
+        if output_tokens >= max_tokens {
+            break FinishReason::Length;
+        }
+
+        let token = match session.infer_next_token(model, params, &mut output, &mut rng) {
+            Ok(token) => token,
+            Err(InferenceError::EndOfText) => break FinishReason::Eot,
+            Err(InferenceError::ContextFull) => break FinishReason::Length,
+            Err(e) => {
+                return Err(e)
+                    .into_report()
+                    .change_context(ModelError::ModelFailure)
+            }
+        };
+
+        output_tokens += 1;
+
+        let Some(token_text) = token_buffer.push(token) else {
+            continue;
+        };
+
+        let (emit, stopped) = match stop_matcher.push(&token_text) {
+            StopCheck::Continue(emit) => (emit, false),
+            StopCheck::Stop(emit) => (emit, true),
+        };
+
+        if !emit.is_empty() {
+            text.push_str(&emit);
+            if on_token(&emit).is_break() {
+                break FinishReason::Stop;
+            }
+        }
+
+        if stopped {
+            break FinishReason::Stop;
+        }
+    };
+
+    if finish_reason != FinishReason::Stop {
+        let remaining = stop_matcher.finish();
+        if !remaining.is_empty() {
+            text.push_str(&remaining);
+            let _ = on_token(&remaining);
+        }
+    }
+
+    Ok(Generation {
+        text,
+        finish_reason,
+        output_tokens,
+        generation_time: start.elapsed(),
+    })
+}
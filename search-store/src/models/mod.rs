@@ -2,15 +2,19 @@ use serde::{Deserialize, Serialize};
 
 use sqlx_transparent_json_decode::sqlx_json_decode;
 
+use self::chat::prompt_template::PromptTemplate;
 pub use self::error::ModelError;
 
 pub mod bi_encoder;
 pub mod chat;
 pub mod completion;
+pub mod cross_encoder;
 pub mod download;
 pub mod error;
 mod ggml;
+pub mod openai;
 mod rust_bert_sentence_embeddings;
+mod stop_sequences;
 pub mod transformers;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,8 +39,8 @@ pub enum ModelCategory {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "code", rename_all = "kebab-case")]
 pub enum ModelParams {
-    OpenaiChat,
-    OpenaiCompletions,
+    OpenaiChat(OpenaiModelParams),
+    OpenaiCompletions(OpenaiModelParams),
     Ggml(GgmlModelParams),
     RustBert(ModelLocation),
 }
@@ -51,8 +55,8 @@ pub struct LocationAndPattern {
 impl ModelParams {
     pub fn location(&self) -> Option<&str> {
         match self {
-            ModelParams::OpenaiChat => None,
-            ModelParams::OpenaiCompletions => None,
+            ModelParams::OpenaiChat(_) => None,
+            ModelParams::OpenaiCompletions(_) => None,
             ModelParams::Ggml(GgmlModelParams { location, .. }) => Some(location),
             ModelParams::RustBert(ModelLocation { location }) => Some(location),
         }
@@ -60,8 +64,8 @@ impl ModelParams {
 
     pub fn additional_files(&self) -> Vec<LocationAndPattern> {
         match self {
-            ModelParams::OpenaiChat => Vec::new(),
-            ModelParams::OpenaiCompletions => Vec::new(),
+            ModelParams::OpenaiChat(_) => Vec::new(),
+            ModelParams::OpenaiCompletions(_) => Vec::new(),
             ModelParams::Ggml(GgmlModelParams { tokenizer, .. }) => match tokenizer {
                 Some(tokenizer) => vec![LocationAndPattern {
                     location: tokenizer.clone(),
@@ -79,11 +83,26 @@ pub struct ModelLocation {
     pub location: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct OpenaiModelParams {
+    /// The model name to send to the API. Defaults to the name of the model definition.
+    pub model: Option<String>,
+    /// The URL of an OpenAI-compatible server. Defaults to the `OPENAI_BASE_URL` environment
+    /// variable, or the OpenAI API if that is not set.
+    pub base_url: Option<String>,
+    /// The environment variable that holds the API key. Defaults to `OPENAI_API_KEY`.
+    pub api_key_var: Option<String>,
+    /// The number of tokens that the model can handle. Defaults to 4096.
+    pub context_size: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GgmlModelParams {
     pub model: String,
     pub location: String,
     pub tokenizer: Option<String>,
+    /// How chat conversations are formatted into a prompt. Defaults to ChatML.
+    #[serde(default)]
+    pub prompt_template: PromptTemplate,
 }
-
-pub struct CrossEncoderModel {}
@@ -0,0 +1,142 @@
+use error_stack::{IntoReport, Report, ResultExt};
+use reqwest::{blocking::Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::Duration;
+
+use super::{
+    completion::{FinishReason, TokenUsage},
+    ModelError, OpenaiModelParams,
+};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+/// The context size to assume when a model's parameters do not specify one.
+pub const DEFAULT_CONTEXT_SIZE: usize = 4096;
+
+/// Connection information for an OpenAI-compatible API.
+#[derive(Debug, Clone)]
+pub struct OpenAiConfig {
+    /// The URL of the server, not including the `/v1` path.
+    pub base_url: String,
+    pub api_key: Option<String>,
+    /// The number of tokens that the model can handle.
+    pub context_size: usize,
+}
+
+impl OpenAiConfig {
+    /// Read the configuration from the `OPENAI_BASE_URL` and `OPENAI_API_KEY` environment variables.
+    pub fn from_env() -> Self {
+        Self {
+            base_url: std::env::var("OPENAI_BASE_URL")
+                .unwrap_or_else(|_| DEFAULT_BASE_URL.to_string()),
+            api_key: std::env::var("OPENAI_API_KEY").ok(),
+            context_size: DEFAULT_CONTEXT_SIZE,
+        }
+    }
+
+    /// Build the configuration for a model, falling back to the environment for anything
+    /// not set in the model's parameters.
+    pub fn for_model(params: &OpenaiModelParams) -> Self {
+        let env_config = Self::from_env();
+        Self {
+            base_url: params.base_url.clone().unwrap_or(env_config.base_url),
+            api_key: match params.api_key_var.as_ref() {
+                Some(var) => std::env::var(var).ok(),
+                None => env_config.api_key,
+            },
+            context_size: params.context_size.unwrap_or(env_config.context_size),
+        }
+    }
+}
+
+/// Convert the `finish_reason` from an API response. The API does not distinguish between
+/// a stop sequence and the end of the text, so both are reported as `Stop`.
+pub fn finish_reason(reason: Option<&str>) -> FinishReason {
+    match reason {
+        Some("length") => FinishReason::Length,
+        _ => FinishReason::Stop,
+    }
+}
+
+/// The token counts returned by the API.
+#[derive(Deserialize, Debug, Default)]
+pub struct ApiUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+impl ApiUsage {
+    pub fn token_usage(&self, request_time: Duration) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            prompt_eval_ms: None,
+            generation_ms: request_time.as_millis() as u64,
+        }
+    }
+}
+
+/// Map an HTTP error status to the closest matching `ModelError`.
+fn error_for_status(status: StatusCode) -> ModelError {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ModelError::AuthenticationError,
+        StatusCode::TOO_MANY_REQUESTS => ModelError::RateLimited,
+        s if s.is_client_error() => ModelError::ParameterError,
+        _ => ModelError::ModelFailure,
+    }
+}
+
+pub struct OpenAiClient {
+    config: OpenAiConfig,
+    client: Client,
+}
+
+impl OpenAiClient {
+    pub fn new(config: OpenAiConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+
+    pub fn config(&self) -> &OpenAiConfig {
+        &self.config
+    }
+
+    /// Send a JSON request to an API endpoint and decode the response.
+    pub fn post<T: Serialize, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<R, Report<ModelError>> {
+        let url = format!(
+            "{}/{}",
+            self.config.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+
+        let mut request = self.client.post(&url).json(body);
+        if let Some(api_key) = self.config.api_key.as_ref() {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request
+            .send()
+            .into_report()
+            .change_context(ModelError::RequestError)
+            .attach_printable_lazy(|| url.clone())?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().unwrap_or_default();
+            return Err(error_for_status(status))
+                .into_report()
+                .attach_printable(format!("{url} returned {status}: {body}"));
+        }
+
+        response
+            .json::<R>()
+            .into_report()
+            .change_context(ModelError::ModelFailure)
+            .attach_printable_lazy(|| url.clone())
+    }
+}
@@ -90,6 +90,16 @@ impl SentenceEmbeddingsTokenizer {
         }
     }
 
+    /// The number of tokens in the text, not counting special tokens.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.tokenizer.tokenize(text).len()
+    }
+
+    /// The maximum number of tokens in an input, including special tokens.
+    pub fn max_seq_length(&self) -> usize {
+        self.sentence_bert_config.max_seq_length
+    }
+
     /// Tokenizes the inputs
     pub fn tokenize<S>(&self, inputs: &[S]) -> SentenceEmbeddingsTokenizerOutput
     where
@@ -272,7 +282,7 @@ fn local_resource(base_path: &Path, file: &str) -> Box<dyn ResourceProvider + Se
     Box::new(LocalResource::from(base_path.join(file)))
 }
 
-fn get_model_type(base_path: &Path) -> Result<ModelType, ModelError> {
+pub(crate) fn get_model_type(base_path: &Path) -> Result<ModelType, ModelError> {
     let model_type = read_model_config(base_path)
         .into_report()
         .change_context(ModelError::LoadingError)?;
@@ -0,0 +1,115 @@
+/// The result of adding text to a [StopSequenceMatcher].
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopCheck {
+    /// No stop sequence was found. This contains the text that can be safely emitted, which
+    /// may be empty if the newest text could be the start of a stop sequence.
+    Continue(String),
+    /// A stop sequence was found. This contains any text that came before it.
+    Stop(String),
+}
+
+/// Watches generated text for stop sequences, even when they span multiple tokens.
+///
+/// Text that might be the start of a stop sequence is held back until it either completes the
+/// stop sequence or diverges from it, so that a stop sequence is never partially emitted.
+pub struct StopSequenceMatcher<'a> {
+    stop: &'a [String],
+    pending: String,
+}
+
+impl<'a> StopSequenceMatcher<'a> {
+    pub fn new(stop: &'a [String]) -> Self {
+        Self {
+            stop,
+            pending: String::new(),
+        }
+    }
+
+    /// Add newly generated text.
+    pub fn push(&mut self, text: &str) -> StopCheck {
+        self.pending.push_str(text);
+
+        let stop_idx = self
+            .stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.pending.find(s.as_str()))
+            .min();
+
+        if let Some(idx) = stop_idx {
+            self.pending.truncate(idx);
+            return StopCheck::Stop(std::mem::take(&mut self.pending));
+        }
+
+        let emit_len = self.pending.len() - self.partial_match_len();
+        StopCheck::Continue(self.pending.drain(..emit_len).collect())
+    }
+
+    /// Return any text that was held back, once generation has finished without a stop sequence.
+    pub fn finish(self) -> String {
+        self.pending
+    }
+
+    /// The length of the longest suffix of the pending text that is the start of a stop sequence.
+    fn partial_match_len(&self) -> usize {
+        self.pending
+            .char_indices()
+            .map(|(i, _)| &self.pending[i..])
+            .find(|suffix| self.stop.iter().any(|s| s.starts_with(suffix)))
+            .map(|suffix| suffix.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StopCheck, StopSequenceMatcher};
+
+    #[test]
+    fn no_stop_sequences() {
+        let stop = Vec::new();
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("hello"), StopCheck::Continue("hello".to_string()));
+        assert_eq!(matcher.finish(), "");
+    }
+
+    #[test]
+    fn stop_in_one_token() {
+        let stop = vec!["\n\n".to_string()];
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("one"), StopCheck::Continue("one".to_string()));
+        assert_eq!(matcher.push(" two\n\nthree"), StopCheck::Stop(" two".to_string()));
+    }
+
+    #[test]
+    fn stop_across_tokens() {
+        let stop = vec!["<|im_end|>".to_string()];
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("Hi<|im"), StopCheck::Continue("Hi".to_string()));
+        assert_eq!(matcher.push("_en"), StopCheck::Continue(String::new()));
+        assert_eq!(matcher.push("d|>more"), StopCheck::Stop(String::new()));
+    }
+
+    #[test]
+    fn partial_match_diverges() {
+        let stop = vec!["User:".to_string()];
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("a Us"), StopCheck::Continue("a ".to_string()));
+        assert_eq!(matcher.push("ually"), StopCheck::Continue("Usually".to_string()));
+    }
+
+    #[test]
+    fn held_back_text_is_returned_at_finish() {
+        let stop = vec!["###".to_string()];
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("done #"), StopCheck::Continue("done ".to_string()));
+        assert_eq!(matcher.finish(), "#");
+    }
+
+    #[test]
+    fn earliest_stop_sequence_wins() {
+        let stop = vec!["b".to_string(), "a".to_string()];
+        let mut matcher = StopSequenceMatcher::new(&stop);
+        assert_eq!(matcher.push("xaby"), StopCheck::Stop("x".to_string()));
+    }
+}
@@ -0,0 +1,519 @@
+//! Hybrid search over items, combining BM25 and vector retrieval with reciprocal rank fusion,
+//! and optionally reranking the results with a cross-encoder.
+
+use std::{collections::HashMap, sync::Arc};
+
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    db::{
+        self,
+        item_chunks::{embedding_from_bytes, ItemChunkEmbedding},
+        items::{ItemStatus, ItemText},
+    },
+    filter::SearchFilter,
+    models::{bi_encoder::BiEncoderModel, cross_encoder::CrossEncoderModel},
+    text_index::{TextIndexError, TextMatch},
+    vector_index::VectorIndex,
+    SearchStore,
+};
+
+/// Items can have many matching chunks, so fetch extra chunks from the vector index to end up
+/// with enough distinct items.
+const CHUNKS_PER_ITEM: usize = 3;
+/// The most candidates to take from each retriever. Larger requests are clamped to this.
+const MAX_CANDIDATES: usize = 1000;
+
+#[derive(Debug, Error)]
+pub enum SearchError {
+    #[error("Database error")]
+    Db,
+    #[error("Invalid query")]
+    InvalidQuery,
+    #[error("Text search failed")]
+    TextSearch,
+    #[error("Model failed")]
+    Model,
+    #[error("Model {0} is not a loaded {1} model")]
+    ModelNotLoaded(i32, &'static str),
+    #[error("No vector index is loaded for model {0}")]
+    NoVectorIndex(i32),
+    #[error("Background task failed")]
+    Task,
+}
+
+/// Weights for reciprocal rank fusion. Each result list contributes `weight / (k + rank)` to
+/// the score of each item in it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FusionWeights {
+    pub text: f32,
+    pub vector: f32,
+    /// Larger values reduce the difference between the top ranks and the rest.
+    pub k: f32,
+}
+
+impl Default for FusionWeights {
+    fn default() -> Self {
+        Self {
+            text: 1.0,
+            vector: 1.0,
+            k: 60.0,
+        }
+    }
+}
+
+fn default_limit() -> usize {
+    10
+}
+
+fn default_candidates() -> usize {
+    50
+}
+
+fn default_rerank_top_n() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    pub query: String,
+    /// Limit the search to a subset of items. By default, all items that are not hidden are
+    /// searched.
+    #[serde(default)]
+    pub filter: SearchFilter,
+    /// The number of results to return
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// The number of results to take from each retriever before fusing them, from 1 to 1000
+    #[serde(default = "default_candidates")]
+    pub candidates: usize,
+    #[serde(default)]
+    pub weights: FusionWeights,
+    /// The bi-encoder to use for vector search. This defaults to the first loaded bi-encoder,
+    /// and only BM25 is used if there are none.
+    pub bi_encoder_id: Option<i32>,
+    /// Rerank the top results with this cross-encoder
+    pub cross_encoder_id: Option<i32>,
+    /// How many of the fused results to rerank
+    #[serde(default = "default_rerank_top_n")]
+    pub rerank_top_n: usize,
+}
+
+impl SearchQuery {
+    pub fn new(query: String) -> Self {
+        Self {
+            query,
+            filter: SearchFilter::default(),
+            limit: default_limit(),
+            candidates: default_candidates(),
+            weights: FusionWeights::default(),
+            bi_encoder_id: None,
+            cross_encoder_id: None,
+            rerank_top_n: default_rerank_top_n(),
+        }
+    }
+}
+
+/// The part of an item that best matches the query.
+#[derive(Debug, Serialize)]
+pub struct SearchChunk {
+    pub sequence_num: i32,
+    /// The index of the first character of the chunk in the item's processed content
+    pub start_idx: i32,
+    /// The index of the last character of the chunk
+    pub end_idx: i32,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub item_id: i64,
+    pub title: Option<String>,
+    /// The rerank score if the result was reranked, and the fused score otherwise
+    pub score: f32,
+    pub fused_score: f32,
+    pub text_score: Option<f32>,
+    pub vector_score: Option<f32>,
+    pub rerank_score: Option<f32>,
+    /// The best matching chunk, when vector search was used
+    pub chunk: Option<SearchChunk>,
+    /// An HTML snippet with the matching terms highlighted, when the item matched in BM25
+    pub snippet: Option<String>,
+}
+
+/// Combine ranked lists of item IDs using weighted reciprocal rank fusion. Returns each item
+/// with its fused score, best first.
+pub fn reciprocal_rank_fusion(lists: &[(&[i64], f32)], k: f32) -> Vec<(i64, f32)> {
+    let mut positions = HashMap::new();
+    let mut fused: Vec<(i64, f32)> = Vec::new();
+
+    for (list, weight) in lists {
+        for (rank, item_id) in list.iter().enumerate() {
+            let position = *positions.entry(*item_id).or_insert_with(|| {
+                fused.push((*item_id, 0.0));
+                fused.len() - 1
+            });
+            fused[position].1 += weight / (k + rank as f32 + 1.0);
+        }
+    }
+
+    // The sort is stable, so ties keep the order in which the items were first seen.
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Get the characters from `start` to `end`, inclusive.
+pub(crate) fn char_span(text: &str, start: i32, end: i32) -> String {
+    let len = (end - start + 1).max(0) as usize;
+    text.chars().skip(start.max(0) as usize).take(len).collect()
+}
+
+pub(crate) struct VectorSearch {
+    pub model_id: i32,
+    pub model: Arc<BiEncoderModel>,
+    pub index: Arc<VectorIndex>,
+}
+
+struct VectorHit {
+    sequence_num: i32,
+    score: f32,
+}
+
+impl SearchStore {
+    pub(crate) fn vector_search_for(
+        &self,
+        model_id: Option<i32>,
+    ) -> Result<Option<VectorSearch>, Report<SearchError>> {
+        let models = self.loaded_bi_encoders.read();
+        let model = match model_id {
+            Some(id) => Some(
+                models
+                    .iter()
+                    .find(|m| m.id == id)
+                    .ok_or(SearchError::ModelNotLoaded(id, "bi-encoder"))
+                    .into_report()?,
+            ),
+            None => models.first(),
+        };
+
+        let Some(model) = model else {
+            return Ok(None);
+        };
+
+        match (self.vector_indexes.get(model.id), model_id) {
+            (Some(index), _) => Ok(Some(VectorSearch {
+                model_id: model.id,
+                model: model.model.clone(),
+                index,
+            })),
+            (None, Some(id)) => Err(SearchError::NoVectorIndex(id)).into_report(),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub(crate) fn cross_encoder(
+        &self,
+        model_id: i32,
+    ) -> Result<Arc<CrossEncoderModel>, Report<SearchError>> {
+        self.loaded_cross_encoders
+            .read()
+            .iter()
+            .find(|m| m.id == model_id)
+            .map(|m| m.model.clone())
+            .ok_or(SearchError::ModelNotLoaded(model_id, "cross-encoder"))
+            .into_report()
+    }
+
+    /// Search for items matching the query. BM25 and vector search run in parallel, and their
+    /// results are fused and then optionally reranked.
+    pub async fn search(
+        &self,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchResult>, Report<SearchError>> {
+        let vector_search = self.vector_search_for(query.bi_encoder_id)?;
+        let cross_encoder = query
+            .cross_encoder_id
+            .map(|id| self.cross_encoder(id))
+            .transpose()?;
+
+        // Tantivy requires a limit of at least one, and sizes its heap from the limit.
+        let candidates = query.candidates.clamp(1, MAX_CANDIDATES);
+
+        let filter = self
+            .compile_filter(&query.filter)
+            .await
+            .change_context(SearchError::Db)?;
+        if filter.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let text_future = {
+            let index = self.text_index.clone();
+            let text = query.query.clone();
+            let limit = candidates;
+            let filter = filter.clone();
+            async move {
+                tokio::task::spawn_blocking(move || index.search(&text, limit, Some(filter)))
+                    .await
+                    .into_report()
+                    .change_context(SearchError::Task)?
+                    .map_err(|e| {
+                        let context = match e.current_context() {
+                            TextIndexError::InvalidQuery => SearchError::InvalidQuery,
+                            _ => SearchError::TextSearch,
+                        };
+                        e.change_context(context)
+                    })
+            }
+        };
+
+        let vector_future = {
+            let search = vector_search
+                .as_ref()
+                .map(|s| (s.model.clone(), s.index.clone()));
+            let text = query.query.clone();
+            let limit = candidates * CHUNKS_PER_ITEM;
+            let filter = filter.clone();
+            async move {
+                let Some((model, index)) = search else {
+                    return Ok(None);
+                };
+
+                tokio::task::spawn_blocking(move || {
+                    let embedding = model
+                        .encode(&[text])
+                        .change_context(SearchError::Model)?
+                        .pop()
+                        .unwrap_or_default();
+                    let matches = index.search(&embedding, limit, Some(&filter));
+                    Ok::<_, Report<SearchError>>(Some((embedding, matches)))
+                })
+                .await
+                .into_report()
+                .change_context(SearchError::Task)?
+            }
+        };
+
+        let (text_results, vector_results) = tokio::try_join!(text_future, vector_future)?;
+
+        let text_ranking = text_results
+            .matches
+            .iter()
+            .map(|m| m.item_id)
+            .collect::<Vec<_>>();
+        let text_matches = text_results
+            .matches
+            .into_iter()
+            .map(|m| (m.item_id, m))
+            .collect::<HashMap<_, _>>();
+
+        // Keep the best chunk for each item.
+        let (query_embedding, vector_matches) = vector_results.unwrap_or_default();
+        let mut vector_hits: HashMap<i64, VectorHit> = HashMap::new();
+        let mut vector_ranking = Vec::new();
+        for m in vector_matches {
+            vector_hits.entry(m.chunk.item_id).or_insert_with(|| {
+                vector_ranking.push(m.chunk.item_id);
+                VectorHit {
+                    sequence_num: m.chunk.sequence_num,
+                    score: m.score,
+                }
+            });
+        }
+
+        let fused = reciprocal_rank_fusion(
+            &[
+                (&text_ranking, query.weights.text),
+                (&vector_ranking, query.weights.vector),
+            ],
+            query.weights.k,
+        );
+
+        let num_candidates = if cross_encoder.is_some() {
+            query.limit.max(query.rerank_top_n)
+        } else {
+            query.limit
+        };
+
+        // An item may have changed since the filter was compiled, so look up the items and skip
+        // any that are no longer ready. Fetch a few extra to make up for them.
+        let candidate_ids = fused
+            .iter()
+            .take(num_candidates.saturating_mul(2))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let items = db::items::get_items_text(&self.pg, &candidate_ids)
+            .await
+            .change_context(SearchError::Db)?
+            .into_iter()
+            .filter(|item| item.status == ItemStatus::Ready)
+            .map(|item| (item.id, item))
+            .collect::<HashMap<_, _>>();
+
+        let fused = fused
+            .into_iter()
+            .filter(|(id, _)| items.contains_key(id))
+            .take(num_candidates)
+            .collect::<Vec<_>>();
+
+        let chunks = match &vector_search {
+            Some(search) => {
+                let ids = fused.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                db::item_chunks::get_item_embeddings(&self.pg, search.model_id, &ids)
+                    .await
+                    .change_context(SearchError::Db)?
+            }
+            None => Vec::new(),
+        };
+        let mut item_chunks: HashMap<i64, Vec<ItemChunkEmbedding>> = HashMap::new();
+        for chunk in chunks {
+            item_chunks.entry(chunk.item_id).or_default().push(chunk);
+        }
+
+        let mut results = fused
+            .into_iter()
+            .map(|(item_id, fused_score)| {
+                let item = &items[&item_id];
+                let text_match = text_matches.get(&item_id);
+                let vector_hit = vector_hits.get(&item_id);
+                let chunk = best_chunk(
+                    item,
+                    item_chunks.get(&item_id).map(|c| c.as_slice()),
+                    vector_hit,
+                    &query_embedding,
+                );
+
+                SearchResult {
+                    item_id,
+                    title: item.title.clone().or_else(|| item.name.clone()),
+                    score: fused_score,
+                    fused_score,
+                    text_score: text_match.map(|m| m.score),
+                    vector_score: vector_hit.map(|h| h.score),
+                    rerank_score: None,
+                    chunk,
+                    snippet: text_match.map(|m| m.snippet.clone()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(cross_encoder) = cross_encoder {
+            let num_rerank = query.rerank_top_n.min(results.len());
+            let passages = results[..num_rerank]
+                .iter()
+                .map(|r| passage_text(r, text_matches.get(&r.item_id)))
+                .collect::<Vec<_>>();
+
+            let text = query.query.clone();
+            let ranked = tokio::task::spawn_blocking(move || cross_encoder.rank(&text, &passages))
+                .await
+                .into_report()
+                .change_context(SearchError::Task)?
+                .change_context(SearchError::Model)?;
+
+            let mut to_rerank = results.drain(..num_rerank).map(Some).collect::<Vec<_>>();
+            let mut reranked = ranked
+                .into_iter()
+                .filter_map(|r| {
+                    let mut result = to_rerank.get_mut(r.index)?.take()?;
+                    result.rerank_score = Some(r.score);
+                    result.score = r.score;
+                    Some(result)
+                })
+                .collect::<Vec<_>>();
+            reranked.extend(results);
+            results = reranked;
+        }
+
+        results.truncate(query.limit);
+        Ok(results)
+    }
+}
+
+/// Find the chunk of an item that best matches the query. This is the chunk found by vector
+/// search if there was one, and otherwise the chunk most similar to the query.
+fn best_chunk(
+    item: &ItemText,
+    chunks: Option<&[ItemChunkEmbedding]>,
+    vector_hit: Option<&VectorHit>,
+    query_embedding: &[f32],
+) -> Option<SearchChunk> {
+    let chunks = chunks?;
+    let chunk = match vector_hit {
+        Some(hit) => chunks.iter().find(|c| c.sequence_num == hit.sequence_num),
+        None => chunks.iter().max_by(|a, b| {
+            let a = cosine_similarity(&embedding_from_bytes(&a.embedding), query_embedding);
+            let b = cosine_similarity(&embedding_from_bytes(&b.embedding), query_embedding);
+            a.total_cmp(&b)
+        }),
+    }?;
+
+    Some(SearchChunk {
+        sequence_num: chunk.sequence_num,
+        start_idx: chunk.start_idx,
+        end_idx: chunk.end_idx,
+        text: char_span(
+            item.processed_content.as_deref().unwrap_or_default(),
+            chunk.start_idx,
+            chunk.end_idx,
+        ),
+    })
+}
+
+/// The text to give the cross-encoder for a result.
+fn passage_text(result: &SearchResult, text_match: Option<&TextMatch>) -> String {
+    result
+        .chunk
+        .as_ref()
+        .map(|c| c.text.clone())
+        .or_else(|| text_match.map(|m| m.snippet_text.clone()))
+        .or_else(|| result.title.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{char_span, reciprocal_rank_fusion};
+
+    #[test]
+    fn fusion() {
+        let text = [1, 2, 3];
+        let vector = [3, 4, 1];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 1.0)], 60.0);
+        let ids = fused.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3, 2, 4]);
+        assert!((fused[0].1 - (1.0 / 61.0 + 1.0 / 63.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fusion_weights() {
+        let text = [1, 2];
+        let vector = [2, 1];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 2.0)], 60.0);
+        assert_eq!(fused[0].0, 2);
+
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 0.0)], 60.0);
+        assert_eq!(fused[0].0, 1);
+    }
+
+    #[test]
+    fn char_spans() {
+        assert_eq!(char_span("héllo wörld", 6, 10), "wörld");
+        assert_eq!(char_span("abc", 0, 0), "a");
+        assert_eq!(char_span("abc", 2, 1), "");
+    }
+}
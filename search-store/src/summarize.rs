@@ -0,0 +1,282 @@
+//! Summarize documents with a completion model. Documents that do not fit in the model's
+//! context are split into sections, which are summarized separately and then combined.
+
+use std::ops::Range;
+
+use error_stack::{IntoReport, Report, ResultExt};
+
+use crate::{
+    chunking::{chunk_text, ChunkOptions},
+    models::{
+        completion::{CompletionModel, CompletionSubmission, SamplingParams},
+        ModelError,
+    },
+};
+
+const DOCUMENT_PROMPT: &str =
+    "Summarize the following document. Keep the key facts, names and conclusions.";
+const SECTION_PROMPT: &str =
+    "Summarize the following section of a longer document. Keep the key facts, names and \
+    conclusions.";
+const COMBINE_PROMPT: &str =
+    "The following are summaries of consecutive sections of one document. Combine them into a \
+    single summary of the whole document.";
+
+/// Summaries of sections are combined in groups, and each group has room for at least this
+/// many of them so that every round makes progress.
+const MIN_GROUP_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct SummaryOptions {
+    /// The completion model to summarize items with after they are processed. Items are only
+    /// summarized on request when this is not set.
+    pub model_id: Option<i32>,
+    /// The longest summary to generate, in tokens
+    pub max_tokens: usize,
+}
+
+impl Default for SummaryOptions {
+    fn default() -> Self {
+        Self {
+            model_id: None,
+            max_tokens: 400,
+        }
+    }
+}
+
+fn build_prompt(instruction: &str, text: &str) -> String {
+    format!("{instruction}\n\n{text}\n\nSummary:")
+}
+
+/// Split consecutive summaries into groups that each fit in `budget` tokens.
+fn group_by_budget(tokens: &[usize], budget: usize) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut total = 0;
+
+    for (i, &t) in tokens.iter().enumerate() {
+        if i > start && total + t > budget {
+            groups.push(start..i);
+            start = i;
+            total = 0;
+        }
+        total += t;
+    }
+
+    if start < tokens.len() {
+        groups.push(start..tokens.len());
+    }
+
+    groups
+}
+
+fn run_prompt(
+    model: &dyn CompletionModel,
+    instruction: &str,
+    text: &str,
+    max_tokens: usize,
+) -> Result<String, Report<ModelError>> {
+    let output = model.complete(CompletionSubmission {
+        prompt: build_prompt(instruction, text),
+        temperature: None,
+        sampling: SamplingParams {
+            max_tokens: Some(max_tokens),
+            ..Default::default()
+        },
+    })?;
+
+    // Models don't always stop at the limit, and a summary that runs long could leave no room
+    // for the others that it is combined with, or not fit in a prompt at all.
+    let text = output.text.trim();
+    if model.count_tokens(text) <= max_tokens {
+        return Ok(text.to_string());
+    }
+
+    let truncated = chunk_text(
+        text,
+        &ChunkOptions {
+            max_tokens,
+            overlap_tokens: 0,
+        },
+        |s| model.count_tokens(s),
+    )
+    .first()
+    .map(|chunk| text[chunk.start..chunk.end].to_string())
+    .unwrap_or_default();
+    Ok(truncated)
+}
+
+/// Summarize some text. If it is too long for the model, each section is summarized and the
+/// section summaries are combined until the result fits into a single prompt.
+pub fn summarize_text(
+    model: &dyn CompletionModel,
+    text: &str,
+    options: &SummaryOptions,
+) -> Result<String, Report<ModelError>> {
+    let overhead = [DOCUMENT_PROMPT, SECTION_PROMPT, COMBINE_PROMPT]
+        .into_iter()
+        .map(|instruction| model.count_tokens(&build_prompt(instruction, "")))
+        .max()
+        .unwrap_or(0);
+    let input_budget = model
+        .context_size()
+        .saturating_sub(options.max_tokens + overhead);
+
+    if model.count_tokens(text) <= input_budget {
+        return run_prompt(model, DOCUMENT_PROMPT, text, options.max_tokens);
+    }
+
+    // Keep the intermediate summaries short enough that several fit into each prompt, along
+    // with the blank lines between them.
+    let part_tokens = options
+        .max_tokens
+        .min((input_budget / MIN_GROUP_SIZE).saturating_sub(1));
+    if part_tokens == 0 {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("The model's context is too small to summarize this document");
+    }
+
+    let sections = chunk_text(
+        text,
+        &ChunkOptions {
+            max_tokens: input_budget,
+            overlap_tokens: 0,
+        },
+        |s| model.count_tokens(s),
+    );
+
+    let mut parts = sections
+        .iter()
+        .map(|section| {
+            run_prompt(
+                model,
+                SECTION_PROMPT,
+                &text[section.start..section.end],
+                part_tokens,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .attach_printable("Summarizing sections")?;
+
+    loop {
+        // Count the blank line between summaries too.
+        let tokens = parts
+            .iter()
+            .map(|p| model.count_tokens(p) + 1)
+            .collect::<Vec<_>>();
+        let groups = group_by_budget(&tokens, input_budget);
+
+        if groups.len() <= 1 {
+            return run_prompt(
+                model,
+                COMBINE_PROMPT,
+                &parts.join("\n\n"),
+                options.max_tokens,
+            );
+        }
+
+        parts = groups
+            .into_iter()
+            .map(|group| {
+                run_prompt(
+                    model,
+                    COMBINE_PROMPT,
+                    &parts[group].join("\n\n"),
+                    part_tokens,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .attach_printable("Combining section summaries")?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{group_by_budget, summarize_text, SummaryOptions};
+    use crate::models::completion::{fake::FakeModel, CompletionModel, CompletionSubmission};
+
+    /// "Summarizes" by returning the first few words of the text.
+    fn first_words(submission: &CompletionSubmission) -> String {
+        submission
+            .prompt
+            .split("\n\n")
+            .nth(1)
+            .unwrap_or_default()
+            .split_whitespace()
+            .take(submission.sampling.max_tokens.unwrap())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Repeats the whole text back, ignoring the token limit.
+    fn whole_text(submission: &CompletionSubmission) -> String {
+        let (_, rest) = submission.prompt.split_once("\n\n").unwrap();
+        let (text, _) = rest.rsplit_once("\n\nSummary:").unwrap();
+        text.to_string()
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(group_by_budget(&[3, 3, 3, 3], 6), vec![0..2, 2..4]);
+        assert_eq!(group_by_budget(&[3, 3, 3], 10), vec![0..3]);
+        assert_eq!(group_by_budget(&[12, 3, 3], 10), vec![0..1, 1..3]);
+        assert!(group_by_budget(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn short_document() {
+        let model = FakeModel::new(100, first_words);
+        let options = SummaryOptions {
+            model_id: None,
+            max_tokens: 10,
+        };
+
+        let summary = summarize_text(&model, "A short document about Rust.", &options).unwrap();
+        assert_eq!(summary, "A short document about Rust.");
+        assert_eq!(model.prompts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn long_document() {
+        let model = FakeModel::new(100, first_words);
+        let options = SummaryOptions {
+            model_id: None,
+            max_tokens: 20,
+        };
+
+        let text = (0..50)
+            .map(|p| format!("Paragraph{p} has some words in it to summarize."))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let summary = summarize_text(&model, &text, &options).unwrap();
+        assert!(summary.starts_with("Paragraph0"));
+
+        let prompts = model.prompts.lock().unwrap();
+        assert!(prompts.len() > 2, "document should be split into sections");
+        assert!(prompts
+            .last()
+            .unwrap()
+            .starts_with("The following are summaries"));
+    }
+
+    #[test]
+    fn long_section_summaries() {
+        let model = FakeModel::new(100, whole_text);
+        let options = SummaryOptions {
+            model_id: None,
+            max_tokens: 20,
+        };
+
+        let text = (0..50)
+            .map(|p| format!("Paragraph{p} has some words in it to summarize."))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        // The model checks that every prompt fits in its context.
+        let summary = summarize_text(&model, &text, &options).unwrap();
+        assert!(summary.starts_with("Paragraph0"));
+        assert!(model.count_tokens(&summary) <= options.max_tokens);
+    }
+}
@@ -0,0 +1,484 @@
+//! Summarize and compare a set of related items. Each item is summarized on its own, and then
+//! the summaries are combined into an overview, notable points, and the places where the items
+//! agree or contradict each other. Each point cites the items that it came from.
+
+use error_stack::{IntoReport, Report, ResultExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    ask::cited_sources,
+    db::{
+        self,
+        items::{ItemStatus, ItemText},
+    },
+    filter::SearchFilter,
+    models::{
+        completion::{CompletionModel, CompletionSubmission, SamplingParams},
+        ModelError,
+    },
+    search::SearchQuery,
+    summarize::{summarize_text, SummaryOptions},
+    SearchStore,
+};
+
+/// The longest report to generate, in tokens. Models with small contexts get less.
+const MAX_OUTPUT_TOKENS: usize = 1024;
+/// Each item needs at least this much room for its summary. If there are too many items to
+/// allow this, the least relevant ones are left out.
+const MIN_ITEM_TOKENS: usize = 64;
+
+/// Citations such as [1] or [2, 3], which are removed from the text of a point
+static CITATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\s*\[\d+(?:\s*,\s*\d+)*\]").expect("valid regex"));
+
+const SYNTHESIS_PROMPT: &str = "Below are summaries of several related documents, each with a \
+number in square brackets. Write a report about them with exactly these sections:
+
+SUMMARY:
+An overview of what the documents say as a whole.
+
+NOTABLE POINTS:
+- One line for each notable point, ending with the numbers of the documents it comes from, \
+such as [1] or [2, 3].
+
+AGREEMENTS:
+- One line for each point that several documents agree on, ending with their numbers.
+
+CONTRADICTIONS:
+- One line for each point where the documents disagree, ending with their numbers.
+
+Write \"- None\" in a section that has nothing to list.";
+const PROMPT_END: &str = "Report:\nSUMMARY:";
+
+#[derive(Debug, Error)]
+pub enum SynthesisError {
+    #[error("Database error")]
+    Db,
+    #[error("Search failed")]
+    Search,
+    #[error("No summary model is configured")]
+    NoSummaryModel,
+    #[error("Model {0} is not a loaded completion model")]
+    ModelNotLoaded(i32),
+    #[error("None of the items have any content")]
+    NoItems,
+    #[error("Model failed")]
+    Model,
+    #[error("Background task failed")]
+    Task,
+}
+
+/// The items to synthesize.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SynthesisSource {
+    Items {
+        item_ids: Vec<i64>,
+    },
+    Tag {
+        tag_id: i32,
+    },
+    /// The items that best match a search
+    Query {
+        query: String,
+        #[serde(default)]
+        filter: SearchFilter,
+    },
+}
+
+fn default_max_items() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SynthesisRequest {
+    pub source: SynthesisSource,
+    /// The completion model to use. Defaults to the configured summary model.
+    pub model_id: Option<i32>,
+    /// The most items to include
+    #[serde(default = "default_max_items")]
+    pub max_items: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SynthesisPoint {
+    pub text: String,
+    /// The items that the point came from
+    pub item_ids: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SynthesisItem {
+    pub item_id: i64,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Synthesis {
+    /// The items that were included
+    pub items: Vec<SynthesisItem>,
+    pub summary: String,
+    pub notable_points: Vec<SynthesisPoint>,
+    pub agreements: Vec<SynthesisPoint>,
+    pub contradictions: Vec<SynthesisPoint>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Summary,
+    NotablePoints,
+    Agreements,
+    Contradictions,
+}
+
+/// Check if a line starts a section of the report, returning the section and any text that
+/// follows the heading on the same line.
+fn parse_heading(line: &str) -> Option<(Section, &str)> {
+    let line = line.trim().trim_start_matches(['#', '*', ' ']);
+    let headings = [
+        ("SUMMARY", Section::Summary),
+        ("NOTABLE POINTS", Section::NotablePoints),
+        ("AGREEMENTS", Section::Agreements),
+        ("CONTRADICTIONS", Section::Contradictions),
+    ];
+
+    headings.into_iter().find_map(|(heading, section)| {
+        let rest = line.get(..heading.len())?;
+        if !rest.eq_ignore_ascii_case(heading) {
+            return None;
+        }
+
+        let rest = line[heading.len()..].trim_start_matches(['*', ' ']);
+        let rest = rest.strip_prefix(':')?;
+        Some((section, rest.trim_start_matches('*').trim()))
+    })
+}
+
+fn parse_point(line: &str, item_ids: &[i64]) -> Option<SynthesisPoint> {
+    let line = line.trim_start_matches(['-', '*', '•', ' ']);
+    let text = CITATION.replace_all(line, "").trim().to_string();
+    if text.is_empty() || text.trim_end_matches('.').eq_ignore_ascii_case("none") {
+        return None;
+    }
+
+    let item_ids = cited_sources(line)
+        .into_iter()
+        .filter_map(|number| item_ids.get(number.checked_sub(1)?).copied())
+        .collect();
+
+    Some(SynthesisPoint { text, item_ids })
+}
+
+/// Parse the report written by the model. `item_ids` are the items in the order they were
+/// numbered in the prompt.
+fn parse_report(report: &str, item_ids: &[i64]) -> Synthesis {
+    let mut synthesis = Synthesis::default();
+    let mut summary = Vec::new();
+    let mut section = Section::Summary;
+
+    for line in report.lines() {
+        let line = match parse_heading(line) {
+            Some((heading, rest)) => {
+                section = heading;
+                rest
+            }
+            None => line.trim(),
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let points = match section {
+            Section::Summary => {
+                summary.push(line);
+                continue;
+            }
+            Section::NotablePoints => &mut synthesis.notable_points,
+            Section::Agreements => &mut synthesis.agreements,
+            Section::Contradictions => &mut synthesis.contradictions,
+        };
+
+        points.extend(parse_point(line, item_ids));
+    }
+
+    synthesis.summary = summary.join("\n");
+    synthesis
+}
+
+fn item_header(number: usize, item: &ItemText) -> String {
+    let title = item
+        .title
+        .as_deref()
+        .or(item.name.as_deref())
+        .unwrap_or("Untitled");
+    format!("[{number}] {title}\n")
+}
+
+/// Summarize each item to fit into the model's context, and then write the report.
+fn synthesize_items(
+    model: &dyn CompletionModel,
+    mut items: Vec<ItemText>,
+    options: &SummaryOptions,
+) -> Result<Synthesis, Report<ModelError>> {
+    let context_size = model.context_size();
+    let output_tokens = (context_size / 4).min(MAX_OUTPUT_TOKENS);
+    let prompt_start = format!("{SYNTHESIS_PROMPT}\n\nDocuments:\n\n");
+    let input_budget = context_size.saturating_sub(
+        output_tokens + model.count_tokens(&prompt_start) + model.count_tokens(PROMPT_END),
+    );
+
+    // Each item needs room for its header and the blank line after it, as well as its
+    // summary. Keep as many items as there is room for.
+    let headers = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| item_header(i + 1, item))
+        .collect::<Vec<_>>();
+    let mut header_tokens = 0;
+    let mut num_items = 0;
+    for header in &headers {
+        let tokens = model.count_tokens(header) + 1;
+        let needed = header_tokens + tokens + (num_items + 1) * MIN_ITEM_TOKENS;
+        if num_items > 0 && needed > input_budget {
+            break;
+        }
+        header_tokens += tokens;
+        num_items += 1;
+    }
+    items.truncate(num_items);
+
+    let summary_budget = input_budget.saturating_sub(header_tokens);
+    let item_tokens = options.max_tokens.min(summary_budget / items.len());
+    let item_options = SummaryOptions {
+        max_tokens: item_tokens,
+        ..options.clone()
+    };
+
+    let mut prompt = prompt_start;
+    for (item, header) in items.iter().zip(&headers) {
+        let text = item
+            .generated_summary
+            .as_deref()
+            .or(item.processed_content.as_deref())
+            .unwrap_or_default();
+
+        // Existing summaries are used as they are if they are short enough.
+        let summary = if item.generated_summary.is_some() && model.count_tokens(text) <= item_tokens
+        {
+            text.to_string()
+        } else {
+            summarize_text(model, text, &item_options)
+                .attach_printable_lazy(|| format!("Summarizing item {}", item.id))?
+        };
+
+        prompt.push_str(header);
+        prompt.push_str(&summary);
+        prompt.push_str("\n\n");
+    }
+    prompt.push_str(PROMPT_END);
+
+    // Token counts of the parts don't always add up to the count of the whole, so check the
+    // finished prompt and take any difference out of the report.
+    let output_tokens = output_tokens.min(context_size.saturating_sub(model.count_tokens(&prompt)));
+    if output_tokens == 0 {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("The documents are too long to fit in the model's context");
+    }
+
+    let output = model.complete(CompletionSubmission {
+        prompt,
+        temperature: None,
+        sampling: SamplingParams {
+            max_tokens: Some(output_tokens),
+            ..Default::default()
+        },
+    })?;
+
+    let item_ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+    let mut synthesis = parse_report(&format!("SUMMARY:{}", output.text), &item_ids);
+    synthesis.items = items
+        .into_iter()
+        .map(|item| SynthesisItem {
+            item_id: item.id,
+            title: item.title.or(item.name),
+        })
+        .collect();
+
+    Ok(synthesis)
+}
+
+impl SearchStore {
+    /// Find the items that a synthesis covers, most relevant first.
+    async fn synthesis_item_ids(
+        &self,
+        request: &SynthesisRequest,
+    ) -> Result<Vec<i64>, Report<SynthesisError>> {
+        let mut ids = match &request.source {
+            SynthesisSource::Items { item_ids } => item_ids.clone(),
+            // The most recent items come first, so those are the ones kept.
+            SynthesisSource::Tag { tag_id } => {
+                db::tags::list_tag_item_ids(&self.pg, *tag_id, request.max_items as i64)
+                    .await
+                    .change_context(SynthesisError::Db)?
+            }
+            SynthesisSource::Query { query, filter } => {
+                let search = SearchQuery {
+                    filter: filter.clone(),
+                    limit: request.max_items,
+                    ..SearchQuery::new(query.clone())
+                };
+
+                self.search(&search)
+                    .await
+                    .change_context(SynthesisError::Search)?
+                    .into_iter()
+                    .map(|result| result.item_id)
+                    .collect()
+            }
+        };
+
+        ids.truncate(request.max_items);
+        Ok(ids)
+    }
+
+    /// Summarize and compare the items in a request.
+    pub async fn synthesize(
+        &self,
+        request: &SynthesisRequest,
+    ) -> Result<Synthesis, Report<SynthesisError>> {
+        let model_id = request
+            .model_id
+            .or(self.summary_options.model_id)
+            .ok_or(SynthesisError::NoSummaryModel)
+            .into_report()?;
+
+        let model = self
+            .loaded_completion_models
+            .read()
+            .iter()
+            .find(|m| m.id == model_id)
+            .map(|m| m.model.clone())
+            .ok_or(SynthesisError::ModelNotLoaded(model_id))
+            .into_report()?;
+
+        let ids = self.synthesis_item_ids(request).await?;
+        let mut items = db::items::get_items_text(&self.pg, &ids)
+            .await
+            .change_context(SynthesisError::Db)?
+            .into_iter()
+            .filter(|item| {
+                item.status == ItemStatus::Ready
+                    && (item.generated_summary.is_some() || item.processed_content.is_some())
+            })
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| ids.iter().position(|id| *id == item.id));
+
+        if items.is_empty() {
+            return Err(SynthesisError::NoItems).into_report();
+        }
+
+        let options = self.summary_options.clone();
+        tokio::task::spawn_blocking(move || synthesize_items(model.as_ref(), items, &options))
+            .await
+            .into_report()
+            .change_context(SynthesisError::Task)?
+            .change_context(SynthesisError::Model)
+            .attach_printable_lazy(|| format!("Model {model_id}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+
+    use super::{parse_report, synthesize_items, SynthesisPoint};
+    use crate::{
+        db::items::{ItemStatus, ItemText},
+        models::completion::fake::FakeModel,
+        summarize::SummaryOptions,
+    };
+
+    #[test]
+    fn parse() {
+        let report = "SUMMARY: The documents discuss Rust.
+It is a programming language.
+
+**NOTABLE POINTS:**
+- Rust has ownership [1].
+- The borrow checker enforces it [1, 3]
+* Unknown sources are dropped [7]
+
+## Agreements:
+- Both like Rust. [1][2]
+
+CONTRADICTIONS:
+- None
+";
+
+        let synthesis = parse_report(report, &[10, 20, 30]);
+        assert_eq!(
+            synthesis.summary,
+            "The documents discuss Rust.\nIt is a programming language."
+        );
+        assert_eq!(
+            synthesis.notable_points,
+            vec![
+                SynthesisPoint {
+                    text: "Rust has ownership.".to_string(),
+                    item_ids: vec![10],
+                },
+                SynthesisPoint {
+                    text: "The borrow checker enforces it".to_string(),
+                    item_ids: vec![10, 30],
+                },
+                SynthesisPoint {
+                    text: "Unknown sources are dropped".to_string(),
+                    item_ids: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            synthesis.agreements,
+            vec![SynthesisPoint {
+                text: "Both like Rust.".to_string(),
+                item_ids: vec![10, 20],
+            }]
+        );
+        assert!(synthesis.contradictions.is_empty());
+    }
+
+    #[test]
+    fn long_titles() {
+        let model = FakeModel::new(600, |_| " An overview.".to_string());
+        let options = SummaryOptions {
+            model_id: None,
+            max_tokens: 100,
+        };
+
+        let items = (0..10)
+            .map(|id| ItemText {
+                id,
+                status: ItemStatus::Ready,
+                hidden: false,
+                name: None,
+                title: Some(["Long"; 40].join(" ")),
+                author: None,
+                description: None,
+                processed_content: None,
+                generated_summary: Some(["Summary"; 60].join(" ")),
+                tags: Vec::new(),
+                updated_at: OffsetDateTime::UNIX_EPOCH,
+            })
+            .collect::<Vec<_>>();
+
+        // The titles take up room too, so fewer items fit than the summaries alone allow.
+        let synthesis = synthesize_items(&model, items, &options).unwrap();
+        assert_eq!(synthesis.summary, "An overview.");
+        assert_eq!(synthesis.items.len(), 3);
+    }
+}
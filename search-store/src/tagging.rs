@@ -0,0 +1,646 @@
+//! Suggest tags for items. Tags that already have enough items are scored by how close the
+//! item's embedding is to the centroid of those items, compared to how close the items
+//! themselves are. Tags with too few examples fall back to asking an instruct model whether
+//! they apply.
+
+use std::collections::HashMap;
+
+use error_stack::{IntoReport, Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::{
+    chunking::{chunk_text, ChunkOptions},
+    db::{
+        self,
+        item_chunks::embedding_from_bytes,
+        tags::{Tag, TaggedItem},
+    },
+    models::{
+        completion::{CompletionModel, CompletionSubmission, SamplingParams},
+        ModelError,
+    },
+    search::cosine_similarity,
+    SearchStore,
+};
+
+/// Tags with fewer example items than this are classified by the instruct model instead.
+const MIN_TAG_EXAMPLES: usize = 3;
+/// The most recently updated items to use for each tag's centroid.
+const MAX_TAG_EXAMPLES: usize = 50;
+/// The number of items to read embeddings for at once.
+const EMBEDDING_BATCH_SIZE: usize = 100;
+/// Room for the model's answer when classifying an item.
+const CLASSIFY_OUTPUT_TOKENS: usize = 256;
+
+const CLASSIFY_PROMPT: &str = "Decide which of the following tags apply to the document below. \
+For each tag that applies, write a line with the tag name, a colon, and how confident you are \
+that it applies, from 0 to 100. Do not list tags that do not apply.";
+
+#[derive(Debug, Error)]
+pub enum TagError {
+    #[error("Database error")]
+    Db,
+    #[error("Item {0} not found")]
+    ItemNotFound(i64),
+    #[error("Model {0} is not a loaded completion model")]
+    ModelNotLoaded(i32),
+    #[error("Model failed")]
+    Model,
+    #[error("Background task failed")]
+    Task,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TagOptions {
+    /// The instruct model that classifies items for tags with too few examples. Those tags are
+    /// not suggested when this is not set.
+    pub model_id: Option<i32>,
+    /// Add suggested tags with at least this confidence to items after they are processed.
+    /// Items are only tagged on request when this is not set.
+    pub auto_apply_threshold: Option<f32>,
+}
+
+fn default_limit() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagSuggestionQuery {
+    /// The bi-encoder whose embeddings to compare. Defaults to the first loaded bi-encoder.
+    pub bi_encoder_id: Option<i32>,
+    /// The instruct model to use. Defaults to the configured tag model.
+    pub model_id: Option<i32>,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    /// Add the suggestions with at least this confidence to the item
+    pub apply_threshold: Option<f32>,
+}
+
+impl Default for TagSuggestionQuery {
+    fn default() -> Self {
+        Self {
+            bi_encoder_id: None,
+            model_id: None,
+            limit: default_limit(),
+            apply_threshold: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestionMethod {
+    /// Similarity to the items that already have the tag. The confidence is the share of
+    /// those items that are no closer to the rest of them than this item is.
+    Centroid,
+    /// Classification by an instruct model
+    ZeroShot,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TagSuggestion {
+    pub tag_id: i32,
+    pub name: String,
+    /// From 0 to 1
+    pub confidence: f32,
+    pub method: SuggestionMethod,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagSuggestions {
+    /// The best suggestions first
+    pub suggestions: Vec<TagSuggestion>,
+    /// The tags that were added to the item
+    pub applied: Vec<i32>,
+}
+
+/// The mean direction of some embeddings, as a unit vector. Each one is normalized first so
+/// that they all count equally.
+fn mean_embedding<'a>(embeddings: impl IntoIterator<Item = &'a [f32]>) -> Option<Vec<f32>> {
+    let mut sum: Option<Vec<f32>> = None;
+    for embedding in embeddings {
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+
+        let sum = sum.get_or_insert_with(|| vec![0.0; embedding.len()]);
+        for (s, v) in sum.iter_mut().zip(embedding) {
+            *s += v / norm;
+        }
+    }
+
+    let mut sum = sum?;
+    let norm = sum.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return None;
+    }
+
+    for v in sum.iter_mut() {
+        *v /= norm;
+    }
+    Some(sum)
+}
+
+/// The example items of a tag.
+struct TagExamples {
+    /// The sum of the embeddings. Only the direction matters for cosine similarity, so this
+    /// never needs to be divided into a mean.
+    sum: Vec<f32>,
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl TagExamples {
+    /// How close each example is to the centroid of the other examples. Leaving the example
+    /// out of the centroid keeps it from making itself look closer.
+    fn example_similarities(&self) -> Vec<f32> {
+        self.embeddings
+            .iter()
+            .map(|embedding| {
+                let rest = self
+                    .sum
+                    .iter()
+                    .zip(embedding)
+                    .map(|(s, v)| s - v)
+                    .collect::<Vec<_>>();
+                cosine_similarity(&rest, embedding)
+            })
+            .collect()
+    }
+
+    /// Raw similarities are not comparable between tags or models, since some embedding
+    /// models rate even unrelated text as quite similar. Instead, the confidence is the share
+    /// of examples that are no closer to the others than the embedding is to all of them.
+    fn confidence(&self, embedding: &[f32]) -> f32 {
+        let similarity = cosine_similarity(&self.sum, embedding);
+        let examples = self.example_similarities();
+        let below = examples.iter().filter(|s| **s <= similarity).count();
+        below as f32 / examples.len() as f32
+    }
+}
+
+/// The example items of each tag, for scoring the tags against other items.
+#[derive(Default)]
+struct TagCentroids {
+    tags: HashMap<i32, TagExamples>,
+}
+
+impl TagCentroids {
+    fn add(&mut self, tags: &[i32], embedding: &[f32]) {
+        for tag in tags {
+            let examples = self.tags.entry(*tag).or_insert_with(|| TagExamples {
+                sum: vec![0.0; embedding.len()],
+                embeddings: Vec::new(),
+            });
+            for (s, v) in examples.sum.iter_mut().zip(embedding) {
+                *s += v;
+            }
+            examples.embeddings.push(embedding.to_vec());
+        }
+    }
+
+    /// Whether a tag has enough examples to score it by similarity.
+    fn has_examples(&self, tag: i32) -> bool {
+        self.tags
+            .get(&tag)
+            .map(|examples| examples.embeddings.len() >= MIN_TAG_EXAMPLES)
+            .unwrap_or(false)
+    }
+
+    /// Score the tags that have enough examples by how close an embedding is to them.
+    fn score(&self, embedding: &[f32]) -> Vec<(i32, f32)> {
+        self.tags
+            .iter()
+            .filter(|(tag, _)| self.has_examples(**tag))
+            .map(|(tag, examples)| (*tag, examples.confidence(embedding)))
+            .collect()
+    }
+}
+
+/// The suggestions that are confident enough to add to the item.
+fn tags_to_apply(suggestions: &[TagSuggestion], threshold: Option<f32>) -> Vec<i32> {
+    let Some(threshold) = threshold else {
+        return Vec::new();
+    };
+
+    suggestions
+        .iter()
+        .filter(|s| s.confidence >= threshold)
+        .map(|s| s.tag_id)
+        .collect()
+}
+
+/// Choose the example items for each tag, taking up to `MAX_TAG_EXAMPLES` from the start of
+/// the list.
+fn select_examples(items: Vec<TaggedItem>) -> Vec<TaggedItem> {
+    let mut counts = HashMap::<i32, usize>::new();
+    items
+        .into_iter()
+        .filter(|item| {
+            let needed = item
+                .tags
+                .iter()
+                .any(|tag| counts.get(tag).copied().unwrap_or(0) < MAX_TAG_EXAMPLES);
+            if needed {
+                for tag in &item.tags {
+                    *counts.entry(*tag).or_default() += 1;
+                }
+            }
+            needed
+        })
+        .collect()
+}
+
+/// Read the model's answer, returning each recognized tag with its confidence.
+fn parse_classification(output: &str, tags: &[Tag]) -> Vec<(i32, f32)> {
+    let mut results = Vec::new();
+    for line in output.lines() {
+        let Some((name, confidence)) = line.rsplit_once(':') else {
+            continue;
+        };
+
+        let name = name
+            .trim()
+            .trim_start_matches(['-', '*', ' '])
+            .trim_matches(['"', '\'', '`']);
+        let Some(tag) = tags.iter().find(|t| t.name.eq_ignore_ascii_case(name)) else {
+            continue;
+        };
+
+        let confidence = confidence.trim();
+        let number_len = confidence
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(confidence.len());
+        let Ok(confidence) = confidence[..number_len].parse::<f32>() else {
+            continue;
+        };
+
+        if !results.iter().any(|(id, _)| *id == tag.id) {
+            results.push((tag.id, (confidence / 100.0).clamp(0.0, 1.0)));
+        }
+    }
+
+    results
+}
+
+/// Ask a model which tags apply to some text. The text is cut short if it does not fit.
+fn classify(
+    model: &dyn CompletionModel,
+    tags: &[Tag],
+    text: &str,
+) -> Result<Vec<(i32, f32)>, Report<ModelError>> {
+    let tag_list = tags
+        .iter()
+        .map(|t| format!("- {}", t.name))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let build_prompt = |text: &str| {
+        format!("{CLASSIFY_PROMPT}\n\nTags:\n{tag_list}\n\nDocument:\n{text}\n\nTags that apply:\n")
+    };
+
+    let budget = model
+        .context_size()
+        .saturating_sub(CLASSIFY_OUTPUT_TOKENS + model.count_tokens(&build_prompt("")));
+    if budget == 0 {
+        return Err(ModelError::ParameterError)
+            .into_report()
+            .attach_printable("The model's context is too small for the list of tags");
+    }
+
+    let end = chunk_text(
+        text,
+        &ChunkOptions {
+            max_tokens: budget,
+            overlap_tokens: 0,
+        },
+        |s| model.count_tokens(s),
+    )
+    .first()
+    .map(|chunk| chunk.end)
+    .unwrap_or(0);
+
+    let output = model.complete(CompletionSubmission {
+        prompt: build_prompt(&text[..end]),
+        temperature: Some(0.0),
+        sampling: SamplingParams {
+            max_tokens: Some(CLASSIFY_OUTPUT_TOKENS),
+            ..Default::default()
+        },
+    })?;
+
+    Ok(parse_classification(&output.text, tags))
+}
+
+impl SearchStore {
+    /// The mean direction of an item's chunk embeddings.
+    async fn item_embedding(
+        &self,
+        model_id: i32,
+        item_id: i64,
+    ) -> Result<Option<Vec<f32>>, Report<TagError>> {
+        let chunks = db::item_chunks::get_item_embeddings(&self.pg, model_id, &[item_id])
+            .await
+            .change_context(TagError::Db)?;
+        let embeddings = chunks
+            .iter()
+            .map(|c| embedding_from_bytes(&c.embedding))
+            .collect::<Vec<_>>();
+
+        Ok(mean_embedding(embeddings.iter().map(|e| e.as_slice())))
+    }
+
+    /// Build the centroid of each tag from the embeddings of items that have it.
+    async fn tag_centroids(
+        &self,
+        model_id: i32,
+        exclude_id: i64,
+    ) -> Result<TagCentroids, Report<TagError>> {
+        let items = db::tags::list_tagged_items(&self.pg, exclude_id)
+            .await
+            .change_context(TagError::Db)?;
+        let items = select_examples(items);
+
+        let mut centroids = TagCentroids::default();
+        for batch in items.chunks(EMBEDDING_BATCH_SIZE) {
+            let ids = batch.iter().map(|item| item.id).collect::<Vec<_>>();
+            let chunks = db::item_chunks::get_item_embeddings(&self.pg, model_id, &ids)
+                .await
+                .change_context(TagError::Db)?;
+
+            let mut embeddings = HashMap::<i64, Vec<Vec<f32>>>::new();
+            for chunk in chunks {
+                embeddings
+                    .entry(chunk.item_id)
+                    .or_default()
+                    .push(embedding_from_bytes(&chunk.embedding));
+            }
+
+            for item in batch {
+                let embedding = embeddings
+                    .get(&item.id)
+                    .and_then(|e| mean_embedding(e.iter().map(|e| e.as_slice())));
+                if let Some(embedding) = embedding {
+                    centroids.add(&item.tags, &embedding);
+                }
+            }
+        }
+
+        Ok(centroids)
+    }
+
+    /// Suggest tags that an item does not have yet, and optionally add the most confident
+    /// ones to it.
+    pub async fn suggest_tags(
+        &self,
+        item_id: i64,
+        query: &TagSuggestionQuery,
+    ) -> Result<TagSuggestions, Report<TagError>> {
+        let item = db::items::lookup_by_id(&self.pg, item_id)
+            .await
+            .change_context(TagError::Db)?
+            .ok_or(TagError::ItemNotFound(item_id))
+            .into_report()?;
+
+        let candidates = db::tags::list_tags(&self.pg)
+            .await
+            .change_context(TagError::Db)?
+            .into_iter()
+            .filter(|tag| !item.tags.contains(&tag.id))
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            return Ok(TagSuggestions {
+                suggestions: Vec::new(),
+                applied: Vec::new(),
+            });
+        }
+
+        let bi_encoder_id = query
+            .bi_encoder_id
+            .or_else(|| self.loaded_bi_encoders.read().first().map(|m| m.id));
+
+        let mut centroids = TagCentroids::default();
+        let mut scores = Vec::new();
+        if let Some(model_id) = bi_encoder_id {
+            if let Some(embedding) = self.item_embedding(model_id, item_id).await? {
+                centroids = self.tag_centroids(model_id, item_id).await?;
+                scores.extend(
+                    centroids
+                        .score(&embedding)
+                        .into_iter()
+                        .map(|(tag, score)| (tag, score, SuggestionMethod::Centroid)),
+                );
+            }
+        }
+
+        // Tags without enough examples, or every tag if the item has no embeddings.
+        let cold_tags = candidates
+            .iter()
+            .filter(|tag| !centroids.has_examples(tag.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let model_id = query.model_id.or(self.tag_options.model_id);
+        if let Some(model_id) = model_id.filter(|_| !cold_tags.is_empty()) {
+            let model = self
+                .loaded_completion_models
+                .read()
+                .iter()
+                .find(|m| m.id == model_id)
+                .map(|m| m.model.clone())
+                .ok_or(TagError::ModelNotLoaded(model_id))
+                .into_report()?;
+
+            let text = db::items::get_items_text(&self.pg, &[item_id])
+                .await
+                .change_context(TagError::Db)?
+                .pop()
+                .map(|item| {
+                    let title = item.title.or(item.name).unwrap_or_default();
+                    let body = item
+                        .generated_summary
+                        .or(item.processed_content)
+                        .or(item.description)
+                        .unwrap_or_default();
+                    format!("{title}\n\n{body}").trim().to_string()
+                })
+                .unwrap_or_default();
+
+            if !text.is_empty() {
+                let classified = tokio::task::spawn_blocking(move || {
+                    classify(model.as_ref(), &cold_tags, &text)
+                })
+                .await
+                .into_report()
+                .change_context(TagError::Task)?
+                .change_context(TagError::Model)
+                .attach_printable_lazy(|| format!("Model {model_id}"))?;
+
+                scores.extend(
+                    classified
+                        .into_iter()
+                        .map(|(tag, score)| (tag, score, SuggestionMethod::ZeroShot)),
+                );
+            }
+        }
+
+        let mut suggestions = scores
+            .into_iter()
+            .filter_map(|(tag_id, confidence, method)| {
+                let tag = candidates.iter().find(|t| t.id == tag_id)?;
+                Some(TagSuggestion {
+                    tag_id,
+                    name: tag.name.clone(),
+                    confidence,
+                    method,
+                })
+            })
+            .collect::<Vec<_>>();
+        suggestions.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        suggestions.truncate(query.limit);
+
+        let applied = tags_to_apply(&suggestions, query.apply_threshold);
+
+        if !applied.is_empty() {
+            db::items::add_item_tags(&self.pg, item_id, &applied)
+                .await
+                .change_context(TagError::Db)?;
+
+            // The tags have been saved at this point, so a failure here is only logged.
+            if let Err(e) = self.update_text_index(item_id).await {
+                error!(error=?e, "Failed to update text index");
+            }
+        }
+
+        Ok(TagSuggestions {
+            suggestions,
+            applied,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        mean_embedding, parse_classification, select_examples, tags_to_apply, SuggestionMethod,
+        TagCentroids, TagSuggestion, MAX_TAG_EXAMPLES,
+    };
+    use crate::db::tags::{Tag, TaggedItem};
+
+    fn tag(id: i32, name: &str) -> Tag {
+        Tag {
+            id,
+            name: name.to_string(),
+            color: None,
+        }
+    }
+
+    #[test]
+    fn centroids() {
+        let mut centroids = TagCentroids::default();
+        for embedding in [[1.0, 0.1], [0.9, 0.0], [1.0, -0.1]] {
+            centroids.add(&[1], &embedding);
+        }
+        for embedding in [[0.0, 1.0], [0.1, 0.9], [-0.1, 1.0]] {
+            centroids.add(&[2], &embedding);
+        }
+        centroids.add(&[3], &[1.0, 0.0]);
+
+        assert!(centroids.has_examples(1));
+        assert!(!centroids.has_examples(3), "too few examples");
+
+        let mut scores = centroids.score(&[1.0, 0.0]);
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(scores.len(), 2);
+        assert_eq!(scores, vec![(1, 1.0), (2, 0.0)]);
+
+        // Closer to the tag than two of its examples are, but not the third
+        let scores = centroids.score(&[1.0, 0.12]);
+        let score = scores.iter().find(|(tag, _)| *tag == 1).unwrap().1;
+        assert!((score - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dissimilar_item_not_applied() {
+        let mut centroids = TagCentroids::default();
+        for embedding in [[1.0, 0.05, 0.0], [1.0, -0.05, 0.0], [1.0, 0.0, 0.05]] {
+            centroids.add(&[1], &embedding);
+        }
+
+        let suggest = |embedding: &[f32]| {
+            centroids
+                .score(embedding)
+                .into_iter()
+                .map(|(tag_id, confidence)| TagSuggestion {
+                    tag_id,
+                    name: "tag".to_string(),
+                    confidence,
+                    method: SuggestionMethod::Centroid,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The raw similarity of this item is over 0.8, but it is much further from the tag than
+        // any of its examples.
+        let dissimilar = suggest(&[1.0, 0.6, 0.3]);
+        assert_eq!(dissimilar[0].confidence, 0.0);
+        assert!(tags_to_apply(&dissimilar, Some(0.7)).is_empty());
+
+        let similar = suggest(&[1.0, 0.01, 0.01]);
+        assert_eq!(similar[0].confidence, 1.0);
+        assert_eq!(tags_to_apply(&similar, Some(0.7)), vec![1]);
+        assert!(tags_to_apply(&similar, None).is_empty());
+    }
+
+    #[test]
+    fn mean() {
+        let embeddings = [vec![2.0, 0.0], vec![0.0, 0.5], vec![0.0, 0.0]];
+        let mean = mean_embedding(embeddings.iter().map(|e| e.as_slice())).unwrap();
+        assert!((mean[0] - mean[1]).abs() < 1e-6);
+        assert!((mean[0] - 0.5f32.sqrt()).abs() < 1e-6);
+
+        let opposite = [vec![1.0, 0.0], vec![-1.0, 0.0]];
+        assert!(mean_embedding(opposite.iter().map(|e| e.as_slice())).is_none());
+    }
+
+    #[test]
+    fn examples() {
+        let items = (0..MAX_TAG_EXAMPLES as i64 + 10)
+            .map(|id| TaggedItem {
+                id,
+                tags: if id % 2 == 0 { vec![1] } else { vec![1, 2] },
+            })
+            .collect();
+
+        let selected = select_examples(items);
+        let count = |tag| selected.iter().filter(|i| i.tags.contains(&tag)).count();
+        assert_eq!(count(2), (MAX_TAG_EXAMPLES + 10) / 2);
+        assert!(
+            count(1) > MAX_TAG_EXAMPLES,
+            "items are kept for their other tags"
+        );
+        assert!(selected
+            .iter()
+            .all(|i| i.tags.contains(&2) || i.id < MAX_TAG_EXAMPLES as i64));
+    }
+
+    #[test]
+    fn classification() {
+        let tags = [
+            tag(1, "Rust"),
+            tag(2, "Cooking"),
+            tag(3, "Machine Learning"),
+        ];
+        let output = "- rust: 95
+Machine learning: 40%
+\"Cooking\": maybe
+Gardening: 80
+Rust: 10";
+
+        assert_eq!(
+            parse_classification(output, &tags),
+            vec![(1, 0.95), (3, 0.4)]
+        );
+    }
+}
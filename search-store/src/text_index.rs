@@ -0,0 +1,509 @@
+//! A BM25 full-text index over the text and metadata of items that are ready to search.
+//! Hidden items are indexed too, and left out of searches by a filter.
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use error_stack::{IntoReport, Report, ResultExt};
+use parking_lot::Mutex;
+use roaring::RoaringTreemap;
+use tantivy::{
+    collector::{Count, FilterCollector, TopDocs},
+    directory::MmapDirectory,
+    query::QueryParser,
+    schema::{Field, Schema, FAST, INDEXED, STORED, TEXT},
+    Document, Index, IndexReader, IndexSettings, IndexWriter, ReloadPolicy, SnippetGenerator,
+    TantivyError, Term,
+};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        self,
+        items::{ItemStatus, ItemText},
+    },
+    SearchStore,
+};
+
+/// The memory to give the index writer.
+const WRITER_MEMORY: usize = 50_000_000;
+/// The maximum length of a snippet
+const SNIPPET_CHARS: usize = 200;
+/// The number of items to read from the database at once when syncing the index.
+const REINDEX_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Error)]
+pub enum TextIndexError {
+    #[error("Database error")]
+    Db,
+    #[error("Failed to open index")]
+    Open,
+    #[error("Failed to update index")]
+    Write,
+    #[error("Invalid query")]
+    InvalidQuery,
+    #[error("Search failed")]
+    Search,
+    #[error("Background task failed")]
+    Task,
+}
+
+struct Fields {
+    id: Field,
+    /// The time the item was last updated, to find stale entries
+    updated: Field,
+    title: Field,
+    author: Field,
+    description: Field,
+    tags: Field,
+    content: Field,
+}
+
+impl Fields {
+    fn build_schema() -> (Schema, Fields) {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            id: builder.add_i64_field("id", INDEXED | STORED | FAST),
+            updated: builder.add_i64_field("updated", FAST),
+            title: builder.add_text_field("title", TEXT | STORED),
+            author: builder.add_text_field("author", TEXT | STORED),
+            description: builder.add_text_field("description", TEXT | STORED),
+            tags: builder.add_text_field("tags", TEXT),
+            content: builder.add_text_field("content", TEXT | STORED),
+        };
+
+        (builder.build(), fields)
+    }
+}
+
+/// The version of an item in the index. Postgres stores times to the microsecond, so this
+/// matches exactly when the item has not changed.
+fn item_version(updated_at: OffsetDateTime) -> i64 {
+    (updated_at.unix_timestamp_nanos() / 1000) as i64
+}
+
+#[derive(Debug)]
+pub struct TextMatch {
+    pub item_id: i64,
+    pub score: f32,
+    pub title: Option<String>,
+    /// An excerpt of the content with the matching terms wrapped in `<b>` tags. The rest of
+    /// the text is HTML-escaped.
+    pub snippet: String,
+    /// The same excerpt as plain text
+    pub snippet_text: String,
+}
+
+#[derive(Debug)]
+pub struct TextSearchResults {
+    /// The total number of matching items
+    pub count: usize,
+    pub matches: Vec<TextMatch>,
+}
+
+pub struct TextIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+}
+
+impl TextIndex {
+    /// Open the index in `dir`, creating it if it does not exist. An index with an older
+    /// schema is replaced with an empty one, which is then filled by [SearchStore::sync_text_index].
+    pub fn open(dir: &Path) -> Result<Self, Report<TextIndexError>> {
+        let (schema, fields) = Fields::build_schema();
+        let index = match Index::open_or_create(Self::open_directory(dir)?, schema.clone()) {
+            Err(TantivyError::SchemaError(e)) => {
+                warn!(error = %e, "Text index schema changed, recreating the index");
+                std::fs::remove_dir_all(dir)
+                    .into_report()
+                    .change_context(TextIndexError::Open)
+                    .attach_printable_lazy(|| dir.display().to_string())?;
+                Index::create(Self::open_directory(dir)?, schema, IndexSettings::default())
+            }
+            result => result,
+        }
+        .into_report()
+        .change_context(TextIndexError::Open)?;
+
+        Self::from_index(index, fields)
+    }
+
+    fn open_directory(dir: &Path) -> Result<MmapDirectory, Report<TextIndexError>> {
+        std::fs::create_dir_all(dir)
+            .into_report()
+            .change_context(TextIndexError::Open)
+            .attach_printable_lazy(|| dir.display().to_string())?;
+
+        MmapDirectory::open(dir)
+            .into_report()
+            .change_context(TextIndexError::Open)
+            .attach_printable_lazy(|| dir.display().to_string())
+    }
+
+    #[cfg(test)]
+    fn in_memory() -> Result<Self, Report<TextIndexError>> {
+        let (schema, fields) = Fields::build_schema();
+        Self::from_index(Index::create_in_ram(schema), fields)
+    }
+
+    fn from_index(index: Index, fields: Fields) -> Result<Self, Report<TextIndexError>> {
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .into_report()
+            .change_context(TextIndexError::Open)?;
+
+        let writer = index
+            .writer(WRITER_MEMORY)
+            .into_report()
+            .change_context(TextIndexError::Open)?;
+
+        Ok(Self {
+            index,
+            reader,
+            writer: Mutex::new(writer),
+            fields,
+        })
+    }
+
+    /// The number of items in the index
+    pub fn num_items(&self) -> u64 {
+        self.reader.searcher().num_docs()
+    }
+
+    /// The version of each item in the index
+    pub fn item_versions(&self) -> Result<HashMap<i64, i64>, Report<TextIndexError>> {
+        let searcher = self.reader.searcher();
+        let mut versions = HashMap::with_capacity(searcher.num_docs() as usize);
+
+        for segment in searcher.segment_readers() {
+            let fast_fields = segment.fast_fields();
+            let ids = fast_fields
+                .i64(self.fields.id)
+                .into_report()
+                .change_context(TextIndexError::Search)?;
+            let updated = fast_fields
+                .i64(self.fields.updated)
+                .into_report()
+                .change_context(TextIndexError::Search)?;
+
+            for doc in segment.doc_ids_alive() {
+                versions.insert(ids.get_val(doc), updated.get_val(doc));
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn document(&self, item: &ItemText) -> Document {
+        let mut doc = Document::default();
+        doc.add_i64(self.fields.id, item.id);
+        doc.add_i64(self.fields.updated, item_version(item.updated_at));
+
+        let text_fields = [
+            (self.fields.title, &item.title),
+            (self.fields.title, &item.name),
+            (self.fields.author, &item.author),
+            (self.fields.description, &item.description),
+            (self.fields.content, &item.processed_content),
+        ];
+        for (field, value) in text_fields {
+            if let Some(value) = value {
+                doc.add_text(field, value);
+            }
+        }
+
+        for tag in &item.tags {
+            doc.add_text(self.fields.tags, tag);
+        }
+
+        doc
+    }
+
+    /// Add or replace items in the index, and remove those that are not ready to search.
+    pub fn update_items(
+        &self,
+        items: &[ItemText],
+        removed: &[i64],
+    ) -> Result<(), Report<TextIndexError>> {
+        let mut writer = self.writer.lock();
+
+        for id in removed {
+            writer.delete_term(Term::from_field_i64(self.fields.id, *id));
+        }
+
+        for item in items {
+            writer.delete_term(Term::from_field_i64(self.fields.id, item.id));
+            if item.status == ItemStatus::Ready {
+                writer
+                    .add_document(self.document(item))
+                    .into_report()
+                    .change_context(TextIndexError::Write)?;
+            }
+        }
+
+        writer
+            .commit()
+            .into_report()
+            .change_context(TextIndexError::Write)?;
+        self.reader
+            .reload()
+            .into_report()
+            .change_context(TextIndexError::Write)
+    }
+
+    /// Search for items matching `query`, which uses Tantivy's query syntax. Matches in the
+    /// title count for more than matches elsewhere. If `filter` is given, only the items in it
+    /// are returned.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        filter: Option<Arc<RoaringTreemap>>,
+    ) -> Result<TextSearchResults, Report<TextIndexError>> {
+        let f = &self.fields;
+        let mut parser = QueryParser::for_index(
+            &self.index,
+            vec![f.title, f.author, f.description, f.tags, f.content],
+        );
+        parser.set_field_boost(f.title, 2.0);
+        parser.set_field_boost(f.tags, 1.5);
+
+        let query = parser
+            .parse_query(query)
+            .into_report()
+            .change_context(TextIndexError::InvalidQuery)?;
+
+        let searcher = self.reader.searcher();
+        let collector = (TopDocs::with_limit(limit), Count);
+        let (top_docs, count) = match filter {
+            Some(filter) => searcher.search(
+                &query,
+                &FilterCollector::new(f.id, move |id: i64| filter.contains(id as u64), collector),
+            ),
+            None => searcher.search(&query, &collector),
+        }
+        .into_report()
+        .change_context(TextIndexError::Search)?;
+
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, f.content)
+            .into_report()
+            .change_context(TextIndexError::Search)?;
+        snippets.set_max_num_chars(SNIPPET_CHARS);
+
+        let matches = top_docs
+            .into_iter()
+            .map(|(score, address)| {
+                let doc = searcher
+                    .doc(address)
+                    .into_report()
+                    .change_context(TextIndexError::Search)?;
+
+                let item_id = doc
+                    .get_first(f.id)
+                    .and_then(|v| v.as_i64())
+                    .ok_or(TextIndexError::Search)
+                    .into_report()
+                    .attach_printable("Document has no ID")?;
+
+                let snippet = snippets.snippet_from_doc(&doc);
+                Ok(TextMatch {
+                    item_id,
+                    score,
+                    title: doc
+                        .get_first(f.title)
+                        .and_then(|v| v.as_text())
+                        .map(String::from),
+                    snippet: snippet.to_html(),
+                    snippet_text: snippet.fragment().to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, Report<TextIndexError>>>()?;
+
+        Ok(TextSearchResults { count, matches })
+    }
+}
+
+impl SearchStore {
+    /// Bring the full-text index up to date with an item after it changes. The item is added
+    /// if it is ready, and removed otherwise.
+    pub async fn update_text_index(&self, item_id: i64) -> Result<(), Report<TextIndexError>> {
+        let items = db::items::get_items_text(&self.pg, &[item_id])
+            .await
+            .change_context(TextIndexError::Db)?;
+        let removed = if items.is_empty() {
+            vec![item_id]
+        } else {
+            Vec::new()
+        };
+
+        self.write_text_index(items, removed).await
+    }
+
+    /// Remove an item from the full-text index.
+    pub async fn remove_from_text_index(&self, item_id: i64) -> Result<(), Report<TextIndexError>> {
+        self.write_text_index(Vec::new(), vec![item_id]).await
+    }
+
+    /// Bring the full-text index up to date with the database, adding items that are missing
+    /// or have changed since they were indexed, and removing items that are no longer ready.
+    /// This catches up on changes made while the index was not being updated.
+    pub async fn sync_text_index(&self) -> Result<(), Report<TextIndexError>> {
+        // Read the index first so that an item updated in between is seen as stale, rather than
+        // missed.
+        let index = self.text_index.clone();
+        let indexed_versions = tokio::task::spawn_blocking(move || index.item_versions())
+            .await
+            .into_report()
+            .change_context(TextIndexError::Task)??;
+        let db_versions = db::items::list_searchable_items(&self.pg)
+            .await
+            .change_context(TextIndexError::Db)?;
+
+        let removed = indexed_versions
+            .keys()
+            .filter(|id| !db_versions.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            info!(count = removed.len(), "Removing items from text index");
+            self.write_text_index(Vec::new(), removed).await?;
+        }
+
+        let mut stale_items = db_versions
+            .into_iter()
+            .filter(|(id, updated_at)| indexed_versions.get(id) != Some(&item_version(*updated_at)))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        stale_items.sort_unstable();
+        if !stale_items.is_empty() {
+            info!(count = stale_items.len(), "Adding items to text index");
+        }
+
+        for batch in stale_items.chunks(REINDEX_BATCH_SIZE) {
+            let items = db::items::get_items_text(&self.pg, batch)
+                .await
+                .change_context(TextIndexError::Db)?;
+            self.write_text_index(items, Vec::new()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_text_index(
+        &self,
+        items: Vec<ItemText>,
+        removed: Vec<i64>,
+    ) -> Result<(), Report<TextIndexError>> {
+        // Every change to which items are searchable comes through here, so this is also
+        // where the cached default filter is cleared.
+        self.default_filter.clear();
+
+        let index = self.text_index.clone();
+        tokio::task::spawn_blocking(move || index.update_items(&items, &removed))
+            .await
+            .into_report()
+            .change_context(TextIndexError::Task)?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use roaring::RoaringTreemap;
+    use time::{Duration, OffsetDateTime};
+
+    use super::{item_version, TextIndex};
+    use crate::db::items::{ItemStatus, ItemText};
+
+    fn item(id: i64, title: &str, content: &str) -> ItemText {
+        ItemText {
+            id,
+            status: ItemStatus::Ready,
+            hidden: false,
+            name: None,
+            title: Some(title.to_string()),
+            author: Some("Someone".to_string()),
+            description: None,
+            processed_content: Some(content.to_string()),
+            generated_summary: None,
+            tags: vec!["reading".to_string()],
+            updated_at: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn search_and_update() {
+        let index = TextIndex::in_memory().unwrap();
+        index
+            .update_items(
+                &[
+                    item(1, "Rust ownership", "Each value in Rust has an owner."),
+                    item(
+                        2,
+                        "Gardening",
+                        "Tomatoes need plenty of sun. Rust can affect leaves.",
+                    ),
+                    item(3, "Cooking", "Tomatoes make a good sauce."),
+                ],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(index.num_items(), 3);
+
+        let results = index.search("rust", 10, None).unwrap();
+        assert_eq!(results.count, 2);
+        assert_eq!(results.matches[0].item_id, 1, "title matches rank first");
+        assert_eq!(results.matches[0].title.as_deref(), Some("Rust ownership"));
+        assert!(results.matches[1].snippet.contains("<b>Rust</b>"));
+
+        let results = index.search("tags:reading", 10, None).unwrap();
+        assert_eq!(results.count, 3);
+
+        let filter = Arc::new([2u64, 3].into_iter().collect::<RoaringTreemap>());
+        let results = index.search("rust", 10, Some(filter)).unwrap();
+        assert_eq!(results.count, 1);
+        assert_eq!(results.matches[0].item_id, 2);
+
+        // Removed items and items that are not ready drop out of the index.
+        let mut processing = item(2, "Gardening", "Tomatoes need plenty of sun.");
+        processing.status = ItemStatus::Processing;
+        index.update_items(&[processing], &[3]).unwrap();
+        assert_eq!(index.num_items(), 1);
+        assert_eq!(index.search("tomatoes", 10, None).unwrap().count, 0);
+    }
+
+    #[test]
+    fn item_versions() {
+        let index = TextIndex::in_memory().unwrap();
+        let mut updated = item(2, "Gardening", "Tomatoes need plenty of sun.");
+        updated.updated_at += Duration::microseconds(1500);
+        index
+            .update_items(&[item(1, "Rust", "Ownership"), updated], &[])
+            .unwrap();
+
+        let versions = index.item_versions().unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[&1], 0);
+        assert_eq!(versions[&2], 1500);
+
+        index.update_items(&[], &[1]).unwrap();
+        let versions = index.item_versions().unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(
+            versions[&2],
+            item_version(OffsetDateTime::UNIX_EPOCH) + 1500
+        );
+    }
+
+    #[test]
+    fn invalid_query() {
+        let index = TextIndex::in_memory().unwrap();
+        assert!(index.search("title:(unclosed", 10, None).is_err());
+    }
+}
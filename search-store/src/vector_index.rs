@@ -0,0 +1,635 @@
+//! Approximate nearest neighbor search over the chunk embeddings of each bi-encoder model.
+//!
+//! Each model has its own index, which is saved to disk and reconciled against the
+//! `item_chunks` table when it is loaded, so only items that changed while it was offline
+//! need to be added again.
+
+pub mod hnsw;
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use error_stack::{IntoReport, Report, ResultExt};
+use itertools::Itertools;
+use parking_lot::RwLock;
+use roaring::RoaringTreemap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use self::hnsw::{Hnsw, HnswParams, Metric};
+use crate::{
+    db::{self, item_chunks::embedding_from_bytes},
+    SearchStore,
+};
+
+/// The number of items to read from the database at once when loading an index.
+const LOAD_BATCH_SIZE: usize = 100;
+/// When a filter allows at most this many items, score their chunks directly instead of
+/// searching the graph.
+const EXACT_SEARCH_MAX_ITEMS: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum VectorIndexError {
+    #[error("Database error")]
+    Db,
+    #[error("Failed to read index")]
+    Read,
+    #[error("Failed to write index")]
+    Write,
+    #[error("Background task failed")]
+    Task,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VectorIndexOptions {
+    pub metric: Metric,
+    pub hnsw: HnswParams,
+}
+
+/// A chunk of an item, as stored in `item_chunks`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub item_id: i64,
+    pub sequence_num: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VectorMatch {
+    pub chunk: ChunkKey,
+    pub score: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedItem {
+    version: i32,
+    nodes: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexData {
+    graph: Hnsw,
+    /// The chunk for each node in the graph
+    chunks: Vec<ChunkKey>,
+    items: HashMap<i64, IndexedItem>,
+    /// Incremented each time the graph is replaced, which renumbers its nodes.
+    #[serde(skip)]
+    graph_generation: u64,
+}
+
+/// A compacted copy of a graph, built while the original can still change.
+struct Compaction {
+    graph: Hnsw,
+    /// The old ID of each node in `graph`
+    old_ids: Vec<u32>,
+    /// The number of nodes in the original graph when the copy was made
+    num_nodes: usize,
+    graph_generation: u64,
+}
+
+impl IndexData {
+    fn new(options: &VectorIndexOptions) -> Self {
+        Self {
+            graph: Hnsw::new(options.metric, options.hnsw.clone()),
+            chunks: Vec::new(),
+            items: HashMap::new(),
+            graph_generation: 0,
+        }
+    }
+
+    /// Remove an item's chunks, returning false if the item was not in the index.
+    fn remove_item(&mut self, item_id: i64) -> bool {
+        let Some(item) = self.items.remove(&item_id) else {
+            return false;
+        };
+
+        for node in item.nodes {
+            self.graph.delete(node);
+        }
+        true
+    }
+
+    /// Whether deleted nodes make up enough of the graph that it should be rebuilt.
+    fn needs_compaction(&self) -> bool {
+        self.graph.num_deleted() >= 1000 && self.graph.num_deleted() >= self.graph.len()
+    }
+
+    /// Build a copy of the graph without its deleted nodes. This only needs read access, so
+    /// searches can continue while it runs.
+    fn start_compaction(&self) -> Compaction {
+        let (graph, old_ids) = self.graph.compact();
+        Compaction {
+            graph,
+            old_ids,
+            num_nodes: self.graph.num_nodes(),
+            graph_generation: self.graph_generation,
+        }
+    }
+
+    /// Replace the graph with a compacted copy, after applying the nodes that were added or
+    /// deleted since the copy was made. Returns false if the graph was already replaced.
+    fn finish_compaction(&mut self, compaction: Compaction) -> bool {
+        if compaction.graph_generation != self.graph_generation {
+            return false;
+        }
+
+        let Compaction {
+            mut graph,
+            old_ids,
+            num_nodes,
+            ..
+        } = compaction;
+
+        let mut new_ids = HashMap::with_capacity(old_ids.len());
+        let mut chunks = Vec::with_capacity(old_ids.len());
+        for (new_id, &old_id) in old_ids.iter().enumerate() {
+            if self.graph.is_deleted(old_id) {
+                graph.delete(new_id as u32);
+            }
+            new_ids.insert(old_id, new_id as u32);
+            chunks.push(self.chunks[old_id as usize]);
+        }
+
+        for old_id in num_nodes as u32..self.graph.num_nodes() as u32 {
+            if !self.graph.is_deleted(old_id) {
+                new_ids.insert(old_id, graph.insert(self.graph.vector(old_id)));
+                chunks.push(self.chunks[old_id as usize]);
+            }
+        }
+
+        // Items only refer to nodes that have not been deleted, which all have a new ID.
+        for item in self.items.values_mut() {
+            for node in item.nodes.iter_mut() {
+                *node = new_ids[&*node];
+            }
+        }
+
+        self.graph = graph;
+        self.chunks = chunks;
+        self.graph_generation += 1;
+        true
+    }
+}
+
+pub struct VectorIndex {
+    data: RwLock<IndexData>,
+    /// Set when the index has changed since it was last saved.
+    dirty: AtomicBool,
+}
+
+impl VectorIndex {
+    fn new(options: &VectorIndexOptions) -> Self {
+        Self {
+            data: RwLock::new(IndexData::new(options)),
+            dirty: AtomicBool::new(true),
+        }
+    }
+
+    /// Load an index from disk, or create an empty one if there is no usable saved index.
+    fn load_or_create(path: &Path, options: &VectorIndexOptions) -> Self {
+        let data = match Self::read(path) {
+            Ok(Some(data)) if data.graph.metric() == options.metric => data,
+            Ok(Some(_)) => {
+                info!(path=%path.display(), "Rebuilding index with new metric");
+                return Self::new(options);
+            }
+            Ok(None) => return Self::new(options),
+            Err(e) => {
+                warn!(error=?e, "Failed to read index, rebuilding it");
+                return Self::new(options);
+            }
+        };
+
+        Self {
+            data: RwLock::new(data),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Option<IndexData>, Report<VectorIndexError>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).into_report().change_context(VectorIndexError::Read),
+        };
+
+        bincode::deserialize_from(BufReader::new(file))
+            .into_report()
+            .change_context(VectorIndexError::Read)
+            .attach_printable_lazy(|| path.display().to_string())
+            .map(Some)
+    }
+
+    /// Write the index to disk if it has changed since it was last saved.
+    fn save(&self, path: &Path) -> Result<(), Report<VectorIndexError>> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let result = self.write(path);
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Report<VectorIndexError>> {
+        // Write to a temporary file first so that a crash never leaves a partial index.
+        let temp_path = path.with_extension("tmp");
+        let file = File::create(&temp_path)
+            .into_report()
+            .change_context(VectorIndexError::Write)
+            .attach_printable_lazy(|| temp_path.display().to_string())?;
+
+        bincode::serialize_into(BufWriter::new(file), &*self.data.read())
+            .into_report()
+            .change_context(VectorIndexError::Write)?;
+
+        std::fs::rename(&temp_path, path)
+            .into_report()
+            .change_context(VectorIndexError::Write)
+            .attach_printable_lazy(|| path.display().to_string())
+    }
+
+    /// The version of each item in the index
+    pub fn item_versions(&self) -> HashMap<i64, i32> {
+        self.data
+            .read()
+            .items
+            .iter()
+            .map(|(id, item)| (*id, item.version))
+            .collect()
+    }
+
+    /// Replace the chunks for an item. `embeddings` must be in sequence order. Updates for a
+    /// version older than the one already indexed are ignored.
+    pub fn set_item<'a>(
+        &self,
+        item_id: i64,
+        version: i32,
+        embeddings: impl IntoIterator<Item = &'a [f32]>,
+    ) {
+        let mut data = self.data.write();
+        if data
+            .items
+            .get(&item_id)
+            .map(|item| item.version > version)
+            .unwrap_or(false)
+        {
+            return;
+        }
+
+        data.remove_item(item_id);
+
+        let nodes = embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(sequence_num, embedding)| {
+                let node = data.graph.insert(embedding);
+                data.chunks.push(ChunkKey {
+                    item_id,
+                    sequence_num: sequence_num as i32,
+                });
+                node
+            })
+            .collect();
+
+        data.items.insert(item_id, IndexedItem { version, nodes });
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    pub fn remove_item(&self, item_id: i64) {
+        if self.data.write().remove_item(item_id) {
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Rebuild the graph once deleted nodes make up most of it. The new graph is built under
+    /// a read lock and swapped in afterwards, so searches are not blocked by the rebuild.
+    fn compact_if_needed(&self) {
+        let compaction = {
+            let data = self.data.read();
+            if !data.needs_compaction() {
+                return;
+            }
+            data.start_compaction()
+        };
+
+        if self.data.write().finish_compaction(compaction) {
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Find the `k` chunks most similar to `query`, only looking at items in `filter` if it
+    /// is given.
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: Option<&RoaringTreemap>,
+    ) -> Vec<VectorMatch> {
+        let data = self.data.read();
+        let results = match filter {
+            None => data.graph.search(query, k),
+            Some(items) if items.len() <= EXACT_SEARCH_MAX_ITEMS => {
+                let nodes = items
+                    .iter()
+                    .filter_map(|id| data.items.get(&(id as i64)))
+                    .flat_map(|item| item.nodes.iter().copied());
+                data.graph.search_exact(query, k, nodes)
+            }
+            Some(items) => data.graph.search_filtered(query, k, |node| {
+                items.contains(data.chunks[node as usize].item_id as u64)
+            }),
+        };
+
+        results
+            .into_iter()
+            .map(|(node, score)| VectorMatch {
+                chunk: data.chunks[node as usize],
+                score,
+            })
+            .collect()
+    }
+}
+
+/// The vector indexes for all loaded bi-encoder models.
+pub struct VectorIndexes {
+    location: PathBuf,
+    pub options: VectorIndexOptions,
+    indexes: RwLock<HashMap<i32, Arc<VectorIndex>>>,
+}
+
+impl VectorIndexes {
+    pub fn new(location: PathBuf, options: VectorIndexOptions) -> Self {
+        Self {
+            location,
+            options,
+            indexes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn path(&self, model_id: i32) -> PathBuf {
+        self.location.join(format!("vectors-{model_id}.bin"))
+    }
+
+    pub fn get(&self, model_id: i32) -> Option<Arc<VectorIndex>> {
+        self.indexes.read().get(&model_id).cloned()
+    }
+
+    /// Remove an item from every index.
+    pub fn remove_item(&self, item_id: i64) {
+        for index in self.indexes.read().values() {
+            index.remove_item(item_id);
+        }
+    }
+}
+
+impl SearchStore {
+    /// Load the vector index for a bi-encoder model and bring it up to date with the chunks
+    /// in the database.
+    pub async fn load_vector_index(&self, model_id: i32) -> Result<(), Report<VectorIndexError>> {
+        if self.vector_indexes.get(model_id).is_some() {
+            return Ok(());
+        }
+
+        let path = self.vector_indexes.path(model_id);
+        let options = self.vector_indexes.options.clone();
+        let index = tokio::task::spawn_blocking(move || {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .into_report()
+                    .change_context(VectorIndexError::Write)?;
+            }
+
+            Ok::<_, Report<VectorIndexError>>(Arc::new(VectorIndex::load_or_create(
+                &path, &options,
+            )))
+        })
+        .await
+        .into_report()
+        .change_context(VectorIndexError::Task)??;
+
+        // Register the index first so that chunks written while it loads are added to it too.
+        // If another call loaded the index in the meantime, that one is kept, since it may
+        // already have received writes.
+        match self.vector_indexes.indexes.write().entry(model_id) {
+            Entry::Occupied(_) => return Ok(()),
+            Entry::Vacant(entry) => {
+                entry.insert(index.clone());
+            }
+        }
+
+        if let Err(e) = self.sync_vector_index(model_id, &index).await {
+            let mut indexes = self.vector_indexes.indexes.write();
+            if let Entry::Occupied(entry) = indexes.entry(model_id) {
+                if Arc::ptr_eq(entry.get(), &index) {
+                    entry.remove();
+                }
+            }
+            return Err(e);
+        }
+
+        self.save_vector_indexes().await
+    }
+
+    /// Add and remove items so that the index matches the chunks in the database.
+    async fn sync_vector_index(
+        &self,
+        model_id: i32,
+        index: &Arc<VectorIndex>,
+    ) -> Result<(), Report<VectorIndexError>> {
+        // Read the index's items before the database's, so that an item added in between is
+        // not mistaken for one that was deleted.
+        let indexed_versions = index.item_versions();
+        let db_versions = db::item_chunks::get_item_versions(&self.pg, model_id)
+            .await
+            .change_context(VectorIndexError::Db)?;
+
+        for item_id in indexed_versions.keys() {
+            if !db_versions.contains_key(item_id) {
+                index.remove_item(*item_id);
+            }
+        }
+
+        let stale_items = db_versions
+            .iter()
+            .filter(|(item_id, version)| indexed_versions.get(item_id) != Some(version))
+            .map(|(item_id, _)| *item_id)
+            .collect::<Vec<_>>();
+
+        if !stale_items.is_empty() {
+            info!(%model_id, count=%stale_items.len(), "Adding items to vector index");
+        }
+
+        for batch in stale_items.chunks(LOAD_BATCH_SIZE) {
+            let chunks = db::item_chunks::get_item_embeddings(&self.pg, model_id, batch)
+                .await
+                .change_context(VectorIndexError::Db)?;
+
+            let index = index.clone();
+            tokio::task::spawn_blocking(move || {
+                for (item_id, item_chunks) in &chunks.into_iter().group_by(|c| c.item_id) {
+                    let item_chunks = item_chunks.collect::<Vec<_>>();
+                    let embeddings = item_chunks
+                        .iter()
+                        .map(|c| embedding_from_bytes(&c.embedding))
+                        .collect::<Vec<_>>();
+                    index.set_item(
+                        item_id,
+                        item_chunks[0].item_version,
+                        embeddings.iter().map(|e| e.as_slice()),
+                    );
+                }
+            })
+            .await
+            .into_report()
+            .change_context(VectorIndexError::Task)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the graphs of any vector indexes that are mostly deleted nodes.
+    pub async fn compact_vector_indexes(&self) -> Result<(), Report<VectorIndexError>> {
+        let indexes = self
+            .vector_indexes
+            .indexes
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        tokio::task::spawn_blocking(move || {
+            for index in indexes {
+                index.compact_if_needed();
+            }
+        })
+        .await
+        .into_report()
+        .change_context(VectorIndexError::Task)
+    }
+
+    /// Save any vector indexes that have changed.
+    pub async fn save_vector_indexes(&self) -> Result<(), Report<VectorIndexError>> {
+        let indexes = self
+            .vector_indexes
+            .indexes
+            .read()
+            .iter()
+            .map(|(model_id, index)| (self.vector_indexes.path(*model_id), index.clone()))
+            .collect::<Vec<_>>();
+
+        tokio::task::spawn_blocking(move || {
+            for (path, index) in indexes {
+                index.save(&path)?;
+            }
+
+            Ok::<_, Report<VectorIndexError>>(())
+        })
+        .await
+        .into_report()
+        .change_context(VectorIndexError::Task)?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use roaring::RoaringTreemap;
+
+    use super::{VectorIndex, VectorIndexOptions};
+
+    #[test]
+    fn replace_item_chunks() {
+        let index = VectorIndex::new(&VectorIndexOptions::default());
+        index.set_item(1, 1, [[1.0, 0.0].as_slice(), &[0.0, 1.0]]);
+        index.set_item(2, 1, [[0.7, 0.7].as_slice()]);
+
+        let results = index.search(&[0.0, 1.0], 1, None);
+        assert_eq!(results[0].chunk.item_id, 1);
+        assert_eq!(results[0].chunk.sequence_num, 1);
+
+        let filter = [2u64].into_iter().collect::<RoaringTreemap>();
+        let results = index.search(&[0.0, 1.0], 3, Some(&filter));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].chunk.item_id, 2);
+
+        index.set_item(1, 2, [[-1.0, 0.0].as_slice()]);
+        let results = index.search(&[0.0, 1.0], 3, None);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].chunk.item_id, 2);
+
+        // Older versions are ignored
+        index.set_item(1, 1, [[0.0, 1.0].as_slice()]);
+        assert_eq!(index.item_versions()[&1], 2);
+
+        index.remove_item(2);
+        let results = index.search(&[0.0, 1.0], 3, None);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].chunk.item_id, 1);
+    }
+
+    #[test]
+    fn compact_with_concurrent_changes() {
+        let index = VectorIndex::new(&VectorIndexOptions::default());
+        for item_id in 0..20 {
+            let angle = item_id as f32 / 10.0;
+            index.set_item(item_id, 1, [[angle.cos(), angle.sin()].as_slice()]);
+        }
+        for item_id in 0..10 {
+            index.remove_item(item_id);
+        }
+
+        let compaction = index.data.read().start_compaction();
+
+        // Changes made while the compacted graph is being built
+        index.remove_item(10);
+        index.set_item(11, 2, [[0.0, -1.0].as_slice(), &[-1.0, 0.0]]);
+        index.set_item(30, 1, [[0.0, 1.0].as_slice()]);
+
+        let mut data = index.data.write();
+        assert!(data.finish_compaction(compaction));
+        assert_eq!(data.graph.len(), 11);
+        assert_eq!(data.graph.num_deleted(), 2);
+        drop(data);
+
+        let results = index.search(&[0.0, 1.0], 30, None);
+        assert_eq!(results.len(), 11);
+        assert_eq!(results[0].chunk.item_id, 30);
+        assert!(results.iter().all(|m| m.chunk.item_id >= 11));
+
+        let results = index.search(&[0.0, -1.0], 1, None);
+        assert_eq!(results[0].chunk.item_id, 11);
+        assert_eq!(results[0].chunk.sequence_num, 0);
+
+        // A compaction of a graph that has since been replaced is discarded.
+        let stale = index.data.read().start_compaction();
+        let current = index.data.read().start_compaction();
+        assert!(index.data.write().finish_compaction(current));
+        assert!(!index.data.write().finish_compaction(stale));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        let options = VectorIndexOptions::default();
+
+        let index = VectorIndex::new(&options);
+        index.set_item(5, 3, [[0.5, 0.5].as_slice()]);
+        index.save(&path).unwrap();
+        assert!(!index.dirty.load(std::sync::atomic::Ordering::Acquire));
+
+        let loaded = VectorIndex::load_or_create(&path, &options);
+        assert_eq!(loaded.item_versions()[&5], 3);
+        assert_eq!(loaded.search(&[1.0, 1.0], 1, None)[0].chunk.item_id, 5);
+    }
+}
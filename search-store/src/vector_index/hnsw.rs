@@ -0,0 +1,581 @@
+//! A Hierarchical Navigable Small World graph for approximate nearest neighbor search, as
+//! described in <https://arxiv.org/abs/1603.09320>.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use ahash::AHashSet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// The maximum level of any node, to keep a bad random draw from creating a very tall graph.
+const MAX_LEVEL: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Vectors are normalized when added, so that the dot product is the cosine similarity.
+    #[default]
+    Cosine,
+    DotProduct,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HnswParams {
+    /// The number of neighbors for each node. The bottom layer allows twice as many.
+    pub m: usize,
+    /// How many candidates to consider when inserting a node
+    pub ef_construction: usize,
+    /// How many candidates to consider when searching
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Node {
+    vector: Vec<f32>,
+    /// The neighbors of the node at each level it appears in
+    neighbors: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Candidate {
+    distance: f32,
+    id: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Hnsw {
+    metric: Metric,
+    params: HnswParams,
+    nodes: Vec<Node>,
+    entry_point: Option<u32>,
+    num_deleted: usize,
+    /// Chooses the level of each new node
+    #[serde(skip, default = "StdRng::from_entropy")]
+    rng: StdRng,
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl Hnsw {
+    pub fn new(metric: Metric, params: HnswParams) -> Self {
+        Self::with_rng(metric, params, StdRng::from_entropy())
+    }
+
+    /// Create a graph that uses `rng` to choose node levels, so that the graph built from a
+    /// sequence of inserts is reproducible.
+    pub fn with_rng(metric: Metric, params: HnswParams, rng: StdRng) -> Self {
+        Self {
+            metric,
+            params,
+            nodes: Vec::new(),
+            entry_point: None,
+            num_deleted: 0,
+            rng,
+        }
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// The number of nodes that have not been deleted
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.num_deleted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_deleted(&self) -> usize {
+        self.num_deleted
+    }
+
+    /// The number of nodes, including deleted ones. New nodes get the next ID after the last.
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_deleted(&self, id: u32) -> bool {
+        self.nodes[id as usize].deleted
+    }
+
+    pub fn vector(&self, id: u32) -> &[f32] {
+        &self.nodes[id as usize].vector
+    }
+
+    fn prepare(&self, vector: &[f32]) -> Vec<f32> {
+        match self.metric {
+            Metric::DotProduct => vector.to_vec(),
+            Metric::Cosine => {
+                let norm = dot(vector, vector).sqrt();
+                if norm > 0.0 {
+                    vector.iter().map(|v| v / norm).collect()
+                } else {
+                    vector.to_vec()
+                }
+            }
+        }
+    }
+
+    /// Smaller distances are closer. For both metrics this is the negated dot product, since
+    /// cosine vectors are already normalized.
+    fn distance(&self, query: &[f32], id: u32) -> f32 {
+        -dot(query, &self.nodes[id as usize].vector)
+    }
+
+    fn max_neighbors(&self, level: usize) -> usize {
+        if level == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        let scale = 1.0 / (self.params.m.max(2) as f64).ln();
+        let uniform = 1.0 - self.rng.gen::<f64>();
+        ((-uniform.ln() * scale) as usize).min(MAX_LEVEL)
+    }
+
+    /// Search a single layer of the graph, returning up to `ef` of the closest nodes that pass
+    /// `accept`, sorted by distance. Rejected nodes are still used to navigate the graph.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[Candidate],
+        ef: usize,
+        level: usize,
+        accept: impl Fn(u32) -> bool,
+    ) -> Vec<Candidate> {
+        let mut visited = entry_points.iter().map(|c| c.id).collect::<AHashSet<_>>();
+        let mut candidates = entry_points
+            .iter()
+            .copied()
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+        let mut results = entry_points
+            .iter()
+            .copied()
+            .filter(|c| accept(c.id))
+            .collect::<BinaryHeap<_>>();
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            let furthest = results.peek().map(|c| c.distance).unwrap_or(f32::INFINITY);
+            if candidate.distance > furthest && results.len() >= ef {
+                break;
+            }
+
+            for &neighbor in &self.nodes[candidate.id as usize].neighbors[level] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let distance = self.distance(query, neighbor);
+                let furthest = results.peek().map(|c| c.distance).unwrap_or(f32::INFINITY);
+                if results.len() < ef || distance < furthest {
+                    let c = Candidate {
+                        distance,
+                        id: neighbor,
+                    };
+                    candidates.push(Reverse(c));
+                    if accept(neighbor) {
+                        results.push(c);
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// Choose neighbors from candidates sorted by distance, preferring candidates that are not
+    /// already close to another chosen neighbor so that the graph stays well connected.
+    fn select_neighbors(&self, candidates: &[Candidate], max: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(max);
+        let mut skipped = Vec::new();
+
+        for candidate in candidates {
+            if selected.len() >= max {
+                break;
+            }
+
+            let vector = &self.nodes[candidate.id as usize].vector;
+            let diverse = selected
+                .iter()
+                .all(|&s| self.distance(vector, s) > candidate.distance);
+            if diverse {
+                selected.push(candidate.id);
+            } else {
+                skipped.push(candidate.id);
+            }
+        }
+
+        let remaining = max.saturating_sub(selected.len());
+        selected.extend(skipped.into_iter().take(remaining));
+        selected
+    }
+
+    fn connect(&mut self, from: u32, to: u32, level: usize) {
+        let max = self.max_neighbors(level);
+        let neighbors = &mut self.nodes[from as usize].neighbors[level];
+        neighbors.push(to);
+        if neighbors.len() <= max {
+            return;
+        }
+
+        let vector = &self.nodes[from as usize].vector;
+        let mut candidates = self.nodes[from as usize].neighbors[level]
+            .iter()
+            .map(|&id| Candidate {
+                distance: self.distance(vector, id),
+                id,
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+
+        let pruned = self.select_neighbors(&candidates, max);
+        self.nodes[from as usize].neighbors[level] = pruned;
+    }
+
+    /// Add a vector to the graph and return its ID.
+    pub fn insert(&mut self, vector: &[f32]) -> u32 {
+        let vector = self.prepare(vector);
+        let level = self.random_level();
+        let id = self.nodes.len() as u32;
+
+        let Some(entry_point) = self.entry_point else {
+            self.nodes.push(Node {
+                vector,
+                neighbors: vec![Vec::new(); level + 1],
+                deleted: false,
+            });
+            self.entry_point = Some(id);
+            return id;
+        };
+
+        let top_level = self.nodes[entry_point as usize].neighbors.len() - 1;
+        let mut entry_points = vec![Candidate {
+            distance: self.distance(&vector, entry_point),
+            id: entry_point,
+        }];
+
+        for l in (level + 1..=top_level).rev() {
+            entry_points = self.search_layer(&vector, &entry_points, 1, l, |_| true);
+        }
+
+        let mut neighbors = vec![Vec::new(); level + 1];
+        for l in (0..=level.min(top_level)).rev() {
+            let candidates = self.search_layer(
+                &vector,
+                &entry_points,
+                self.params.ef_construction,
+                l,
+                |_| true,
+            );
+            neighbors[l] = self.select_neighbors(&candidates, self.max_neighbors(l));
+            entry_points = candidates;
+        }
+
+        self.nodes.push(Node {
+            vector,
+            neighbors: neighbors.clone(),
+            deleted: false,
+        });
+
+        for (l, level_neighbors) in neighbors.into_iter().enumerate() {
+            for neighbor in level_neighbors {
+                self.connect(neighbor, id, l);
+            }
+        }
+
+        if level > top_level {
+            self.entry_point = Some(id);
+        }
+
+        id
+    }
+
+    /// Mark a node as deleted. It stays in the graph to help navigation, but is no longer
+    /// returned from searches.
+    pub fn delete(&mut self, id: u32) {
+        let node = &mut self.nodes[id as usize];
+        if !node.deleted {
+            node.deleted = true;
+            self.num_deleted += 1;
+        }
+    }
+
+    /// Find the `k` nearest nodes to `query`, returning their IDs and similarity scores with
+    /// the highest score first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(u32, f32)> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// Find the `k` nearest nodes to `query` for which `filter` returns true. The search keeps
+    /// walking through nodes that fail the filter, so a restrictive filter makes it slower
+    /// but does not cut off the results.
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(u32) -> bool,
+    ) -> Vec<(u32, f32)> {
+        let Some(entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+
+        let query = self.prepare(query);
+        let top_level = self.nodes[entry_point as usize].neighbors.len() - 1;
+        let mut entry_points = vec![Candidate {
+            distance: self.distance(&query, entry_point),
+            id: entry_point,
+        }];
+
+        for l in (1..=top_level).rev() {
+            entry_points = self.search_layer(&query, &entry_points, 1, l, |_| true);
+        }
+
+        let ef = self.params.ef_search.max(k);
+        self.search_layer(&query, &entry_points, ef, 0, |id| {
+            !self.nodes[id as usize].deleted && filter(id)
+        })
+        .into_iter()
+        .take(k)
+        .map(|c| (c.id, -c.distance))
+        .collect()
+    }
+
+    /// Score every node in `ids` against `query` and return the `k` best. This is faster than
+    /// a filtered graph search when only a few nodes pass the filter.
+    pub fn search_exact(
+        &self,
+        query: &[f32],
+        k: usize,
+        ids: impl IntoIterator<Item = u32>,
+    ) -> Vec<(u32, f32)> {
+        let query = self.prepare(query);
+        let mut results = ids
+            .into_iter()
+            .filter(|&id| !self.nodes[id as usize].deleted)
+            .map(|id| Candidate {
+                distance: self.distance(&query, id),
+                id,
+            })
+            .collect::<Vec<_>>();
+        results.sort();
+
+        results
+            .into_iter()
+            .take(k)
+            .map(|c| (c.id, -c.distance))
+            .collect()
+    }
+
+    /// Build a new graph containing only the nodes that have not been deleted. Returns the
+    /// graph and a list mapping each new ID to the old one.
+    pub fn compact(&self) -> (Hnsw, Vec<u32>) {
+        let mut graph = Hnsw::with_rng(self.metric, self.params.clone(), self.rng.clone());
+        let mut old_ids = Vec::with_capacity(self.len());
+
+        for (id, node) in self.nodes.iter().enumerate() {
+            if !node.deleted {
+                graph.insert(&node.vector);
+                old_ids.push(id as u32);
+            }
+        }
+
+        (graph, old_ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{Hnsw, HnswParams, Metric};
+
+    /// Seeded so that the recall thresholds are checked against the same graph on every run.
+    fn seeded_rng() -> StdRng {
+        StdRng::seed_from_u64(0x5eed)
+    }
+
+    fn new_graph(metric: Metric) -> Hnsw {
+        Hnsw::with_rng(metric, HnswParams::default(), seeded_rng())
+    }
+
+    fn random_vectors(rng: &mut StdRng, count: usize, dims: usize) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|_| (0..dims).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect()
+    }
+
+    fn exact_nearest(metric: Metric, vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<u32> {
+        let score = |v: &[f32]| {
+            let d: f32 = v.iter().zip(query).map(|(a, b)| a * b).sum();
+            match metric {
+                Metric::DotProduct => d,
+                Metric::Cosine => d / v.iter().map(|x| x * x).sum::<f32>().sqrt(),
+            }
+        };
+
+        let mut scored = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i as u32, score(v)))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(i, _)| i).collect()
+    }
+
+    fn recall(metric: Metric) -> f32 {
+        let mut rng = seeded_rng();
+        let vectors = random_vectors(&mut rng, 1000, 16);
+        let mut graph = new_graph(metric);
+        for v in &vectors {
+            graph.insert(v);
+        }
+
+        let queries = random_vectors(&mut rng, 20, 16);
+        let found = queries
+            .iter()
+            .map(|q| {
+                let expected = exact_nearest(metric, &vectors, q, 10);
+                graph
+                    .search(q, 10)
+                    .into_iter()
+                    .filter(|(id, _)| expected.contains(id))
+                    .count()
+            })
+            .sum::<usize>();
+
+        found as f32 / 200.0
+    }
+
+    #[test]
+    fn cosine_recall() {
+        let r = recall(Metric::Cosine);
+        assert!(r > 0.9, "recall {r}");
+    }
+
+    #[test]
+    fn dot_product_recall() {
+        let r = recall(Metric::DotProduct);
+        assert!(r > 0.8, "recall {r}");
+    }
+
+    #[test]
+    fn scores() {
+        let mut graph = new_graph(Metric::Cosine);
+        let a = graph.insert(&[1.0, 0.0]);
+        let b = graph.insert(&[0.0, 2.0]);
+        graph.insert(&[-1.0, 0.0]);
+
+        let results = graph.search(&[2.0, 0.1], 2);
+        assert_eq!(results[0].0, a);
+        assert!((results[0].1 - 0.9988).abs() < 0.001);
+        assert_eq!(results[1].0, b);
+    }
+
+    #[test]
+    fn delete_and_compact() {
+        let mut rng = seeded_rng();
+        let vectors = random_vectors(&mut rng, 100, 8);
+        let mut graph = new_graph(Metric::Cosine);
+        for v in &vectors {
+            graph.insert(v);
+        }
+
+        for id in 0..50 {
+            graph.delete(id);
+        }
+        assert_eq!(graph.len(), 50);
+
+        let results = graph.search(&vectors[10], 10);
+        assert_eq!(results.len(), 10);
+        assert!(results.iter().all(|(id, _)| *id >= 50));
+
+        let (compacted, old_ids) = graph.compact();
+        assert_eq!(compacted.len(), 50);
+        assert_eq!(compacted.num_deleted(), 0);
+        let (new_id, _) = compacted.search(&vectors[75], 1)[0];
+        assert_eq!(old_ids[new_id as usize], 75);
+    }
+
+    #[test]
+    fn filtered_search() {
+        let mut rng = seeded_rng();
+        let vectors = random_vectors(&mut rng, 1000, 16);
+        let mut graph = new_graph(Metric::Cosine);
+        for v in &vectors {
+            graph.insert(v);
+        }
+
+        let allowed = |id: u32| id % 10 == 3;
+        let allowed_vectors = vectors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| allowed(*i as u32))
+            .map(|(_, v)| v.clone())
+            .collect::<Vec<_>>();
+
+        let queries = random_vectors(&mut rng, 20, 16);
+        let mut found = 0;
+        for q in &queries {
+            let expected = exact_nearest(Metric::Cosine, &allowed_vectors, q, 10)
+                .into_iter()
+                .map(|i| i * 10 + 3)
+                .collect::<Vec<_>>();
+
+            let results = graph.search_filtered(q, 10, allowed);
+            assert_eq!(results.len(), 10);
+            assert!(results.iter().all(|(id, _)| allowed(*id)));
+            found += results
+                .iter()
+                .filter(|(id, _)| expected.contains(id))
+                .count();
+
+            let exact = graph.search_exact(q, 10, (0..1000).filter(|id| allowed(*id)));
+            let exact_ids = exact.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+            assert_eq!(exact_ids, expected);
+        }
+
+        let r = found as f32 / 200.0;
+        assert!(r > 0.9, "recall {r}");
+    }
+}